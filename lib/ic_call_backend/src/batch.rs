@@ -0,0 +1,373 @@
+use crate::operations::{
+    build_call_request, build_query_request, build_read_state_request, construct_sign_message,
+    detect_sender_public_key, serialize_envelope,
+};
+use crate::public_key::public_key_to_der;
+use crate::read_state::request_status_path;
+use crate::render::RandGenerator;
+use crate::request_id::{to_request_id_with, RequestId};
+use crate::sha256::{Sha256Algorithm, Sha256Hasher};
+use crate::signer::{KeyAlgorithm, Signature, Signer};
+use crate::types::{
+    CallRequestContent, DeviceKey, QueryContent, ReadStateContent, SenderInfo, SignedDelegation,
+};
+use crate::RequestCtx;
+use candid::Principal;
+use icgeek_ic_call_api::{AgentCallRequest, AgentQueryRequest, AgentRequest};
+
+#[derive(Debug, Clone)]
+pub enum BatchRequest {
+    Query {
+        canister_id: Principal,
+        method_name: String,
+        arg: Vec<u8>,
+    },
+    Call {
+        canister_id: Principal,
+        method_name: String,
+        arg: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum PreparedRequest {
+    Query {
+        canister_id: Principal,
+        public_key: DeviceKey,
+        signed_delegation: Option<Vec<SignedDelegation>>,
+        request: QueryContent,
+        request_id: RequestId,
+    },
+    Call {
+        canister_id: Principal,
+        public_key: DeviceKey,
+        signed_delegation: Option<Vec<SignedDelegation>>,
+        request: CallRequestContent,
+        request_id: RequestId,
+        rs_request: ReadStateContent,
+        rs_request_id: RequestId,
+    },
+}
+
+/// Build all requests and sign them with one `Signer::sign_batch` call.
+/// The result keeps the order of the `requests`.
+pub async fn create_batch_requests<C: RequestCtx + Sync>(
+    ctx: &C,
+    signer: &dyn Signer<C>,
+    rand_generator: &dyn RandGenerator,
+    requests: Vec<BatchRequest>,
+) -> Result<Vec<AgentRequest>, String> {
+    create_batch_requests_with::<Sha256Algorithm, C>(ctx, signer, rand_generator, requests).await
+}
+
+/// Same as `create_batch_requests`, hashing with the `H` hasher.
+pub async fn create_batch_requests_with<H: Sha256Hasher, C: RequestCtx + Sync>(
+    ctx: &C,
+    signer: &dyn Signer<C>,
+    rand_generator: &dyn RandGenerator,
+    requests: Vec<BatchRequest>,
+) -> Result<Vec<AgentRequest>, String> {
+    let prepared_requests = PreparedRequests::prepare_with::<H, C>(
+        ctx,
+        signer.get_key_algorithm(ctx),
+        &signer.get_public_key(ctx),
+        rand_generator,
+        requests,
+    )
+    .await?;
+
+    let signatures = signer
+        .sign_batch(ctx, &prepared_requests.messages())
+        .await?;
+    prepared_requests.finalize(signatures)
+}
+
+/// Requests built for the signer key but not signed yet. The messages are either signed
+/// in one round-trip, see `create_batch_requests`, or out of band, e.g. certified by
+/// the canister, see `canister_sig::CanisterSigRequests`.
+#[derive(Debug, Clone)]
+pub struct PreparedRequests {
+    messages: Vec<Vec<u8>>,
+    requests: Vec<PreparedRequest>,
+    pub(crate) sender_info: Option<SenderInfo>,
+}
+
+impl PreparedRequests {
+    /// Build the requests for the raw public key of the signer key algorithm.
+    pub async fn prepare<C: RequestCtx>(
+        ctx: &C,
+        algorithm: KeyAlgorithm,
+        public_key: &[u8],
+        rand_generator: &dyn RandGenerator,
+        requests: Vec<BatchRequest>,
+    ) -> Result<Self, String> {
+        Self::prepare_with::<Sha256Algorithm, C>(
+            ctx,
+            algorithm,
+            public_key,
+            rand_generator,
+            requests,
+        )
+        .await
+    }
+
+    /// Same as `prepare`, hashing with the `H` hasher.
+    pub async fn prepare_with<H: Sha256Hasher, C: RequestCtx>(
+        ctx: &C,
+        algorithm: KeyAlgorithm,
+        public_key: &[u8],
+        rand_generator: &dyn RandGenerator,
+        requests: Vec<BatchRequest>,
+    ) -> Result<Self, String> {
+        let signer_public_key = public_key_to_der(algorithm, public_key);
+
+        let mut prepared_requests = Vec::with_capacity(requests.len());
+        for request in requests {
+            prepared_requests.push(
+                prepare_request::<H, C>(ctx, signer_public_key.clone(), rand_generator, request)
+                    .await?,
+            );
+        }
+
+        let messages = prepared_requests
+            .iter()
+            .flat_map(|prepared_request| match prepared_request {
+                PreparedRequest::Query { request_id, .. } => vec![request_id],
+                PreparedRequest::Call {
+                    request_id,
+                    rs_request_id,
+                    ..
+                } => vec![request_id, rs_request_id],
+            })
+            .map(|request_id| construct_sign_message::<H>(algorithm, request_id))
+            .collect();
+
+        Ok(Self {
+            messages,
+            requests: prepared_requests,
+            sender_info: ctx.get_sender_info(),
+        })
+    }
+
+    /// Messages to be signed, in the order of the signatures expected by `finalize`:
+    /// the query request, or the call request followed by its read_state request.
+    pub fn messages(&self) -> Vec<Vec<u8>> {
+        self.messages.clone()
+    }
+
+    /// Signed envelopes of the requests, in the order of the prepared requests.
+    pub fn finalize(self, signatures: Vec<Signature>) -> Result<Vec<AgentRequest>, String> {
+        let messages_count = self.messages.len();
+        if signatures.len() != messages_count {
+            return Err(format!(
+                "Signer returned {} signatures for {} messages",
+                signatures.len(),
+                messages_count
+            ));
+        }
+
+        let sender_info = self.sender_info;
+        let mut signatures = signatures.into_iter();
+        let mut next_signature = || signatures.next().unwrap();
+
+        self.requests
+            .into_iter()
+            .map(|prepared_request| match prepared_request {
+                PreparedRequest::Query {
+                    canister_id,
+                    public_key,
+                    signed_delegation,
+                    request,
+                    ..
+                } => Ok(AgentRequest::Query(AgentQueryRequest {
+                    canister_id,
+                    request_sign: serialize_envelope(
+                        public_key,
+                        signed_delegation,
+                        sender_info.clone(),
+                        next_signature(),
+                        &request,
+                    )?,
+                })),
+                PreparedRequest::Call {
+                    canister_id,
+                    public_key,
+                    signed_delegation,
+                    request,
+                    request_id,
+                    rs_request,
+                    ..
+                } => Ok(AgentRequest::Call(AgentCallRequest {
+                    canister_id,
+                    request_id: request_id.into(),
+                    request_sign: serialize_envelope(
+                        public_key.clone(),
+                        signed_delegation.clone(),
+                        sender_info.clone(),
+                        next_signature(),
+                        &request,
+                    )?,
+                    read_state_request_sign: serialize_envelope(
+                        public_key,
+                        signed_delegation,
+                        sender_info.clone(),
+                        next_signature(),
+                        &rs_request,
+                    )?,
+                })),
+            })
+            .collect()
+    }
+}
+
+async fn prepare_request<H: Sha256Hasher, C: RequestCtx>(
+    ctx: &C,
+    signer_public_key: DeviceKey,
+    rand_generator: &dyn RandGenerator,
+    request: BatchRequest,
+) -> Result<PreparedRequest, String> {
+    match request {
+        BatchRequest::Query {
+            canister_id,
+            method_name,
+            arg,
+        } => {
+            let (public_key, signed_delegation) =
+                detect_sender_public_key(ctx, signer_public_key, &canister_id)?;
+            let sender = Principal::self_authenticating(&public_key);
+
+            let request = build_query_request(
+                &sender,
+                &canister_id,
+                &method_name,
+                arg,
+                ctx.get_ingress_expiry(),
+            )
+            .await?;
+            let request_id =
+                to_request_id_with::<H, _>(&request).map_err(|e| std::format!("{:?}", e))?;
+
+            Ok(PreparedRequest::Query {
+                canister_id,
+                public_key,
+                signed_delegation,
+                request,
+                request_id,
+            })
+        }
+        BatchRequest::Call {
+            canister_id,
+            method_name,
+            arg,
+        } => {
+            let (public_key, signed_delegation) =
+                detect_sender_public_key(ctx, signer_public_key, &canister_id)?;
+            let sender = Principal::self_authenticating(&public_key);
+
+            let request = build_call_request(
+                rand_generator,
+                &sender,
+                &canister_id,
+                &method_name,
+                arg,
+                ctx.get_ingress_expiry(),
+            )
+            .await?;
+            let request_id =
+                to_request_id_with::<H, _>(&request).map_err(|e| std::format!("{:?}", e))?;
+
+            let rs_request = build_read_state_request(
+                sender,
+                vec![request_status_path(&request_id)],
+                ctx.get_ingress_expiry(),
+            );
+            let rs_request_id =
+                to_request_id_with::<H, _>(&rs_request).map_err(|e| std::format!("{:?}", e))?;
+
+            Ok(PreparedRequest::Call {
+                canister_id,
+                public_key,
+                signed_delegation,
+                request,
+                request_id,
+                rs_request,
+                rs_request_id,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::{create_batch_requests, BatchRequest};
+    use crate::request_id::to_request_id;
+    use crate::test_utils::{Ctx, DevRandGenerator, Ed25519Signer};
+    use crate::types::{CallRequestContent, Envelope, QueryContent, ReadStateContent};
+    use candid::Principal;
+    use ed25519_dalek::Verifier;
+    use icgeek_ic_call_api::AgentRequest;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::sync::atomic::Ordering;
+
+    fn verify_envelope<T: Serialize + DeserializeOwned>(request_sign: &[u8]) {
+        let envelope: Envelope<T> = serde_cbor::from_slice(request_sign).unwrap();
+        let request_id = to_request_id(&envelope.content).unwrap();
+
+        let mut message = b"\x0Aic-request".to_vec();
+        message.extend_from_slice(request_id.as_slice());
+        let signature =
+            ed25519_dalek::Signature::from_slice(&envelope.sender_sig.unwrap()).unwrap();
+        Ed25519Signer::signing_key()
+            .verifying_key()
+            .verify(&message, &signature)
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test() {
+        let signer = Ed25519Signer::default();
+        let canister_id = Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap();
+
+        let requests: Vec<BatchRequest> = (0..5_u8)
+            .map(|index| {
+                let method_name = format!("method_{index}");
+                let arg = vec![index];
+                if index % 2 == 0 {
+                    BatchRequest::Call {
+                        canister_id,
+                        method_name,
+                        arg,
+                    }
+                } else {
+                    BatchRequest::Query {
+                        canister_id,
+                        method_name,
+                        arg,
+                    }
+                }
+            })
+            .collect();
+
+        let agent_requests = create_batch_requests(&Ctx, &signer, &DevRandGenerator, requests)
+            .await
+            .unwrap();
+        assert_eq!(signer.batch_count.load(Ordering::Relaxed), 1);
+        assert_eq!(signer.sign_count.load(Ordering::Relaxed), 0);
+        assert_eq!(agent_requests.len(), 5);
+
+        for (index, agent_request) in agent_requests.iter().enumerate() {
+            match agent_request {
+                AgentRequest::Call(call) => {
+                    assert_eq!(index % 2, 0);
+                    verify_envelope::<CallRequestContent>(&call.request_sign);
+                    verify_envelope::<ReadStateContent>(&call.read_state_request_sign);
+                }
+                AgentRequest::Query(query) => {
+                    assert_eq!(index % 2, 1);
+                    verify_envelope::<QueryContent>(&query.request_sign);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,439 @@
+use crate::batch::{BatchRequest, PreparedRequests};
+use crate::public_key::public_key_to_der;
+use crate::render::RandGenerator;
+use crate::sha256::{get_sha256, Sha256Hash};
+use crate::signer::{KeyAlgorithm, RawPublicKey, Signature};
+use crate::types::{DeviceKey, SenderInfo};
+use crate::RequestCtx;
+use candid::Principal;
+use ic_certification::{empty, fork, label, leaf, pruned, HashTree};
+use icgeek_ic_call_api::AgentRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+const SIG_LABEL: &str = "sig";
+const IC_SENDER_INFO_DOMAIN_SEPARATOR: &[u8; 15] = b"\x0Eic-sender-info";
+
+/// Raw canister signature public key: the length of the canister id,
+/// the canister id and the seed chosen by the canister.
+pub fn canister_sig_public_key(canister_id: &Principal, seed: &[u8]) -> RawPublicKey {
+    let canister_id = canister_id.as_slice();
+
+    let mut buf = Vec::with_capacity(1 + canister_id.len() + seed.len());
+    buf.push(canister_id.len() as u8);
+    buf.extend_from_slice(canister_id);
+    buf.extend_from_slice(seed);
+    buf
+}
+
+/// DER encoded canister signature public key, the sender key of the requests.
+pub fn canister_sig_public_key_to_der(canister_id: &Principal, seed: &[u8]) -> DeviceKey {
+    public_key_to_der(
+        KeyAlgorithm::CanisterSignature,
+        &canister_sig_public_key(canister_id, seed),
+    )
+}
+
+/// CBOR encoded canister signature.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanisterSignature {
+    #[serde(with = "serde_bytes")]
+    pub certificate: Vec<u8>,
+    pub tree: HashTree,
+}
+
+impl CanisterSignature {
+    pub fn to_cbor(&self) -> Result<Vec<u8>, String> {
+        let mut serializer = serde_cbor::Serializer::new(vec![]);
+        serializer.self_describe().map_err(|e| e.to_string())?;
+        self.serialize(&mut serializer).map_err(|e| e.to_string())?;
+        Ok(serializer.into_inner())
+    }
+}
+
+/// Signatures issued by the canister, as the `sig/<sha256(seed)>/<sha256(message)>`
+/// paths of a hash tree.
+///
+/// The canister adds the messages in an update call and sets `root_hash` as its
+/// certified data (`ic_cdk::api::set_certified_data`). In a later query call the
+/// signature is built from the data certificate (`ic_cdk::api::data_certificate`).
+#[derive(Debug, Clone, Default)]
+pub struct CanisterSigTree {
+    sigs: BTreeMap<Sha256Hash, BTreeSet<Sha256Hash>>,
+}
+
+impl CanisterSigTree {
+    pub fn add(&mut self, seed: &[u8], message: &[u8]) {
+        self.sigs
+            .entry(get_sha256(seed))
+            .or_default()
+            .insert(get_sha256(message));
+    }
+
+    pub fn remove(&mut self, seed: &[u8], message: &[u8]) {
+        let seed_hash = get_sha256(seed);
+        if let Some(messages) = self.sigs.get_mut(&seed_hash) {
+            messages.remove(&get_sha256(message));
+            if messages.is_empty() {
+                self.sigs.remove(&seed_hash);
+            }
+        }
+    }
+
+    pub fn contains(&self, seed: &[u8], message: &[u8]) -> bool {
+        self.sigs
+            .get(&get_sha256(seed))
+            .map(|messages| messages.contains(&get_sha256(message)))
+            .unwrap_or(false)
+    }
+
+    /// Data to be certified by the canister.
+    pub fn root_hash(&self) -> Sha256Hash {
+        self.build(None).digest()
+    }
+
+    /// Tree with the signature path of the message, all other branches pruned.
+    pub fn witness(&self, seed: &[u8], message: &[u8]) -> Result<HashTree, String> {
+        if !self.contains(seed, message) {
+            return Err("Message is not signed for the seed".to_owned());
+        }
+        Ok(self.build(Some((&get_sha256(seed), &get_sha256(message)))))
+    }
+
+    /// CBOR encoded canister signature of the message, for the certificate
+    /// of the current certified data.
+    pub fn signature(
+        &self,
+        seed: &[u8],
+        message: &[u8],
+        certificate: Vec<u8>,
+    ) -> Result<Signature, String> {
+        CanisterSignature {
+            certificate,
+            tree: self.witness(seed, message)?,
+        }
+        .to_cbor()
+    }
+
+    fn build(&self, path: Option<(&Sha256Hash, &Sha256Hash)>) -> HashTree {
+        let seeds = self
+            .sigs
+            .iter()
+            .map(|(seed_hash, messages)| {
+                let messages = messages
+                    .iter()
+                    .map(|message_hash| (message_hash, leaf(vec![])))
+                    .collect::<Vec<_>>();
+                let message_path = path
+                    .filter(|(path_seed_hash, _)| *path_seed_hash == seed_hash)
+                    .map(|(_, message_hash)| message_hash);
+                (seed_hash, build_forest(&messages, message_path))
+            })
+            .collect::<Vec<_>>();
+
+        label(
+            SIG_LABEL,
+            build_forest(&seeds, path.map(|(seed_hash, _)| seed_hash)),
+        )
+    }
+}
+
+/// Balanced tree of the labeled nodes sorted by label. With the `path` label,
+/// the subtrees not containing it are pruned.
+fn build_forest(nodes: &[(&Sha256Hash, HashTree)], path: Option<&Sha256Hash>) -> HashTree {
+    match nodes {
+        [] => empty(),
+        [(node_label, node)] => match path {
+            Some(path) if path != *node_label => pruned(label(*node_label, node.clone()).digest()),
+            _ => label(*node_label, node.clone()),
+        },
+        _ => {
+            let (left, right) = nodes.split_at(nodes.len() / 2);
+            let subtree = |nodes: &[(&Sha256Hash, HashTree)]| match path {
+                Some(path) if !nodes.iter().any(|(node_label, _)| *node_label == path) => {
+                    pruned(build_forest(nodes, None).digest())
+                }
+                _ => build_forest(nodes, path),
+            };
+            fork(subtree(left), subtree(right))
+        }
+    }
+}
+
+/// Requests signed by the canister acting on behalf of the `seed` user, in two phases.
+///
+/// The signed messages contain the request ids, so they are known only after the requests
+/// are built. In an update call the canister prepares the requests, adds the messages
+/// to its signature tree (`add_to_tree`) and certifies the tree root hash. In a later query
+/// call it finalizes the requests with the data certificate.
+#[derive(Debug, Clone)]
+pub struct CanisterSigRequests {
+    canister_id: Principal,
+    seed: Vec<u8>,
+    sender_info: Option<Vec<u8>>,
+    prepared: PreparedRequests,
+}
+
+impl CanisterSigRequests {
+    /// Build the requests of the canister signature key; the optional `sender_info`
+    /// is signed by the same key and attached to the envelopes.
+    pub async fn prepare<C: RequestCtx>(
+        ctx: &C,
+        canister_id: Principal,
+        seed: Vec<u8>,
+        sender_info: Option<Vec<u8>>,
+        rand_generator: &dyn RandGenerator,
+        requests: Vec<BatchRequest>,
+    ) -> Result<Self, String> {
+        let prepared = PreparedRequests::prepare(
+            ctx,
+            KeyAlgorithm::CanisterSignature,
+            &canister_sig_public_key(&canister_id, &seed),
+            rand_generator,
+            requests,
+        )
+        .await?;
+
+        Ok(Self {
+            canister_id,
+            seed,
+            sender_info,
+            prepared,
+        })
+    }
+
+    /// Messages to be certified: the request messages and the sender info message.
+    pub fn messages(&self) -> Vec<Vec<u8>> {
+        let mut messages = self.prepared.messages();
+        messages.extend(self.sender_info.as_deref().map(sender_info_message));
+        messages
+    }
+
+    pub fn add_to_tree(&self, tree: &mut CanisterSigTree) {
+        for message in self.messages() {
+            tree.add(&self.seed, &message);
+        }
+    }
+
+    /// Remove the messages once the requests are finalized (or expired).
+    pub fn remove_from_tree(&self, tree: &mut CanisterSigTree) {
+        for message in self.messages() {
+            tree.remove(&self.seed, &message);
+        }
+    }
+
+    /// Signed envelopes of the requests, `certificate` must certify the current tree.
+    pub fn finalize(
+        self,
+        tree: &CanisterSigTree,
+        certificate: Vec<u8>,
+    ) -> Result<Vec<AgentRequest>, String> {
+        let signatures = self
+            .prepared
+            .messages()
+            .iter()
+            .map(|message| tree.signature(&self.seed, message, certificate.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut prepared = self.prepared;
+        if let Some(info) = self.sender_info {
+            let sig = tree.signature(&self.seed, &sender_info_message(&info), certificate)?;
+            prepared.sender_info = Some(SenderInfo {
+                info,
+                signer: canister_sig_public_key_to_der(&self.canister_id, &self.seed),
+                sig,
+            });
+        }
+        prepared.finalize(signatures)
+    }
+}
+
+/// Message of the sender info signature: the domain separator followed by the info.
+pub fn sender_info_message(info: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    buf.extend_from_slice(IC_SENDER_INFO_DOMAIN_SEPARATOR);
+    buf.extend_from_slice(info);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::BatchRequest;
+    use crate::canister_sig::{
+        canister_sig_public_key, canister_sig_public_key_to_der, sender_info_message,
+        CanisterSigRequests, CanisterSigTree, CanisterSignature,
+    };
+    use crate::envelope::decode_envelope;
+    use crate::public_key::Asn1PublicKey;
+    use crate::sha256::get_sha256;
+    use crate::signer::KeyAlgorithm;
+    use crate::test_utils::{Ctx, DevRandGenerator};
+    use candid::Principal;
+    use ic_certification::{Label, LookupResult};
+    use icgeek_ic_call_api::AgentRequest;
+    use sec1::der::Decodable;
+
+    fn canister_id() -> Principal {
+        Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap()
+    }
+
+    #[test]
+    fn test_public_key() {
+        let canister_id = canister_id();
+        let seed = [5_u8; 32];
+
+        let public_key = canister_sig_public_key(&canister_id, &seed);
+        assert_eq!(public_key[0], 10);
+
+        let der_public_key = canister_sig_public_key_to_der(&canister_id, &seed);
+        assert_eq!(
+            icgeek_ic_certification::parse_canister_sig_public_key(&der_public_key).unwrap(),
+            (canister_id, seed.to_vec())
+        );
+        assert_eq!(
+            der_public_key[2..16],
+            [48, 12, 6, 10, 43, 6, 1, 4, 1, 131, 184, 67, 1, 2]
+        );
+        let asn1_public_key = Asn1PublicKey::from_der(&der_public_key).unwrap();
+        assert_eq!(
+            asn1_public_key.meta_data.key_algorithm(),
+            Some(KeyAlgorithm::CanisterSignature)
+        );
+        assert_eq!(asn1_public_key.data.as_bytes().unwrap(), public_key);
+    }
+
+    #[test]
+    fn test_tree() {
+        let mut tree = CanisterSigTree::default();
+        let empty_root_hash = tree.root_hash();
+
+        for seed in 0..5_u8 {
+            for message in 0..3_u8 {
+                tree.add(&[seed], &[seed, message]);
+            }
+        }
+        assert_ne!(tree.root_hash(), empty_root_hash);
+        assert!(tree.witness(&[1], &[2, 0]).is_err());
+
+        for seed in 0..5_u8 {
+            for message in 0..3_u8 {
+                let witness = tree.witness(&[seed], &[seed, message]).unwrap();
+                assert_eq!(witness.digest(), tree.root_hash());
+
+                let path: [Label; 3] = [
+                    "sig".into(),
+                    get_sha256([seed]).into(),
+                    get_sha256([seed, message]).into(),
+                ];
+                assert_eq!(witness.lookup_path(&path), LookupResult::Found(&[]));
+
+                let other_path: [Label; 3] = [
+                    "sig".into(),
+                    get_sha256([seed]).into(),
+                    get_sha256([seed, message + 1]).into(),
+                ];
+                assert_ne!(witness.lookup_path(&other_path), LookupResult::Found(&[]));
+            }
+        }
+
+        let signature = tree.signature(&[3], &[3, 1], vec![1, 2, 3]).unwrap();
+        assert_eq!(signature[..3], [0xd9, 0xd9, 0xf7]);
+        let signature: CanisterSignature = serde_cbor::from_slice(&signature).unwrap();
+        assert_eq!(signature.certificate, vec![1, 2, 3]);
+        assert_eq!(signature.tree.digest(), tree.root_hash());
+
+        let der_public_key = canister_sig_public_key_to_der(&canister_id(), &[3]);
+        assert_eq!(
+            icgeek_ic_certification::parse_canister_sig_public_key(&der_public_key).unwrap(),
+            (canister_id(), vec![3])
+        );
+        icgeek_ic_certification::verify_canister_sig_tree(&signature.tree, &[3], &[3, 1]).unwrap();
+        assert!(
+            icgeek_ic_certification::verify_canister_sig_tree(&signature.tree, &[3], &[3, 2])
+                .is_err()
+        );
+
+        tree.remove(&[3], &[3, 1]);
+        assert!(!tree.contains(&[3], &[3, 1]));
+        assert!(tree.contains(&[3], &[3, 2]));
+    }
+
+    #[actix_rt::test]
+    async fn test_two_phase_requests() {
+        let target_id = Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap();
+        let seed = b"user".to_vec();
+        let requests = vec![
+            BatchRequest::Call {
+                canister_id: target_id,
+                method_name: "update".to_owned(),
+                arg: vec![1],
+            },
+            BatchRequest::Query {
+                canister_id: target_id,
+                method_name: "query".to_owned(),
+                arg: vec![2],
+            },
+        ];
+
+        let prepared = CanisterSigRequests::prepare(
+            &Ctx,
+            canister_id(),
+            seed.clone(),
+            Some(b"info".to_vec()),
+            &DevRandGenerator,
+            requests,
+        )
+        .await
+        .unwrap();
+        assert_eq!(prepared.messages().len(), 4);
+        assert_eq!(prepared.messages()[3], sender_info_message(b"info"));
+
+        // update call: certify the messages
+        let mut tree = CanisterSigTree::default();
+        assert!(prepared.clone().finalize(&tree, vec![]).is_err());
+        prepared.add_to_tree(&mut tree);
+
+        // query call: finalize with the data certificate
+        let agent_requests = prepared.clone().finalize(&tree, vec![1, 2, 3]).unwrap();
+        let der_public_key = canister_sig_public_key_to_der(&canister_id(), &seed);
+
+        let request_signs = agent_requests.iter().flat_map(|request| match request {
+            AgentRequest::Call(call) => {
+                vec![&call.request_sign, &call.read_state_request_sign]
+            }
+            AgentRequest::Query(query) => vec![&query.request_sign],
+        });
+        for request_sign in request_signs {
+            let envelope = decode_envelope(request_sign).unwrap();
+            assert_eq!(
+                envelope.content.get_sender(),
+                Principal::self_authenticating(&der_public_key)
+            );
+            assert_eq!(envelope.sender_pubkey.as_ref(), Some(&der_public_key));
+
+            let signature: CanisterSignature =
+                serde_cbor::from_slice(envelope.sender_sig.as_ref().unwrap()).unwrap();
+            assert_eq!(signature.certificate, vec![1, 2, 3]);
+            assert_eq!(signature.tree.digest(), tree.root_hash());
+
+            let mut message = b"\x0Aic-request".to_vec();
+            message.extend_from_slice(envelope.request_id.as_slice());
+            icgeek_ic_certification::verify_canister_sig_tree(&signature.tree, &seed, &message)
+                .unwrap();
+
+            let sender_info = envelope.sender_info.unwrap();
+            assert_eq!(sender_info.info, b"info");
+            assert_eq!(sender_info.signer, der_public_key);
+            let signature: CanisterSignature = serde_cbor::from_slice(&sender_info.sig).unwrap();
+            icgeek_ic_certification::verify_canister_sig_tree(
+                &signature.tree,
+                &seed,
+                &sender_info_message(b"info"),
+            )
+            .unwrap();
+        }
+
+        prepared.remove_from_tree(&mut tree);
+        assert_eq!(tree.root_hash(), CanisterSigTree::default().root_hash());
+    }
+}
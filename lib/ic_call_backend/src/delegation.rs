@@ -0,0 +1,244 @@
+use crate::request_id::to_representation_independent_hash_with;
+use crate::sha256::{Sha256Algorithm, Sha256Hasher};
+use crate::types::{Delegation, SignedDelegation};
+use candid::Principal;
+use thiserror::Error;
+
+const IC_REQUEST_AUTH_DELEGATION_DOMAIN_SEPARATOR: &[u8; 27] = b"\x1Aic-request-auth-delegation";
+
+/// Maximum number of delegations in the chain accepted by the replica.
+pub const MAX_DELEGATION_CHAIN_LENGTH: usize = 20;
+
+/// Reasons why the delegation chain does not authorize the request.
+#[derive(Error, Clone, Debug, Eq, PartialEq)]
+pub enum DelegationError {
+    #[error("Delegation chain is empty")]
+    EmptyChain,
+
+    #[error("Delegation chain is too long: {0}, at most {MAX_DELEGATION_CHAIN_LENGTH} delegations are allowed")]
+    ChainTooLong(usize),
+
+    #[error("Delegation {index} expired at {expiration}, before the current time {current_time}")]
+    Expired {
+        index: usize,
+        expiration: u64,
+        current_time: u64,
+    },
+
+    #[error("Delegation {index} targets do not contain the canister {canister_id}")]
+    CanisterNotTargeted {
+        index: usize,
+        canister_id: Principal,
+    },
+
+    #[error("Last delegation of the chain is not issued to the signer public key")]
+    SignerKeyMismatch,
+}
+
+/// Check that the ordered delegation chain (from the sender key to the signer key)
+/// authorizes the signer to send requests to the canister at the `current_time`
+/// (nanoseconds since the epoch). Like the replica, the expirations are compared with
+/// the time the request is processed, not with its ingress expiry.
+///
+/// Delegation signatures are not verified, they are checked by the replica.
+pub fn validate_delegation_chain(
+    delegations: &[SignedDelegation],
+    signer_public_key: &[u8],
+    canister_id: &Principal,
+    current_time: u64,
+) -> Result<(), DelegationError> {
+    if delegations.len() > MAX_DELEGATION_CHAIN_LENGTH {
+        return Err(DelegationError::ChainTooLong(delegations.len()));
+    }
+
+    for (index, signed_delegation) in delegations.iter().enumerate() {
+        let delegation = &signed_delegation.delegation;
+
+        if delegation.expiration < current_time {
+            return Err(DelegationError::Expired {
+                index,
+                expiration: delegation.expiration,
+                current_time,
+            });
+        }
+
+        if let Some(targets) = &delegation.targets {
+            if !targets.contains(canister_id) {
+                return Err(DelegationError::CanisterNotTargeted {
+                    index,
+                    canister_id: *canister_id,
+                });
+            }
+        }
+    }
+
+    match delegations.last() {
+        None => Err(DelegationError::EmptyChain),
+        Some(last) if last.delegation.pubkey != signer_public_key => {
+            Err(DelegationError::SignerKeyMismatch)
+        }
+        Some(_) => Ok(()),
+    }
+}
+
+/// Message to be signed by the delegating key: the domain separator followed by
+/// the representation-independent hash of the delegation.
+pub fn construct_delegation_message(delegation: &Delegation) -> Result<Vec<u8>, String> {
+    construct_delegation_message_with::<Sha256Algorithm>(delegation)
+}
+
+/// Same as `construct_delegation_message`, hashing with the `H` hasher.
+pub fn construct_delegation_message_with<H: Sha256Hasher>(
+    delegation: &Delegation,
+) -> Result<Vec<u8>, String> {
+    let delegation_hash = to_representation_independent_hash_with::<H, _>(delegation)
+        .map_err(|e| std::format!("{:?}", e))?;
+
+    let mut buf = vec![];
+    buf.extend_from_slice(IC_REQUEST_AUTH_DELEGATION_DOMAIN_SEPARATOR);
+    buf.extend_from_slice(&delegation_hash);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::delegation::{validate_delegation_chain, DelegationError};
+    use crate::sha256::get_sha256;
+    use crate::types::{self, Delegation, SignedDelegation};
+    use candid::Principal;
+    use serde::Serialize;
+
+    fn signed_delegation(
+        pubkey: u8,
+        expiration: u64,
+        targets: Option<Vec<Principal>>,
+    ) -> SignedDelegation {
+        SignedDelegation {
+            delegation: Delegation {
+                pubkey: vec![pubkey],
+                expiration,
+                targets,
+            },
+            signature: vec![],
+        }
+    }
+
+    #[test]
+    fn test() {
+        let canister_id = Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap();
+        let other_canister_id = Principal::management_canister();
+
+        let chain = vec![
+            signed_delegation(1, 100, None),
+            signed_delegation(2, 200, Some(vec![other_canister_id, canister_id])),
+            signed_delegation(3, 100, None),
+        ];
+        assert_eq!(
+            validate_delegation_chain(&chain, &[3], &canister_id, 100),
+            Ok(())
+        );
+        assert_eq!(
+            validate_delegation_chain(&chain, &[2], &canister_id, 100),
+            Err(DelegationError::SignerKeyMismatch)
+        );
+        assert_eq!(
+            validate_delegation_chain(&chain, &[3], &canister_id, 101),
+            Err(DelegationError::Expired {
+                index: 0,
+                expiration: 100,
+                current_time: 101
+            })
+        );
+        assert_eq!(
+            validate_delegation_chain(&chain, &[3], &other_canister_id, 100),
+            Ok(())
+        );
+        assert_eq!(
+            validate_delegation_chain(&chain, &[3], &Principal::anonymous(), 100),
+            Err(DelegationError::CanisterNotTargeted {
+                index: 1,
+                canister_id: Principal::anonymous()
+            })
+        );
+        assert_eq!(
+            validate_delegation_chain(&[], &[3], &canister_id, 100),
+            Err(DelegationError::EmptyChain)
+        );
+
+        let long_chain = vec![signed_delegation(3, 100, None); 21];
+        assert_eq!(
+            validate_delegation_chain(&long_chain, &[3], &canister_id, 100),
+            Err(DelegationError::ChainTooLong(21))
+        );
+    }
+
+    /// The delegations serialized with the earlier `request_type` tag are still readable.
+    #[test]
+    fn test_legacy_tagged_format() {
+        #[derive(Serialize)]
+        #[serde(tag = "request_type")]
+        struct Delegation {
+            #[serde(with = "serde_bytes")]
+            pubkey: Vec<u8>,
+            expiration: u64,
+            targets: Option<Vec<Principal>>,
+        }
+
+        #[derive(Serialize)]
+        #[serde(tag = "request_type")]
+        struct SignedDelegation {
+            delegation: Delegation,
+            #[serde(with = "serde_bytes")]
+            signature: Vec<u8>,
+        }
+
+        let targets = Some(vec![Principal::management_canister()]);
+        let legacy = SignedDelegation {
+            delegation: Delegation {
+                pubkey: vec![1, 2, 3],
+                expiration: 300,
+                targets: targets.clone(),
+            },
+            signature: vec![4, 5],
+        };
+
+        let json = serde_json::to_string(&legacy).unwrap();
+        assert!(json.contains("\"request_type\":\"Delegation\""));
+        let decoded: types::SignedDelegation = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.delegation.pubkey, vec![1, 2, 3]);
+        assert_eq!(decoded.delegation.expiration, 300);
+        assert_eq!(decoded.delegation.targets, targets);
+        assert_eq!(decoded.signature, vec![4, 5]);
+
+        let cbor = serde_cbor::to_vec(&legacy).unwrap();
+        let decoded: types::SignedDelegation = serde_cbor::from_slice(&cbor).unwrap();
+        assert_eq!(decoded.delegation.pubkey, vec![1, 2, 3]);
+        assert_eq!(decoded.signature, vec![4, 5]);
+
+        // written back in the current format, without the tag
+        let json = serde_json::to_string(&decoded).unwrap();
+        assert!(!json.contains("request_type"));
+        let decoded: types::SignedDelegation = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.delegation.targets, targets);
+    }
+
+    #[test]
+    fn test_delegation_message() {
+        let delegation = Delegation {
+            pubkey: vec![1, 2, 3],
+            expiration: 300,
+            targets: None,
+        };
+
+        let mut fields = [
+            [get_sha256("pubkey"), get_sha256([1, 2, 3])].concat(),
+            [get_sha256("expiration"), get_sha256([172, 2])].concat(),
+        ];
+        fields.sort();
+        let hash = get_sha256(fields.concat());
+
+        let message = super::construct_delegation_message(&delegation).unwrap();
+        assert_eq!(message[..27], b"\x1Aic-request-auth-delegation"[..]);
+        assert_eq!(message[27..], hash);
+    }
+}
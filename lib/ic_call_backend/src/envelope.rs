@@ -0,0 +1,556 @@
+use crate::delegation::construct_delegation_message;
+use crate::operations::construct_message;
+use crate::public_key::{p256_public_key_from_cose, Asn1PublicKey};
+use crate::read_state::request_status_path;
+use crate::request_id::{RequestId, RequestIdError};
+use crate::sha256::get_sha256;
+use crate::signature::WebAuthnSignature;
+use crate::signer::KeyAlgorithm;
+use crate::types::{
+    CallRequestContent, Envelope, IngressExpiryDatetimeNanos, QueryContent, ReadStateContent,
+    SenderInfo, SignedDelegation,
+};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use candid::Principal;
+use icgeek_ic_call_api::{AgentCallRequest, AgentQueryRequest};
+use sec1::der::Decodable;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Content of any request the envelope can carry.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RequestContent {
+    Call(CallRequestContent),
+    Query(QueryContent),
+    ReadState(ReadStateContent),
+}
+
+impl RequestContent {
+    pub fn request_id(&self) -> Result<RequestId, RequestIdError> {
+        match self {
+            RequestContent::Call(content) => content.request_id(),
+            RequestContent::Query(content) => content.request_id(),
+            RequestContent::ReadState(content) => content.request_id(),
+        }
+    }
+
+    pub fn get_sender(&self) -> Principal {
+        match self {
+            RequestContent::Call(CallRequestContent::CallRequest { sender, .. }) => *sender,
+            RequestContent::Query(QueryContent::QueryRequest { sender, .. }) => *sender,
+            RequestContent::ReadState(ReadStateContent::ReadStateRequest { sender, .. }) => *sender,
+        }
+    }
+
+    pub fn get_ingress_expiry(&self) -> IngressExpiryDatetimeNanos {
+        match self {
+            RequestContent::Call(CallRequestContent::CallRequest { ingress_expiry, .. }) => {
+                *ingress_expiry
+            }
+            RequestContent::Query(QueryContent::QueryRequest { ingress_expiry, .. }) => {
+                *ingress_expiry
+            }
+            RequestContent::ReadState(ReadStateContent::ReadStateRequest {
+                ingress_expiry,
+                ..
+            }) => *ingress_expiry,
+        }
+    }
+
+    /// Canister the request is addressed to, read_state requests have none.
+    pub fn get_canister_id(&self) -> Option<Principal> {
+        match self {
+            RequestContent::Call(CallRequestContent::CallRequest { canister_id, .. }) => {
+                Some(*canister_id)
+            }
+            RequestContent::Query(QueryContent::QueryRequest { canister_id, .. }) => {
+                Some(*canister_id)
+            }
+            RequestContent::ReadState(_) => None,
+        }
+    }
+}
+
+#[derive(Error, Clone, Debug, Eq, PartialEq)]
+pub enum EnvelopeError {
+    #[error("Invalid CBOR envelope: {0}")]
+    InvalidCbor(String),
+
+    #[error("Cannot calculate the request id: {0}")]
+    RequestId(String),
+
+    #[error("Request id {actual} does not match the expected {expected}")]
+    RequestIdMismatch { expected: String, actual: String },
+
+    #[error("Sender {sender} does not match the sender public key")]
+    SenderMismatch { sender: Principal },
+
+    #[error("Envelope of the sender {sender} has no signature")]
+    MissingSignature { sender: Principal },
+
+    #[error("Invalid DER public key: {0}")]
+    InvalidPublicKey(String),
+
+    #[error("Signature verification is not supported for the public key algorithm")]
+    UnsupportedPublicKey,
+
+    #[error("Invalid signature of the {0}")]
+    InvalidSignature(String),
+
+    #[error("Request is addressed to the canister {actual:?}, expected {expected}")]
+    CanisterMismatch {
+        expected: Principal,
+        actual: Option<Principal>,
+    },
+
+    #[error("Request content is not a {0} request")]
+    UnexpectedContent(&'static str),
+
+    #[error("Read state request does not ask for the status of the call request")]
+    ReadStatePathMismatch,
+}
+
+/// Envelope decoded from the signed request blob.
+#[derive(Debug, Clone)]
+pub struct DecodedEnvelope {
+    pub content: RequestContent,
+    pub request_id: RequestId,
+    pub sender_pubkey: Option<Vec<u8>>,
+    pub sender_delegation: Option<Vec<SignedDelegation>>,
+    pub sender_sig: Option<Vec<u8>>,
+    /// Not verified by `verify`: its canister signature needs the IC root key.
+    pub sender_info: Option<SenderInfo>,
+}
+
+impl DecodedEnvelope {
+    /// Verify that the sender is derived from `sender_pubkey`, that every delegation
+    /// is signed by the previous key and that `sender_sig` is made by the last key.
+    pub fn verify(&self) -> Result<(), EnvelopeError> {
+        let sender = self.content.get_sender();
+
+        let (sender_pubkey, sender_sig) = match (&self.sender_pubkey, &self.sender_sig) {
+            (None, None) if sender == Principal::anonymous() => return Ok(()),
+            (Some(sender_pubkey), Some(sender_sig)) => (sender_pubkey, sender_sig),
+            _ => return Err(EnvelopeError::MissingSignature { sender }),
+        };
+
+        if sender != Principal::self_authenticating(sender_pubkey) {
+            return Err(EnvelopeError::SenderMismatch { sender });
+        }
+
+        let mut signing_key = sender_pubkey;
+        for (index, signed_delegation) in self.sender_delegation.iter().flatten().enumerate() {
+            let message = construct_delegation_message(&signed_delegation.delegation)
+                .map_err(EnvelopeError::RequestId)?;
+            verify_signature(signing_key, &message, &signed_delegation.signature)
+                .map_err(|e| wrap_signature_error(e, format!("delegation {index}")))?;
+            signing_key = &signed_delegation.delegation.pubkey;
+        }
+
+        verify_signature(
+            signing_key,
+            &construct_message(&self.request_id),
+            sender_sig,
+        )
+        .map_err(|e| wrap_signature_error(e, "request".to_owned()))
+    }
+}
+
+/// Decode the CBOR `request_sign` blob and recompute its request id.
+pub fn decode_envelope(request_sign: &[u8]) -> Result<DecodedEnvelope, EnvelopeError> {
+    let envelope: Envelope<RequestContent> = serde_cbor::from_slice(request_sign)
+        .map_err(|e| EnvelopeError::InvalidCbor(e.to_string()))?;
+
+    let request_id = envelope
+        .content
+        .request_id()
+        .map_err(|e| EnvelopeError::RequestId(std::format!("{:?}", e)))?;
+
+    Ok(DecodedEnvelope {
+        content: envelope.content,
+        request_id,
+        sender_pubkey: envelope.sender_pubkey,
+        sender_delegation: envelope.sender_delegation,
+        sender_sig: envelope.sender_sig,
+        sender_info: envelope.sender_info,
+    })
+}
+
+/// Decode and verify the query envelope produced by `create_query_request`.
+pub fn inspect_query_request(
+    request: &AgentQueryRequest,
+) -> Result<DecodedEnvelope, EnvelopeError> {
+    let envelope = decode_envelope(&request.request_sign)?;
+    if !matches!(envelope.content, RequestContent::Query(_)) {
+        return Err(EnvelopeError::UnexpectedContent("query"));
+    }
+    check_canister_id(&envelope, &request.canister_id)?;
+    envelope.verify()?;
+    Ok(envelope)
+}
+
+/// Decode and verify the call and read_state envelopes produced by `create_call_request`.
+pub fn inspect_call_request(
+    request: &AgentCallRequest,
+) -> Result<(DecodedEnvelope, DecodedEnvelope), EnvelopeError> {
+    let envelope = decode_envelope(&request.request_sign)?;
+    if !matches!(envelope.content, RequestContent::Call(_)) {
+        return Err(EnvelopeError::UnexpectedContent("call"));
+    }
+    check_canister_id(&envelope, &request.canister_id)?;
+    if envelope.request_id.as_slice() != request.request_id.as_slice() {
+        return Err(EnvelopeError::RequestIdMismatch {
+            expected: hex::encode(&request.request_id),
+            actual: hex::encode(envelope.request_id.as_slice()),
+        });
+    }
+    envelope.verify()?;
+
+    let rs_envelope = decode_envelope(&request.read_state_request_sign)?;
+    let RequestContent::ReadState(ReadStateContent::ReadStateRequest { paths, sender, .. }) =
+        &rs_envelope.content
+    else {
+        return Err(EnvelopeError::UnexpectedContent("read_state"));
+    };
+    if *sender != envelope.content.get_sender() {
+        return Err(EnvelopeError::SenderMismatch { sender: *sender });
+    }
+    if !paths.contains(&request_status_path(&envelope.request_id)) {
+        return Err(EnvelopeError::ReadStatePathMismatch);
+    }
+    rs_envelope.verify()?;
+
+    Ok((envelope, rs_envelope))
+}
+
+fn check_canister_id(
+    envelope: &DecodedEnvelope,
+    canister_id: &Principal,
+) -> Result<(), EnvelopeError> {
+    let actual = envelope.content.get_canister_id();
+    if actual != Some(*canister_id) {
+        return Err(EnvelopeError::CanisterMismatch {
+            expected: *canister_id,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+fn wrap_signature_error(error: EnvelopeError, subject: String) -> EnvelopeError {
+    match error {
+        EnvelopeError::InvalidSignature(_) => EnvelopeError::InvalidSignature(subject),
+        error => error,
+    }
+}
+
+/// Verify the signature of the message (before algorithm specific hashing)
+/// made by the DER encoded public key.
+pub fn verify_signature(
+    der_public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), EnvelopeError> {
+    let public_key = Asn1PublicKey::from_der(der_public_key)
+        .map_err(|e| EnvelopeError::InvalidPublicKey(e.to_string()))?;
+    let raw_public_key = public_key
+        .data
+        .as_bytes()
+        .ok_or_else(|| EnvelopeError::InvalidPublicKey("unaligned bit string".to_owned()))?;
+
+    let invalid_key = |e: String| EnvelopeError::InvalidPublicKey(e);
+    let invalid_signature = |_| EnvelopeError::InvalidSignature("message".to_owned());
+
+    match public_key.meta_data.key_algorithm() {
+        Some(KeyAlgorithm::EcdsaSecp256k1) => {
+            use k256::ecdsa::signature::Verifier;
+            let verifying_key = k256::ecdsa::VerifyingKey::from_sec1_bytes(raw_public_key)
+                .map_err(|e| invalid_key(e.to_string()))?;
+            let signature =
+                k256::ecdsa::Signature::from_slice(signature).map_err(invalid_signature)?;
+            verifying_key
+                .verify(message, &signature)
+                .map_err(invalid_signature)
+        }
+        Some(KeyAlgorithm::EcdsaSecp256r1) => {
+            use p256::ecdsa::signature::Verifier;
+            let verifying_key = p256::ecdsa::VerifyingKey::from_sec1_bytes(raw_public_key)
+                .map_err(|e| invalid_key(e.to_string()))?;
+            let signature =
+                p256::ecdsa::Signature::from_slice(signature).map_err(invalid_signature)?;
+            verifying_key
+                .verify(message, &signature)
+                .map_err(invalid_signature)
+        }
+        Some(KeyAlgorithm::Ed25519) => {
+            use ed25519_dalek::Verifier;
+            let raw_public_key: &[u8; 32] = raw_public_key
+                .try_into()
+                .map_err(|_| invalid_key("Ed25519 key must be 32 bytes".to_owned()))?;
+            let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(raw_public_key)
+                .map_err(|e| invalid_key(e.to_string()))?;
+            let signature =
+                ed25519_dalek::Signature::from_slice(signature).map_err(invalid_signature)?;
+            verifying_key
+                .verify(message, &signature)
+                .map_err(invalid_signature)
+        }
+        Some(KeyAlgorithm::WebAuthn) => {
+            verify_webauthn_signature(raw_public_key, message, signature)
+        }
+        // Canister signatures need the IC root key, see `icgeek_ic_certification`.
+        Some(KeyAlgorithm::CanisterSignature) | None => Err(EnvelopeError::UnsupportedPublicKey),
+    }
+}
+
+/// The challenge of the client data must be the base64url encoded message, and the
+/// authenticator signs `authenticator_data || sha256(client_data_json)` with the P-256 key.
+fn verify_webauthn_signature(
+    cose_public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), EnvelopeError> {
+    use p256::ecdsa::signature::Verifier;
+
+    #[derive(Deserialize)]
+    struct ClientData {
+        challenge: String,
+    }
+
+    let invalid_signature = || EnvelopeError::InvalidSignature("message".to_owned());
+
+    let public_key =
+        p256_public_key_from_cose(cose_public_key).map_err(EnvelopeError::InvalidPublicKey)?;
+    let verifying_key = p256::ecdsa::VerifyingKey::from_sec1_bytes(&public_key)
+        .map_err(|e| EnvelopeError::InvalidPublicKey(e.to_string()))?;
+
+    let webauthn_signature =
+        WebAuthnSignature::from_sender_sig(signature).map_err(|_| invalid_signature())?;
+    let client_data: ClientData = serde_json::from_str(&webauthn_signature.client_data_json)
+        .map_err(|_| invalid_signature())?;
+    let challenge =
+        Base64UrlUnpadded::decode_vec(&client_data.challenge).map_err(|_| invalid_signature())?;
+    if challenge != message {
+        return Err(invalid_signature());
+    }
+
+    // Authenticators do not normalize the signatures.
+    let signature = p256::ecdsa::Signature::from_der(&webauthn_signature.signature)
+        .map_err(|_| invalid_signature())?;
+    let signature = signature.normalize_s().unwrap_or(signature);
+
+    let signed_data = [
+        webauthn_signature.authenticator_data.as_slice(),
+        &get_sha256(webauthn_signature.client_data_json.as_bytes()),
+    ]
+    .concat();
+    verifying_key
+        .verify(&signed_data, &signature)
+        .map_err(|_| invalid_signature())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::envelope::{
+        decode_envelope, inspect_call_request, inspect_query_request, EnvelopeError, RequestContent,
+    };
+    use crate::public_key::p256_public_key_to_cose;
+    use crate::session::{Session, SessionKey};
+    use crate::sha256::get_sha256;
+    use crate::signature::WebAuthnSignature;
+    use crate::signer::{KeyAlgorithm, RawPublicKey, Signature, Signer};
+    use crate::test_utils::{Ctx, DevRandGenerator};
+    use async_trait::async_trait;
+    use base64ct::{Base64UrlUnpadded, Encoding};
+    use candid::Principal;
+    use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+
+    struct Secp256k1Signer;
+
+    #[async_trait]
+    impl Signer<Ctx> for Secp256k1Signer {
+        fn get_public_key(&self, _ctx: &Ctx) -> RawPublicKey {
+            let secret_key = SecretKey::from_slice(&[11; 32]).unwrap();
+            PublicKey::from_secret_key(&Secp256k1::new(), &secret_key)
+                .serialize_uncompressed()
+                .to_vec()
+        }
+
+        async fn sign(&self, _ctx: &Ctx, message_hash: &[u8]) -> Result<Signature, String> {
+            let secret_key = SecretKey::from_slice(&[11; 32]).unwrap();
+            let message = Message::from_slice(message_hash).unwrap();
+            let sig = Secp256k1::new().sign_ecdsa(&message, &secret_key);
+            Ok(sig.serialize_compact().to_vec())
+        }
+    }
+
+    /// P-256 authenticator answering `navigator.credentials.get` with the message as the challenge.
+    struct WebAuthnSigner;
+
+    impl WebAuthnSigner {
+        fn signing_key() -> p256::ecdsa::SigningKey {
+            p256::ecdsa::SigningKey::from_slice(&[13; 32]).unwrap()
+        }
+
+        fn assertion(challenge: &[u8]) -> WebAuthnSignature {
+            use p256::ecdsa::signature::Signer;
+
+            // rp id hash, flags (user present and verified) and signature counter
+            let authenticator_data = [
+                get_sha256("identity.ic0.app").as_slice(),
+                &[0x05, 0, 0, 0, 1],
+            ]
+            .concat();
+            let client_data_json = format!(
+                r#"{{"type":"webauthn.get","challenge":"{}","origin":"https://identity.ic0.app","crossOrigin":false}}"#,
+                Base64UrlUnpadded::encode_string(challenge)
+            );
+            let signature: p256::ecdsa::Signature = Self::signing_key().sign(
+                &[
+                    authenticator_data.as_slice(),
+                    &get_sha256(client_data_json.as_bytes()),
+                ]
+                .concat(),
+            );
+
+            WebAuthnSignature {
+                authenticator_data,
+                client_data_json,
+                signature: signature.to_der().as_bytes().to_vec(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Signer<Ctx> for WebAuthnSigner {
+        fn get_key_algorithm(&self, _ctx: &Ctx) -> KeyAlgorithm {
+            KeyAlgorithm::WebAuthn
+        }
+
+        fn get_public_key(&self, _ctx: &Ctx) -> RawPublicKey {
+            let public_key = Self::signing_key().verifying_key().to_encoded_point(false);
+            p256_public_key_to_cose(public_key.as_bytes()).unwrap()
+        }
+
+        async fn sign(&self, _ctx: &Ctx, message: &[u8]) -> Result<Signature, String> {
+            Self::assertion(message).to_sender_sig()
+        }
+    }
+
+    fn canister_id() -> Principal {
+        Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_secp256k1() {
+        let call_request = crate::create_call_request(
+            &Ctx,
+            &Secp256k1Signer,
+            &DevRandGenerator,
+            &canister_id(),
+            "transfer",
+            vec![1, 2, 3],
+        )
+        .await
+        .unwrap();
+
+        let (envelope, rs_envelope) = inspect_call_request(&call_request).unwrap();
+        assert!(matches!(envelope.content, RequestContent::Call(_)));
+        assert!(matches!(rs_envelope.content, RequestContent::ReadState(_)));
+        assert_eq!(envelope.request_id.as_slice(), call_request.request_id);
+
+        let query_request =
+            crate::create_query_request(&Ctx, &Secp256k1Signer, &canister_id(), "balance", vec![])
+                .await
+                .unwrap();
+        let envelope = inspect_query_request(&query_request).unwrap();
+        assert!(matches!(envelope.content, RequestContent::Query(_)));
+
+        let mut tampered = call_request.clone();
+        tampered.canister_id = Principal::management_canister();
+        assert!(matches!(
+            inspect_call_request(&tampered),
+            Err(EnvelopeError::CanisterMismatch { .. })
+        ));
+
+        let mut tampered = call_request.clone();
+        let arg_position = tampered
+            .request_sign
+            .windows(3)
+            .position(|w| w == [1, 2, 3])
+            .unwrap();
+        tampered.request_sign[arg_position] = 9;
+        assert!(matches!(
+            decode_envelope(&tampered.request_sign).unwrap().verify(),
+            Err(EnvelopeError::InvalidSignature(_))
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn test_delegation_chain() {
+        let root_key = SessionKey::generate(&DevRandGenerator).await.unwrap();
+        let session = Session::issue(&Ctx, &root_key, &DevRandGenerator, 1_000, None)
+            .await
+            .unwrap();
+
+        let query_request = crate::create_query_request(
+            &session.get_request_ctx(100),
+            &session.key,
+            &canister_id(),
+            "balance",
+            vec![],
+        )
+        .await
+        .unwrap();
+
+        let envelope = inspect_query_request(&query_request).unwrap();
+        assert_eq!(envelope.content.get_sender(), session.get_sender());
+        assert_eq!(envelope.sender_delegation.as_ref().unwrap().len(), 1);
+
+        let mut forged = envelope.clone();
+        forged.sender_delegation.as_mut().unwrap()[0]
+            .delegation
+            .expiration = 2_000;
+        assert_eq!(
+            forged.verify(),
+            Err(EnvelopeError::InvalidSignature("delegation 0".to_owned()))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_webauthn() {
+        let query_request =
+            crate::create_query_request(&Ctx, &WebAuthnSigner, &canister_id(), "balance", vec![])
+                .await
+                .unwrap();
+
+        let envelope = inspect_query_request(&query_request).unwrap();
+        let signature =
+            WebAuthnSignature::from_sender_sig(envelope.sender_sig.as_ref().unwrap()).unwrap();
+        assert!(signature
+            .client_data_json
+            .starts_with(r#"{"type":"webauthn.get""#));
+
+        // validly signed assertion of another challenge
+        let mut forged = envelope.clone();
+        forged.sender_sig = Some(
+            WebAuthnSigner::assertion(b"another message")
+                .to_sender_sig()
+                .unwrap(),
+        );
+        assert_eq!(
+            forged.verify(),
+            Err(EnvelopeError::InvalidSignature("request".to_owned()))
+        );
+
+        let mut forged = envelope.clone();
+        let mut tampered_signature = signature.clone();
+        tampered_signature.authenticator_data[32] = 0x01;
+        forged.sender_sig = Some(tampered_signature.to_sender_sig().unwrap());
+        assert_eq!(
+            forged.verify(),
+            Err(EnvelopeError::InvalidSignature("request".to_owned()))
+        );
+    }
+}
@@ -1,9 +1,19 @@
+pub mod batch;
+pub mod canister_sig;
+pub mod delegation;
+pub mod envelope;
 pub mod operations;
 pub mod public_key;
+pub mod read_state;
 pub mod render;
 pub mod request_id;
+pub mod session;
 pub mod sha256;
+pub mod signature;
 pub mod signer;
+#[cfg(test)]
+mod test_utils;
+pub mod typed;
 pub mod types;
 
 pub use operations::*;
@@ -1,11 +1,14 @@
-use crate::public_key::public_key_to_asn1_block;
+use crate::delegation::validate_delegation_chain;
+use crate::envelope::decode_envelope;
+use crate::public_key::public_key_to_der;
+use crate::read_state::request_status_path;
 use crate::render::RandGenerator;
-use crate::request_id::{to_request_id, RequestId};
-use crate::sha256::get_sha256;
-use crate::signer::{EcdsaSignatureCompact, MessageHash, Signer};
+use crate::request_id::{to_request_id_with, RequestId};
+use crate::sha256::{get_sha256_with, Sha256Algorithm, Sha256Hasher};
+use crate::signer::{KeyAlgorithm, Signature, Signer};
 use crate::types::{
     CallRequestContent, DeviceKey, Envelope, IngressExpiryDatetimeNanos, QueryContent,
-    ReadStateContent, SignedDelegation,
+    ReadStateContent, SenderInfo, SignedDelegation,
 };
 use candid::Principal;
 use ic_certification::Label;
@@ -15,7 +18,28 @@ use std::time::Duration;
 
 pub trait RequestCtx {
     fn get_ingress_expiry(&self) -> IngressExpiryDatetimeNanos;
-    fn get_delegation(&self) -> Option<(DeviceKey, SignedDelegation)>;
+
+    /// Current time in nanoseconds since the epoch, the delegations of the chain must not
+    /// be expired at it. By default it is the ingress expiry: the delegations must outlive
+    /// the request, as the replica may process it until then.
+    fn get_current_time(&self) -> u64 {
+        self.get_ingress_expiry()
+    }
+
+    fn get_delegation(&self) -> Option<(DeviceKey, SignedDelegation)> {
+        None
+    }
+
+    /// Sender information attached to the envelopes, see `types::SenderInfo`.
+    fn get_sender_info(&self) -> Option<SenderInfo> {
+        None
+    }
+
+    /// Sender public key and the ordered delegation chain from it to the signer key.
+    fn get_delegation_chain(&self) -> Option<(DeviceKey, Vec<SignedDelegation>)> {
+        self.get_delegation()
+            .map(|(sender_pubkey, delegation)| (sender_pubkey, vec![delegation]))
+    }
 }
 
 pub async fn create_query_request<C: RequestCtx>(
@@ -25,7 +49,20 @@ pub async fn create_query_request<C: RequestCtx>(
     method_name: &str,
     arg: Vec<u8>,
 ) -> Result<AgentQueryRequest, String> {
-    let (public_key, signed_delegation) = detect_public_key_and_delegations(signer, ctx);
+    create_query_request_with::<Sha256Algorithm, C>(ctx, signer, canister_id, method_name, arg)
+        .await
+}
+
+/// Same as `create_query_request`, hashing with the `H` hasher.
+pub async fn create_query_request_with<H: Sha256Hasher, C: RequestCtx>(
+    ctx: &C,
+    signer: &dyn Signer<C>,
+    canister_id: &Principal,
+    method_name: &str,
+    arg: Vec<u8>,
+) -> Result<AgentQueryRequest, String> {
+    let (public_key, signed_delegation) =
+        detect_public_key_and_delegations(signer, ctx, canister_id)?;
 
     let sender = Principal::self_authenticating(&public_key);
 
@@ -40,13 +77,14 @@ pub async fn create_query_request<C: RequestCtx>(
     )
     .await?;
 
-    let request_id = to_request_id(&request).map_err(|e| std::format!("{:?}", e))?;
+    let request_id = to_request_id_with::<H, _>(&request).map_err(|e| std::format!("{:?}", e))?;
 
-    let message_hash = construct_sign_message_hash(&request_id);
-    let sign_result = signer.sign(ctx, &message_hash).await?;
+    let message = construct_sign_message::<H>(signer.get_key_algorithm(ctx), &request_id);
+    let sign_result = signer.sign(ctx, &message).await?;
     let request_sign = serialize_envelope(
         public_key.clone(),
         signed_delegation.clone(),
+        ctx.get_sender_info(),
         sign_result,
         &request,
     )?;
@@ -65,7 +103,28 @@ pub async fn create_call_request<C: RequestCtx>(
     method_name: &str,
     arg: Vec<u8>,
 ) -> Result<AgentCallRequest, String> {
-    let (public_key, signed_delegation) = detect_public_key_and_delegations(signer, ctx);
+    create_call_request_with::<Sha256Algorithm, C>(
+        ctx,
+        signer,
+        rand_generator,
+        canister_id,
+        method_name,
+        arg,
+    )
+    .await
+}
+
+/// Same as `create_call_request`, hashing with the `H` hasher.
+pub async fn create_call_request_with<H: Sha256Hasher, C: RequestCtx>(
+    ctx: &C,
+    signer: &dyn Signer<C>,
+    rand_generator: &dyn RandGenerator,
+    canister_id: &Principal,
+    method_name: &str,
+    arg: Vec<u8>,
+) -> Result<AgentCallRequest, String> {
+    let (public_key, signed_delegation) =
+        detect_public_key_and_delegations(signer, ctx, canister_id)?;
 
     let sender = Principal::self_authenticating(&public_key);
 
@@ -81,65 +140,167 @@ pub async fn create_call_request<C: RequestCtx>(
     )
     .await?;
 
-    let request_id = to_request_id(&request).map_err(|e| std::format!("{:?}", e))?;
+    let request_id = to_request_id_with::<H, _>(&request).map_err(|e| std::format!("{:?}", e))?;
 
-    let message_hash = construct_sign_message_hash(&request_id);
-    let sign_result = signer.sign(ctx, &message_hash).await?;
+    let message = construct_sign_message::<H>(signer.get_key_algorithm(ctx), &request_id);
+    let sign_result = signer.sign(ctx, &message).await?;
     let request_sign = serialize_envelope(
         public_key.clone(),
         signed_delegation.clone(),
+        ctx.get_sender_info(),
         sign_result,
         &request,
     )?;
 
     // read state request sign
 
-    let rs_request = build_read_state_request(sender, &request_id, ctx.get_ingress_expiry());
-
-    let rs_request_id = to_request_id(&rs_request).map_err(|e| std::format!("{:?}", e))?;
-
-    let rs_message_hash = construct_sign_message_hash(&rs_request_id);
-    let rs_sign_result = signer.sign(ctx, &rs_message_hash).await?;
-    let read_state_request_sign =
-        serialize_envelope(public_key, signed_delegation, rs_sign_result, &rs_request)?;
+    let rs_request = build_read_state_request(
+        sender,
+        vec![request_status_path(&request_id)],
+        ctx.get_ingress_expiry(),
+    );
+
+    let rs_request_id =
+        to_request_id_with::<H, _>(&rs_request).map_err(|e| std::format!("{:?}", e))?;
+
+    let rs_message = construct_sign_message::<H>(signer.get_key_algorithm(ctx), &rs_request_id);
+    let rs_sign_result = signer.sign(ctx, &rs_message).await?;
+    let read_state_request_sign = serialize_envelope(
+        public_key,
+        signed_delegation,
+        ctx.get_sender_info(),
+        rs_sign_result,
+        &rs_request,
+    )?;
 
     Ok(AgentCallRequest {
         canister_id: *canister_id,
-        request_id: request_id.as_slice().to_vec(),
+        request_id: request_id.into(),
         request_sign,
         read_state_request_sign,
     })
 }
 
-fn detect_public_key_and_delegations<C: RequestCtx>(
+pub(crate) fn detect_public_key_and_delegations<C: RequestCtx>(
     signer: &dyn Signer<C>,
     ctx: &C,
-) -> (DeviceKey, Option<Vec<SignedDelegation>>) {
-    ctx.get_delegation()
-        .map(|(k, d)| (k, Some(vec![d])))
-        .unwrap_or_else(|| {
-            (
-                public_key_to_asn1_block(signer.get_uncompressed_public_key(ctx).as_slice()),
-                None,
+    canister_id: &Principal,
+) -> Result<(DeviceKey, Option<Vec<SignedDelegation>>), String> {
+    let signer_public_key = public_key_to_der(
+        signer.get_key_algorithm(ctx),
+        signer.get_public_key(ctx).as_slice(),
+    );
+    detect_sender_public_key(ctx, signer_public_key, canister_id)
+}
+
+/// Sender public key and the delegations to the DER encoded `signer_public_key`.
+pub(crate) fn detect_sender_public_key<C: RequestCtx>(
+    ctx: &C,
+    signer_public_key: DeviceKey,
+    canister_id: &Principal,
+) -> Result<(DeviceKey, Option<Vec<SignedDelegation>>), String> {
+    match ctx.get_delegation_chain() {
+        Some((sender_public_key, delegations)) => {
+            validate_delegation_chain(
+                &delegations,
+                &signer_public_key,
+                canister_id,
+                ctx.get_current_time(),
             )
-        })
+            .map_err(|e| format!("Delegation chain does not authorize the call: {e}"))?;
+
+            Ok((sender_public_key, Some(delegations)))
+        }
+        None => Ok((signer_public_key, None)),
+    }
+}
+
+/// Replicas reject requests expiring further than this in the future.
+pub const MAX_INGRESS_EXPIRY_DELTA: Duration = Duration::from_secs(300);
+
+const NANOS_PER_MINUTE: u64 = 60_000_000_000;
+
+/// How the ingress expiry of the signed requests is chosen.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct IngressExpiryPolicy {
+    /// Requested lifetime of the request, from the current time.
+    pub lifetime: Duration,
+    /// Subtracted from the expiry to tolerate the clock of the replica running behind.
+    pub permitted_drift: Duration,
+    /// Round the expiry down to the whole minute, so that repeated requests
+    /// share the expiry (and the replica caches) and leak less of the signing time.
+    pub round_to_minute: bool,
+}
+
+impl Default for IngressExpiryPolicy {
+    fn default() -> Self {
+        Self {
+            lifetime: Duration::from_secs(300),
+            permitted_drift: Duration::from_secs(60),
+            round_to_minute: false,
+        }
+    }
+}
+
+impl IngressExpiryPolicy {
+    /// Ingress expiry for the requests signed at `unix_epoch_time_nanos`.
+    /// The expiry is never further than `MAX_INGRESS_EXPIRY_DELTA` in the future.
+    pub fn get_ingress_expiry(&self, unix_epoch_time_nanos: u128) -> IngressExpiryDatetimeNanos {
+        let delta = self
+            .lifetime
+            .saturating_sub(self.permitted_drift)
+            .min(MAX_INGRESS_EXPIRY_DELTA);
+        let expiry = delta.as_nanos().saturating_add(unix_epoch_time_nanos) as u64;
+
+        if self.round_to_minute {
+            expiry - expiry % NANOS_PER_MINUTE
+        } else {
+            expiry
+        }
+    }
 }
 
 pub fn get_ingress_expiry_datetime_nanos(
     unix_epoch_time_nanos: u128,
 ) -> IngressExpiryDatetimeNanos {
-    let permitted_drift = Duration::from_secs(60);
-    let ingress_expiry_duration = Duration::from_secs(300);
+    IngressExpiryPolicy::default().get_ingress_expiry(unix_epoch_time_nanos)
+}
 
-    (ingress_expiry_duration
-        .as_nanos()
-        .saturating_add(unix_epoch_time_nanos)
-        .saturating_sub(permitted_drift.as_nanos())) as u64
+/// Ingress expiry of the signed request envelope.
+pub fn get_request_sign_ingress_expiry(
+    request_sign: &[u8],
+) -> Result<IngressExpiryDatetimeNanos, String> {
+    decode_envelope(request_sign)
+        .map(|envelope| envelope.content.get_ingress_expiry())
+        .map_err(|e| e.to_string())
+}
+
+/// Ingress expiry of the signed call request: the earliest of the call
+/// and the read_state envelopes expiries.
+pub fn get_call_request_ingress_expiry(
+    request: &AgentCallRequest,
+) -> Result<IngressExpiryDatetimeNanos, String> {
+    Ok(get_request_sign_ingress_expiry(&request.request_sign)?.min(
+        get_request_sign_ingress_expiry(&request.read_state_request_sign)?,
+    ))
+}
+
+/// How long the signed call request is still accepted by the replica,
+/// `None` if it has already expired at `unix_epoch_time_nanos`.
+pub fn get_call_request_remaining_validity(
+    request: &AgentCallRequest,
+    unix_epoch_time_nanos: u128,
+) -> Result<Option<Duration>, String> {
+    let ingress_expiry = get_call_request_ingress_expiry(request)? as u128;
+
+    Ok(ingress_expiry
+        .checked_sub(unix_epoch_time_nanos)
+        .map(|remaining| Duration::from_nanos(remaining as u64)))
 }
 
 // PRIVATE
 
-async fn build_query_request(
+pub(crate) async fn build_query_request(
     sender: &Principal,
     canister_id: &Principal,
     method_name: &str,
@@ -155,7 +316,7 @@ async fn build_query_request(
     })
 }
 
-async fn build_call_request(
+pub(crate) async fn build_call_request(
     rand_generator: &dyn RandGenerator,
     sender: &Principal,
     canister_id: &Principal,
@@ -175,13 +336,11 @@ async fn build_call_request(
     })
 }
 
-fn build_read_state_request(
+pub(crate) fn build_read_state_request(
     sender: Principal,
-    request_id: &RequestId,
+    paths: Vec<Vec<Label>>,
     ingress_expiry: IngressExpiryDatetimeNanos,
 ) -> ReadStateContent {
-    let paths: Vec<Vec<Label>> = vec![vec!["request_status".into(), request_id.to_vec().into()]];
-
     ReadStateContent::ReadStateRequest {
         sender,
         paths,
@@ -189,11 +348,26 @@ fn build_read_state_request(
     }
 }
 
-fn construct_sign_message_hash(request_id: &RequestId) -> MessageHash {
-    get_sha256(construct_message(request_id))
+pub(crate) fn construct_sign_message<H: Sha256Hasher>(
+    algorithm: KeyAlgorithm,
+    request_id: &RequestId,
+) -> Vec<u8> {
+    prepare_sign_message::<H>(algorithm, construct_message(request_id))
+}
+
+pub(crate) fn prepare_sign_message<H: Sha256Hasher>(
+    algorithm: KeyAlgorithm,
+    message: Vec<u8>,
+) -> Vec<u8> {
+    match algorithm {
+        KeyAlgorithm::EcdsaSecp256k1 | KeyAlgorithm::EcdsaSecp256r1 => {
+            get_sha256_with::<H>(message).to_vec()
+        }
+        KeyAlgorithm::Ed25519 | KeyAlgorithm::WebAuthn | KeyAlgorithm::CanisterSignature => message,
+    }
 }
 
-fn construct_message(request_id: &RequestId) -> Vec<u8> {
+pub(crate) fn construct_message(request_id: &RequestId) -> Vec<u8> {
     const IC_REQUEST_DOMAIN_SEPARATOR: &[u8; 11] = b"\x0Aic-request";
 
     let mut buf = vec![];
@@ -202,10 +376,11 @@ fn construct_message(request_id: &RequestId) -> Vec<u8> {
     buf
 }
 
-fn serialize_envelope<'a, V>(
+pub(crate) fn serialize_envelope<'a, V>(
     asn1_public_key: Vec<u8>,
     signed_delegation: Option<Vec<SignedDelegation>>,
-    signature: EcdsaSignatureCompact,
+    sender_info: Option<SenderInfo>,
+    signature: Signature,
     request: &V,
 ) -> Result<Vec<u8>, String>
 where
@@ -216,6 +391,7 @@ where
         sender_pubkey: Some(asn1_public_key),
         sender_delegation: signed_delegation,
         sender_sig: Some(signature),
+        sender_info,
     };
 
     let mut serialized_bytes = Vec::new();
@@ -231,33 +407,44 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::public_key::public_key_to_asn1_block;
-    use crate::render::RandGenerator;
+    use crate::public_key::{public_key_to_asn1_block, public_key_to_der};
     use crate::request_id::to_request_id;
-    use crate::signer::{EcdsaSignatureCompact, MessageHash, Signer, UncompressedPublicKey};
+    use crate::sha256::{Sha256Algorithm, Sha256Hash, Sha256Hasher};
+    use crate::signature::ecdsa_der_to_compact;
+    use crate::signer::{KeyAlgorithm, RawPublicKey, Signature, Signer};
+    use crate::test_utils::DevRandGenerator;
+    use crate::types::{
+        CallRequestContent, Delegation, DeviceKey, Envelope, IngressExpiryDatetimeNanos,
+        QueryContent, SignedDelegation,
+    };
+    use crate::{IngressExpiryPolicy, RequestCtx};
     use async_trait::async_trait;
     use candid::{Encode, Principal};
+    use ed25519_dalek::{SigningKey, Verifier};
     use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+    use std::cell::Cell;
+    use std::time::Duration;
 
+    #[derive(Default)]
     struct Ctx {
         pub key: Vec<u8>,
+        pub delegation_chain: Option<(DeviceKey, Vec<SignedDelegation>)>,
+        pub ingress_expiry: IngressExpiryDatetimeNanos,
     }
 
     struct DevSigner;
 
     #[async_trait]
     impl Signer<Ctx> for DevSigner {
-        fn get_uncompressed_public_key(&self, ctx: &Ctx) -> UncompressedPublicKey {
+        fn get_public_key(&self, ctx: &Ctx) -> RawPublicKey {
             let secp = Secp256k1::new();
             let secret_key = SecretKey::from_slice(ctx.key.as_slice()).unwrap();
-            PublicKey::from_secret_key(&secp, &secret_key).serialize_uncompressed()
+            PublicKey::from_secret_key(&secp, &secret_key)
+                .serialize_uncompressed()
+                .to_vec()
         }
 
-        async fn sign(
-            &self,
-            ctx: &Ctx,
-            message_hash: &MessageHash,
-        ) -> Result<EcdsaSignatureCompact, String> {
+        async fn sign(&self, ctx: &Ctx, message_hash: &[u8]) -> Result<Signature, String> {
             let secp = Secp256k1::new();
             let secret_key = SecretKey::from_slice(ctx.key.as_slice()).unwrap();
             let message = Message::from_slice(message_hash).unwrap();
@@ -266,16 +453,68 @@ mod tests {
         }
     }
 
-    pub struct DevRandGenerator;
+    struct DevEd25519Signer;
+
+    impl DevEd25519Signer {
+        fn signing_key(ctx: &Ctx) -> SigningKey {
+            SigningKey::from_bytes(ctx.key.as_slice().try_into().unwrap())
+        }
+    }
+
+    #[async_trait]
+    impl Signer<Ctx> for DevEd25519Signer {
+        fn get_key_algorithm(&self, _ctx: &Ctx) -> KeyAlgorithm {
+            KeyAlgorithm::Ed25519
+        }
+
+        fn get_public_key(&self, ctx: &Ctx) -> RawPublicKey {
+            Self::signing_key(ctx).verifying_key().to_bytes().to_vec()
+        }
+
+        async fn sign(&self, ctx: &Ctx, message: &[u8]) -> Result<Signature, String> {
+            use ed25519_dalek::Signer;
+            Ok(Self::signing_key(ctx).sign(message).to_bytes().to_vec())
+        }
+    }
+
+    struct DevP256Signer;
+
+    impl DevP256Signer {
+        fn signing_key(ctx: &Ctx) -> p256::ecdsa::SigningKey {
+            p256::ecdsa::SigningKey::from_slice(ctx.key.as_slice()).unwrap()
+        }
+    }
 
     #[async_trait]
-    impl RandGenerator for DevRandGenerator {
-        async fn generate_16(&self) -> Result<Vec<u8>, String> {
-            Ok([0; 16].to_vec())
+    impl Signer<Ctx> for DevP256Signer {
+        fn get_key_algorithm(&self, _ctx: &Ctx) -> KeyAlgorithm {
+            KeyAlgorithm::EcdsaSecp256r1
+        }
+
+        fn get_public_key(&self, ctx: &Ctx) -> RawPublicKey {
+            Self::signing_key(ctx)
+                .verifying_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec()
+        }
+
+        async fn sign(&self, ctx: &Ctx, message_hash: &[u8]) -> Result<Signature, String> {
+            use p256::ecdsa::signature::hazmat::PrehashSigner;
+            let signature: p256::ecdsa::Signature =
+                Self::signing_key(ctx).sign_prehash(message_hash).unwrap();
+            // emulate HSM which produces DER encoded signatures
+            ecdsa_der_to_compact(signature.to_der().as_bytes())
+        }
+    }
+
+    impl RequestCtx for Ctx {
+        fn get_ingress_expiry(&self) -> IngressExpiryDatetimeNanos {
+            self.ingress_expiry
         }
 
-        async fn generate_32(&self) -> Result<Vec<u8>, String> {
-            Ok([0; 32].to_vec())
+        fn get_delegation_chain(&self) -> Option<(DeviceKey, Vec<SignedDelegation>)> {
+            self.delegation_chain.clone()
         }
     }
 
@@ -287,8 +526,11 @@ mod tests {
         ];
 
         let signer = DevSigner {};
-        let ctx = Ctx { key: ecdsa_key };
-        let public_key = signer.get_uncompressed_public_key(&ctx);
+        let ctx = Ctx {
+            key: ecdsa_key,
+            ..Default::default()
+        };
+        let public_key = signer.get_public_key(&ctx);
         let asn1_public_key = public_key_to_asn1_block(public_key.as_slice());
         assert_eq!(
             asn1_public_key,
@@ -327,9 +569,14 @@ mod tests {
 
         // let sign_result = super::sign_request_id(&signer, &public_key, &request_id).await.unwrap();
         //
-        let request_sign =
-            super::serialize_envelope(asn1_public_key, None, [0_u8; 64].to_vec(), &call_request)
-                .unwrap();
+        let request_sign = super::serialize_envelope(
+            asn1_public_key,
+            None,
+            None,
+            [0_u8; 64].to_vec(),
+            &call_request,
+        )
+        .unwrap();
         assert_eq!(
             request_sign,
             vec![
@@ -354,4 +601,284 @@ mod tests {
             ]
         );
     }
+
+    #[actix_rt::test]
+    async fn test_ed25519() {
+        let signer = DevEd25519Signer {};
+        let ctx = Ctx {
+            key: [3_u8; 32].to_vec(),
+            ..Default::default()
+        };
+        let canister_id = Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap();
+
+        let call_request = super::create_call_request(
+            &ctx,
+            &signer,
+            &DevRandGenerator {},
+            &canister_id,
+            "transfer",
+            Encode!(&()).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let envelope: Envelope<CallRequestContent> =
+            serde_cbor::from_slice(&call_request.request_sign).unwrap();
+        let sender_pubkey = envelope.sender_pubkey.unwrap();
+        assert_eq!(
+            sender_pubkey,
+            public_key_to_der(KeyAlgorithm::Ed25519, &signer.get_public_key(&ctx))
+        );
+
+        let CallRequestContent::CallRequest { sender, .. } = &envelope.content;
+        assert_eq!(*sender, Principal::self_authenticating(&sender_pubkey));
+
+        let request_id = to_request_id(&envelope.content).unwrap();
+        assert_eq!(request_id.as_slice(), call_request.request_id.as_slice());
+
+        let verifying_key = DevEd25519Signer::signing_key(&ctx).verifying_key();
+        let signature =
+            ed25519_dalek::Signature::from_slice(&envelope.sender_sig.unwrap()).unwrap();
+        verifying_key
+            .verify(&super::construct_message(&request_id), &signature)
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_p256() {
+        let signer = DevP256Signer {};
+        let ctx = Ctx {
+            key: [3_u8; 32].to_vec(),
+            ..Default::default()
+        };
+        let canister_id = Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap();
+
+        let query_request = super::create_query_request(
+            &ctx,
+            &signer,
+            &canister_id,
+            "balance",
+            Encode!(&()).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let envelope: Envelope<QueryContent> =
+            serde_cbor::from_slice(&query_request.request_sign).unwrap();
+        assert_eq!(
+            envelope.sender_pubkey.unwrap(),
+            public_key_to_der(KeyAlgorithm::EcdsaSecp256r1, &signer.get_public_key(&ctx))
+        );
+
+        let request_id = to_request_id(&envelope.content).unwrap();
+        let signature = p256::ecdsa::Signature::from_slice(&envelope.sender_sig.unwrap()).unwrap();
+        DevP256Signer::signing_key(&ctx)
+            .verifying_key()
+            .verify(&super::construct_message(&request_id), &signature)
+            .unwrap();
+    }
+
+    thread_local! {
+        static HASHES: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Default hasher counting the computed hashes.
+    #[derive(Default)]
+    struct CountingHasher {
+        hasher: Sha256Algorithm,
+    }
+
+    impl Sha256Hasher for CountingHasher {
+        fn update(&mut self, data: impl AsRef<[u8]>) {
+            self.hasher.update(data);
+        }
+
+        fn finish(&self) -> Sha256Hash {
+            HASHES.with(|hashes| hashes.set(hashes.get() + 1));
+            self.hasher.finish()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_call_request_with_hasher() {
+        let signer = DevP256Signer {};
+        let ctx = Ctx {
+            key: [3_u8; 32].to_vec(),
+            ..Default::default()
+        };
+        let canister_id = Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap();
+
+        let call_request = super::create_call_request_with::<CountingHasher, Ctx>(
+            &ctx,
+            &signer,
+            &DevRandGenerator {},
+            &canister_id,
+            "transfer",
+            Encode!(&()).unwrap(),
+        )
+        .await
+        .unwrap();
+        // request ids, field hashes and the prehashed messages of both requests
+        assert!(HASHES.with(|hashes| hashes.get()) > 4);
+
+        let envelope: Envelope<CallRequestContent> =
+            serde_cbor::from_slice(&call_request.request_sign).unwrap();
+        let request_id = to_request_id(&envelope.content).unwrap();
+        assert_eq!(request_id.as_slice(), call_request.request_id.as_slice());
+
+        let signature = p256::ecdsa::Signature::from_slice(&envelope.sender_sig.unwrap()).unwrap();
+        DevP256Signer::signing_key(&ctx)
+            .verifying_key()
+            .verify(&super::construct_message(&request_id), &signature)
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_delegation_chain() {
+        let signer = DevEd25519Signer {};
+        let key = [3_u8; 32].to_vec();
+        let signer_public_key = public_key_to_der(
+            KeyAlgorithm::Ed25519,
+            &signer.get_public_key(&Ctx {
+                key: key.clone(),
+                ..Default::default()
+            }),
+        );
+        let canister_id = Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap();
+
+        let delegation = |pubkey: Vec<u8>, targets: Option<Vec<Principal>>| SignedDelegation {
+            delegation: Delegation {
+                pubkey,
+                expiration: 10,
+                targets,
+            },
+            signature: vec![1, 2, 3],
+        };
+        let chain = vec![
+            delegation(vec![5; 44], None),
+            delegation(signer_public_key, Some(vec![canister_id])),
+        ];
+
+        let ctx = Ctx {
+            key: key.clone(),
+            delegation_chain: Some((vec![4; 44], chain.clone())),
+            ..Default::default()
+        };
+        let query_request =
+            super::create_query_request(&ctx, &signer, &canister_id, "balance", vec![])
+                .await
+                .unwrap();
+        let envelope: Envelope<QueryContent> =
+            serde_cbor::from_slice(&query_request.request_sign).unwrap();
+        assert_eq!(envelope.sender_pubkey, Some(vec![4; 44]));
+        assert_eq!(envelope.sender_delegation.unwrap().len(), 2);
+
+        let error = super::create_query_request(
+            &ctx,
+            &signer,
+            &Principal::management_canister(),
+            "balance",
+            vec![],
+        )
+        .await
+        .unwrap_err();
+        assert!(error.starts_with("Delegation chain does not authorize the call"));
+
+        // the delegations must outlive the request
+        let ctx = Ctx {
+            ingress_expiry: 10,
+            ..ctx
+        };
+        assert!(
+            super::create_query_request(&ctx, &signer, &canister_id, "balance", vec![])
+                .await
+                .is_ok()
+        );
+
+        let ctx = Ctx {
+            ingress_expiry: 11,
+            ..ctx
+        };
+        let error = super::create_query_request(&ctx, &signer, &canister_id, "balance", vec![])
+            .await
+            .unwrap_err();
+        assert!(error.contains("Delegation 0 expired at 10, before the current time 11"));
+
+        // the delegation expired a second ago is refused for the request created now
+        let now = 1_700_000_000_000_000_000_u64;
+        let expired_chain: Vec<SignedDelegation> = chain
+            .into_iter()
+            .map(|mut signed_delegation| {
+                signed_delegation.delegation.expiration = now - 1_000_000_000;
+                signed_delegation
+            })
+            .collect();
+        let ctx = Ctx {
+            delegation_chain: Some((vec![4; 44], expired_chain)),
+            ingress_expiry: super::get_ingress_expiry_datetime_nanos(now as u128),
+            ..ctx
+        };
+        let error = super::create_query_request(&ctx, &signer, &canister_id, "balance", vec![])
+            .await
+            .unwrap_err();
+        assert!(error.contains("Delegation 0 expired at 1699999999000000000"));
+    }
+
+    #[test]
+    fn test_ingress_expiry_policy() {
+        let now = 1_700_000_030_000_000_000_u128;
+
+        assert_eq!(
+            super::get_ingress_expiry_datetime_nanos(now),
+            1_700_000_270_000_000_000
+        );
+
+        let policy = IngressExpiryPolicy {
+            round_to_minute: true,
+            ..Default::default()
+        };
+        assert_eq!(policy.get_ingress_expiry(now), 1_700_000_220_000_000_000);
+
+        let policy = IngressExpiryPolicy {
+            lifetime: Duration::from_secs(3_600),
+            permitted_drift: Duration::from_secs(0),
+            round_to_minute: false,
+        };
+        assert_eq!(policy.get_ingress_expiry(now), 1_700_000_330_000_000_000);
+    }
+
+    #[actix_rt::test]
+    async fn test_call_request_validity() {
+        let ctx = Ctx {
+            key: [3_u8; 32].to_vec(),
+            ingress_expiry: 5_000,
+            ..Default::default()
+        };
+        let canister_id = Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap();
+
+        let call_request = super::create_call_request(
+            &ctx,
+            &DevEd25519Signer {},
+            &DevRandGenerator {},
+            &canister_id,
+            "transfer",
+            Encode!(&()).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            super::get_call_request_ingress_expiry(&call_request),
+            Ok(5_000)
+        );
+        assert_eq!(
+            super::get_call_request_remaining_validity(&call_request, 4_000),
+            Ok(Some(Duration::from_nanos(1_000)))
+        );
+        assert_eq!(
+            super::get_call_request_remaining_validity(&call_request, 5_001),
+            Ok(None)
+        );
+        assert!(super::get_request_sign_ingress_expiry(&[1, 2, 3]).is_err());
+    }
 }
@@ -1,30 +1,79 @@
+use crate::signer::KeyAlgorithm;
 use sec1::der::asn1::{BitString, ObjectIdentifier};
 use sec1::der::{Decodable, Decoder, Encodable, Result, Sequence};
+use serde_cbor::Value;
+use std::collections::BTreeMap;
+
+const EC_PUBLIC_KEY_OID: &str = "1.2.840.10045.2.1";
+const SECP256K1_OID: &str = "1.3.132.0.10";
+const SECP256R1_OID: &str = "1.2.840.10045.3.1.7";
+const ED25519_OID: &str = "1.3.101.112";
+const COSE_OID: &str = "1.3.6.1.4.1.56387.1.1";
+const CANISTER_SIG_OID: &str = "1.3.6.1.4.1.56387.1.2";
+
+const COSE_KEY_TYPE: i128 = 1;
+const COSE_KEY_TYPE_EC2: i128 = 2;
+const COSE_ALGORITHM: i128 = 3;
+const COSE_ALGORITHM_ES256: i128 = -7;
+const COSE_EC2_CURVE: i128 = -1;
+const COSE_EC2_CURVE_P256: i128 = 1;
+const COSE_EC2_X: i128 = -2;
+const COSE_EC2_Y: i128 = -3;
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct MetaData {
-    pub ec_public_key_id: ObjectIdentifier,
-    pub secp256k1_id: ObjectIdentifier,
+    pub algorithm_id: ObjectIdentifier,
+    pub curve_id: Option<ObjectIdentifier>,
 }
 
 impl MetaData {
-    fn new() -> Self {
-        Self {
-            ec_public_key_id: "1.2.840.10045.2.1".parse::<ObjectIdentifier>().unwrap(),
-            secp256k1_id: "1.3.132.0.10".parse::<ObjectIdentifier>().unwrap(),
+    fn new(algorithm: KeyAlgorithm) -> Self {
+        match algorithm {
+            KeyAlgorithm::EcdsaSecp256k1 => Self {
+                algorithm_id: EC_PUBLIC_KEY_OID.parse::<ObjectIdentifier>().unwrap(),
+                curve_id: Some(SECP256K1_OID.parse::<ObjectIdentifier>().unwrap()),
+            },
+            KeyAlgorithm::EcdsaSecp256r1 => Self {
+                algorithm_id: EC_PUBLIC_KEY_OID.parse::<ObjectIdentifier>().unwrap(),
+                curve_id: Some(SECP256R1_OID.parse::<ObjectIdentifier>().unwrap()),
+            },
+            KeyAlgorithm::Ed25519 => Self {
+                algorithm_id: ED25519_OID.parse::<ObjectIdentifier>().unwrap(),
+                curve_id: None,
+            },
+            KeyAlgorithm::WebAuthn => Self {
+                algorithm_id: COSE_OID.parse::<ObjectIdentifier>().unwrap(),
+                curve_id: None,
+            },
+            KeyAlgorithm::CanisterSignature => Self {
+                algorithm_id: CANISTER_SIG_OID.parse::<ObjectIdentifier>().unwrap(),
+                curve_id: None,
+            },
         }
     }
+
+    pub fn key_algorithm(&self) -> Option<KeyAlgorithm> {
+        [
+            KeyAlgorithm::EcdsaSecp256k1,
+            KeyAlgorithm::EcdsaSecp256r1,
+            KeyAlgorithm::Ed25519,
+            KeyAlgorithm::WebAuthn,
+            KeyAlgorithm::CanisterSignature,
+        ]
+        .into_iter()
+        .find(|algorithm| MetaData::new(*algorithm) == *self)
+    }
 }
 
 impl<'a> Decodable<'a> for MetaData {
     fn decode(decoder: &mut Decoder<'a>) -> Result<Self> {
         decoder.sequence(|decoder| {
-            let ec_public_key_id = decoder.decode()?;
-            let secp256k1_id = decoder.decode()?;
+            let algorithm_id = decoder.decode()?;
+            let curve_id = decoder.decode()?;
 
             Ok(Self {
-                ec_public_key_id,
-                secp256k1_id,
+                algorithm_id,
+                curve_id,
             })
         })
     }
@@ -35,7 +84,7 @@ impl<'a> Sequence<'a> for MetaData {
     where
         F: FnOnce(&[&dyn Encodable]) -> Result<T>,
     {
-        field_encoder(&[&self.ec_public_key_id, &self.secp256k1_id])
+        field_encoder(&[&self.algorithm_id, &self.curve_id])
     }
 }
 
@@ -46,9 +95,9 @@ pub struct Asn1PublicKey<'a> {
 }
 
 impl<'a> Asn1PublicKey<'a> {
-    fn new(pk: &'a [u8]) -> Self {
+    fn new(algorithm: KeyAlgorithm, pk: &'a [u8]) -> Self {
         Self {
-            meta_data: MetaData::new(),
+            meta_data: MetaData::new(algorithm),
             data: BitString::from_bytes(pk).unwrap(),
         }
     }
@@ -74,13 +123,63 @@ impl<'a> Sequence<'a> for Asn1PublicKey<'a> {
     }
 }
 
+/// DER encoded `SubjectPublicKeyInfo` of the secp256k1 uncompressed public key.
 pub fn public_key_to_asn1_block(public_key: &[u8]) -> Vec<u8> {
-    Asn1PublicKey::new(public_key).to_vec().unwrap()
+    public_key_to_der(KeyAlgorithm::EcdsaSecp256k1, public_key)
+}
+
+/// DER encoded `SubjectPublicKeyInfo` of the raw public key of the algorithm.
+pub fn public_key_to_der(algorithm: KeyAlgorithm, public_key: &[u8]) -> Vec<u8> {
+    Asn1PublicKey::new(algorithm, public_key).to_vec().unwrap()
+}
+
+/// COSE encoded ES256 key of the P-256 uncompressed public key, as it is
+/// stored (DER wrapped) in the Internet Identity `DeviceData.pubkey`.
+pub fn p256_public_key_to_cose(public_key: &[u8]) -> std::result::Result<Vec<u8>, String> {
+    if public_key.len() != 65 || public_key[0] != 0x04 {
+        return Err("P-256 public key must be uncompressed 65 bytes point".to_owned());
+    }
+
+    let key = BTreeMap::from([
+        (COSE_KEY_TYPE, Value::Integer(COSE_KEY_TYPE_EC2)),
+        (COSE_ALGORITHM, Value::Integer(COSE_ALGORITHM_ES256)),
+        (COSE_EC2_CURVE, Value::Integer(COSE_EC2_CURVE_P256)),
+        (COSE_EC2_X, Value::Bytes(public_key[1..33].to_vec())),
+        (COSE_EC2_Y, Value::Bytes(public_key[33..].to_vec())),
+    ]);
+
+    serde_cbor::to_vec(&key).map_err(|e| e.to_string())
+}
+
+/// P-256 uncompressed public key of the COSE encoded ES256 key.
+pub fn p256_public_key_from_cose(cose_key: &[u8]) -> std::result::Result<Vec<u8>, String> {
+    let key: BTreeMap<i128, Value> = serde_cbor::from_slice(cose_key).map_err(|e| e.to_string())?;
+
+    let check = |label: i128, expected: i128| match key.get(&label) {
+        Some(Value::Integer(value)) if *value == expected => Ok(()),
+        _ => Err(format!(
+            "unsupported COSE key, wrong value of label {label}"
+        )),
+    };
+    check(COSE_KEY_TYPE, COSE_KEY_TYPE_EC2)?;
+    check(COSE_ALGORITHM, COSE_ALGORITHM_ES256)?;
+    check(COSE_EC2_CURVE, COSE_EC2_CURVE_P256)?;
+
+    let coordinate = |label: i128| match key.get(&label) {
+        Some(Value::Bytes(value)) if value.len() == 32 => Ok(value.as_slice()),
+        _ => Err(format!("unsupported COSE key, wrong coordinate {label}")),
+    };
+
+    let mut public_key = vec![0x04];
+    public_key.extend_from_slice(coordinate(COSE_EC2_X)?);
+    public_key.extend_from_slice(coordinate(COSE_EC2_Y)?);
+    Ok(public_key)
 }
 
 #[cfg(test)]
 mod tests {
     use crate::public_key::Asn1PublicKey;
+    use crate::signer::KeyAlgorithm;
     use sec1::der::Decodable;
 
     #[test]
@@ -96,4 +195,57 @@ mod tests {
                 .unwrap()
         );
     }
+
+    #[test]
+    fn test_ed25519() {
+        let pk = [7_u8; 32];
+        let der_pk = super::public_key_to_der(KeyAlgorithm::Ed25519, pk.as_slice());
+        assert_eq!(der_pk[..12], [48, 42, 48, 5, 6, 3, 43, 101, 112, 3, 33, 0]);
+
+        let asn1_pk = Asn1PublicKey::from_der(der_pk.as_slice()).unwrap();
+        assert_eq!(
+            asn1_pk.meta_data.key_algorithm(),
+            Some(KeyAlgorithm::Ed25519)
+        );
+        assert_eq!(pk.as_slice(), asn1_pk.data.as_bytes().unwrap());
+    }
+
+    #[test]
+    fn test_p256() {
+        let pk = [4_u8; 65];
+        let der_pk = super::public_key_to_der(KeyAlgorithm::EcdsaSecp256r1, pk.as_slice());
+        assert_eq!(
+            hex::encode(&der_pk[..26]),
+            "3059301306072a8648ce3d020106082a8648ce3d030107034200"
+        );
+
+        let asn1_pk = Asn1PublicKey::from_der(der_pk.as_slice()).unwrap();
+        assert_eq!(
+            asn1_pk.meta_data.key_algorithm(),
+            Some(KeyAlgorithm::EcdsaSecp256r1)
+        );
+    }
+
+    #[test]
+    fn test_cose() {
+        let mut pk = vec![4_u8];
+        pk.extend([1_u8; 32]);
+        pk.extend([2_u8; 32]);
+
+        let cose_pk = super::p256_public_key_to_cose(&pk).unwrap();
+        assert_eq!(super::p256_public_key_from_cose(&cose_pk).unwrap(), pk);
+
+        let der_pk = super::public_key_to_der(KeyAlgorithm::WebAuthn, &cose_pk);
+        assert_eq!(
+            hex::encode(&der_pk[..19]),
+            "305e300c060a2b0601040183b8430101034e00"
+        );
+
+        let asn1_pk = Asn1PublicKey::from_der(der_pk.as_slice()).unwrap();
+        assert_eq!(
+            asn1_pk.meta_data.key_algorithm(),
+            Some(KeyAlgorithm::WebAuthn)
+        );
+        assert_eq!(asn1_pk.data.as_bytes().unwrap(), cose_pk.as_slice());
+    }
 }
@@ -0,0 +1,167 @@
+use crate::operations::{
+    build_read_state_request, construct_sign_message, detect_public_key_and_delegations,
+    serialize_envelope,
+};
+use crate::request_id::{to_request_id_with, RequestId};
+use crate::sha256::{Sha256Algorithm, Sha256Hasher};
+use crate::signer::Signer;
+use crate::RequestCtx;
+use candid::Principal;
+use ic_certification::Label;
+use icgeek_ic_call_api::AgentRequestSign;
+
+/// Path of the certified tree, see
+/// https://internetcomputer.org/docs/current/references/ic-interface-spec#state-tree
+pub type StatePath = Vec<Label>;
+
+/// Signed read_state request, to be sent to `/api/v2/canister/<effective_canister_id>/read_state`.
+#[derive(Debug, Clone)]
+pub struct AgentReadStateRequest {
+    pub effective_canister_id: Principal,
+    pub request_sign: AgentRequestSign,
+}
+
+/// Sign the read_state request of the arbitrary `paths`. The delegation chain of the `ctx`
+/// (if any) must authorize the `effective_canister_id`.
+pub async fn create_read_state_request<C: RequestCtx>(
+    ctx: &C,
+    signer: &dyn Signer<C>,
+    effective_canister_id: &Principal,
+    paths: Vec<StatePath>,
+) -> Result<AgentReadStateRequest, String> {
+    create_read_state_request_with::<Sha256Algorithm, C>(ctx, signer, effective_canister_id, paths)
+        .await
+}
+
+/// Same as `create_read_state_request`, hashing with the `H` hasher.
+pub async fn create_read_state_request_with<H: Sha256Hasher, C: RequestCtx>(
+    ctx: &C,
+    signer: &dyn Signer<C>,
+    effective_canister_id: &Principal,
+    paths: Vec<StatePath>,
+) -> Result<AgentReadStateRequest, String> {
+    if paths.is_empty() {
+        return Err("read_state request must contain at least one path".to_owned());
+    }
+
+    let (public_key, signed_delegation) =
+        detect_public_key_and_delegations(signer, ctx, effective_canister_id)?;
+
+    let sender = Principal::self_authenticating(&public_key);
+
+    let request = build_read_state_request(sender, paths, ctx.get_ingress_expiry());
+
+    let request_id = to_request_id_with::<H, _>(&request).map_err(|e| std::format!("{:?}", e))?;
+
+    let message = construct_sign_message::<H>(signer.get_key_algorithm(ctx), &request_id);
+    let sign_result = signer.sign(ctx, &message).await?;
+    let request_sign = serialize_envelope(
+        public_key,
+        signed_delegation,
+        ctx.get_sender_info(),
+        sign_result,
+        &request,
+    )?;
+
+    Ok(AgentReadStateRequest {
+        effective_canister_id: *effective_canister_id,
+        request_sign,
+    })
+}
+
+/// `time`
+pub fn time_path() -> StatePath {
+    vec!["time".into()]
+}
+
+/// `request_status/<request_id>`
+pub fn request_status_path(request_id: &RequestId) -> StatePath {
+    vec!["request_status".into(), request_id.to_vec().into()]
+}
+
+/// `canister/<canister_id>/module_hash`
+pub fn canister_module_hash_path(canister_id: &Principal) -> StatePath {
+    canister_path(canister_id, "module_hash")
+}
+
+/// `canister/<canister_id>/controllers`
+pub fn canister_controllers_path(canister_id: &Principal) -> StatePath {
+    canister_path(canister_id, "controllers")
+}
+
+/// `canister/<canister_id>/metadata/<name>`
+pub fn canister_metadata_path(canister_id: &Principal, name: &str) -> StatePath {
+    let mut path = canister_path(canister_id, "metadata");
+    path.push(name.into());
+    path
+}
+
+/// `subnet/<subnet_id>/<field>`, e.g. `public_key`, `canister_ranges` or `node`.
+pub fn subnet_path(subnet_id: &Principal, field: &str) -> StatePath {
+    vec!["subnet".into(), subnet_id.as_slice().into(), field.into()]
+}
+
+fn canister_path(canister_id: &Principal, field: &str) -> StatePath {
+    vec![
+        "canister".into(),
+        canister_id.as_slice().into(),
+        field.into(),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::envelope::{decode_envelope, RequestContent};
+    use crate::read_state::{
+        canister_controllers_path, canister_metadata_path, create_read_state_request, time_path,
+    };
+    use crate::test_utils::{Ctx, Ed25519Signer};
+    use crate::types::ReadStateContent;
+    use candid::Principal;
+    use ic_certification::Label;
+
+    #[actix_rt::test]
+    async fn test() {
+        let canister_id = Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap();
+        let paths = vec![
+            canister_controllers_path(&canister_id),
+            canister_metadata_path(&canister_id, "candid:service"),
+            time_path(),
+        ];
+
+        let request =
+            create_read_state_request(&Ctx, &Ed25519Signer::default(), &canister_id, paths.clone())
+                .await
+                .unwrap();
+        assert_eq!(request.effective_canister_id, canister_id);
+
+        let envelope = decode_envelope(&request.request_sign).unwrap();
+        envelope.verify().unwrap();
+
+        let RequestContent::ReadState(ReadStateContent::ReadStateRequest {
+            paths: signed_paths,
+            ingress_expiry,
+            ..
+        }) = &envelope.content
+        else {
+            panic!("unexpected content");
+        };
+        assert_eq!(*signed_paths, paths);
+        assert_eq!(*ingress_expiry, 100);
+        assert_eq!(
+            signed_paths[1],
+            vec![
+                Label::from("canister"),
+                Label::from(canister_id.as_slice()),
+                Label::from("metadata"),
+                Label::from("candid:service"),
+            ]
+        );
+
+        assert!(
+            create_read_state_request(&Ctx, &Ed25519Signer::default(), &canister_id, vec![])
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,4 +1,9 @@
+use crate::sha256::get_sha256;
 use async_trait::async_trait;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
 
 #[async_trait]
 pub trait RandGenerator: Sync + Send {
@@ -6,3 +11,300 @@ pub trait RandGenerator: Sync + Send {
 
     async fn generate_32(&self) -> Result<Vec<u8>, String>;
 }
+
+/// Number of bytes generated from one `raw_rand` seed before fetching the next one.
+#[cfg(feature = "canister")]
+pub const DEFAULT_RESEED_INTERVAL: usize = 64 * 1024;
+
+/// Canister generator: ChaCha20 stream seeded by the management canister `raw_rand`,
+/// so that one management call serves many nonces. The seed is refreshed after
+/// `reseed_interval` generated bytes. Requires the `canister` feature.
+#[cfg(feature = "canister")]
+pub struct RawRandGenerator {
+    pool: Mutex<EntropyPool>,
+}
+
+#[cfg(feature = "canister")]
+impl RawRandGenerator {
+    pub fn new(reseed_interval: usize) -> Self {
+        Self {
+            pool: Mutex::new(EntropyPool::new(reseed_interval)),
+        }
+    }
+
+    async fn generate(&self, len: usize) -> Result<Vec<u8>, String> {
+        if let Some(bytes) = self.lock()?.take(len) {
+            return Ok(bytes);
+        }
+
+        // The lock is not held while awaiting the management canister.
+        let (seed,) = ic_cdk::api::management_canister::main::raw_rand()
+            .await
+            .map_err(|(code, message)| format!("raw_rand failed: {code:?} {message}"))?;
+
+        let mut pool = self.lock()?;
+        pool.reseed(&seed)?;
+        pool.take(len)
+            .ok_or_else(|| "Entropy pool is exhausted right after reseed".to_owned())
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, EntropyPool>, String> {
+        self.pool.lock().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "canister")]
+impl Default for RawRandGenerator {
+    fn default() -> Self {
+        Self::new(DEFAULT_RESEED_INTERVAL)
+    }
+}
+
+#[cfg(feature = "canister")]
+#[async_trait]
+impl RandGenerator for RawRandGenerator {
+    async fn generate_16(&self) -> Result<Vec<u8>, String> {
+        self.generate(16).await
+    }
+
+    async fn generate_32(&self) -> Result<Vec<u8>, String> {
+        self.generate(32).await
+    }
+}
+
+#[cfg(feature = "canister")]
+struct EntropyPool {
+    rng: Option<ChaCha20Rng>,
+    remaining: usize,
+    reseed_interval: usize,
+}
+
+#[cfg(feature = "canister")]
+impl EntropyPool {
+    fn new(reseed_interval: usize) -> Self {
+        Self {
+            rng: None,
+            remaining: 0,
+            reseed_interval,
+        }
+    }
+
+    fn reseed(&mut self, seed: &[u8]) -> Result<(), String> {
+        let seed: [u8; 32] = seed
+            .try_into()
+            .map_err(|_| format!("Seed must be 32 bytes, got {}", seed.len()))?;
+        self.rng = Some(ChaCha20Rng::from_seed(seed));
+        self.remaining = self.reseed_interval;
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> Option<Vec<u8>> {
+        if self.remaining < len {
+            return None;
+        }
+        let rng = self.rng.as_mut()?;
+
+        let mut bytes = vec![0; len];
+        rng.fill_bytes(&mut bytes);
+        self.remaining -= len;
+        Some(bytes)
+    }
+}
+
+/// ChaCha20 stream of the caller provided 32 bytes seed.
+pub struct ChaChaRandGenerator {
+    rng: Mutex<ChaCha20Rng>,
+}
+
+impl ChaChaRandGenerator {
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Self {
+            rng: Mutex::new(ChaCha20Rng::from_seed(seed)),
+        }
+    }
+
+    fn generate(&self, len: usize) -> Result<Vec<u8>, String> {
+        let mut bytes = vec![0; len];
+        self.rng
+            .lock()
+            .map_err(|e| e.to_string())?
+            .fill_bytes(&mut bytes);
+        Ok(bytes)
+    }
+}
+
+#[async_trait]
+impl RandGenerator for ChaChaRandGenerator {
+    async fn generate_16(&self) -> Result<Vec<u8>, String> {
+        self.generate(16)
+    }
+
+    async fn generate_32(&self) -> Result<Vec<u8>, String> {
+        self.generate(32)
+    }
+}
+
+/// Reproducible generator for test vectors: the n-th output is the prefix of
+/// `sha256(seed || n)`, with the seed and the counter as little endian u64.
+pub struct DeterministicRandGenerator {
+    seed: u64,
+    counter: Mutex<u64>,
+}
+
+impl DeterministicRandGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            counter: Mutex::new(0),
+        }
+    }
+
+    fn generate(&self, len: usize) -> Result<Vec<u8>, String> {
+        let mut counter = self.counter.lock().map_err(|e| e.to_string())?;
+        let hash = get_sha256([self.seed.to_le_bytes(), counter.to_le_bytes()].concat());
+        *counter += 1;
+        Ok(hash[..len].to_vec())
+    }
+}
+
+#[async_trait]
+impl RandGenerator for DeterministicRandGenerator {
+    async fn generate_16(&self) -> Result<Vec<u8>, String> {
+        self.generate(16)
+    }
+
+    async fn generate_32(&self) -> Result<Vec<u8>, String> {
+        self.generate(32)
+    }
+}
+
+/// Wrapper detecting the reuse of the call nonces (the `generate_16` outputs)
+/// among the last `capacity` generated ones. A reused nonce is an error, since the
+/// replica would deduplicate the call as the same request.
+pub struct NonceReuseGuard<G> {
+    generator: G,
+    capacity: usize,
+    issued: Mutex<IssuedNonces>,
+}
+
+#[derive(Default)]
+struct IssuedNonces {
+    seen: HashSet<Vec<u8>>,
+    order: VecDeque<Vec<u8>>,
+}
+
+impl<G: RandGenerator> NonceReuseGuard<G> {
+    pub fn new(generator: G, capacity: usize) -> Self {
+        Self {
+            generator,
+            capacity,
+            issued: Mutex::new(IssuedNonces::default()),
+        }
+    }
+}
+
+#[async_trait]
+impl<G: RandGenerator> RandGenerator for NonceReuseGuard<G> {
+    async fn generate_16(&self) -> Result<Vec<u8>, String> {
+        let nonce = self.generator.generate_16().await?;
+
+        let mut issued = self.issued.lock().map_err(|e| e.to_string())?;
+        if !issued.seen.insert(nonce.clone()) {
+            return Err(format!("Nonce reuse detected: {}", hex::encode(&nonce)));
+        }
+        issued.order.push_back(nonce.clone());
+        if issued.order.len() > self.capacity {
+            if let Some(oldest) = issued.order.pop_front() {
+                issued.seen.remove(&oldest);
+            }
+        }
+
+        Ok(nonce)
+    }
+
+    async fn generate_32(&self) -> Result<Vec<u8>, String> {
+        self.generator.generate_32().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::render::{
+        ChaChaRandGenerator, DeterministicRandGenerator, NonceReuseGuard, RandGenerator,
+    };
+    use crate::sha256::get_sha256;
+
+    #[actix_rt::test]
+    async fn test_chacha() {
+        let first = ChaChaRandGenerator::from_seed([1; 32]);
+        let second = ChaChaRandGenerator::from_seed([1; 32]);
+
+        let nonce = first.generate_16().await.unwrap();
+        assert_eq!(nonce, second.generate_16().await.unwrap());
+        assert_ne!(nonce, first.generate_16().await.unwrap());
+        assert_eq!(first.generate_32().await.unwrap().len(), 32);
+
+        let other = ChaChaRandGenerator::from_seed([2; 32]);
+        assert_ne!(nonce, other.generate_16().await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn test_deterministic() {
+        let generator = DeterministicRandGenerator::new(7);
+
+        let first = get_sha256([7_u64.to_le_bytes(), 0_u64.to_le_bytes()].concat());
+        let second = get_sha256([7_u64.to_le_bytes(), 1_u64.to_le_bytes()].concat());
+        assert_eq!(generator.generate_16().await.unwrap(), first[..16]);
+        assert_eq!(generator.generate_32().await.unwrap(), second);
+    }
+
+    #[cfg(feature = "canister")]
+    #[test]
+    fn test_entropy_pool() {
+        use crate::render::EntropyPool;
+
+        let mut pool = EntropyPool::new(40);
+        assert_eq!(pool.take(16), None);
+
+        pool.reseed(&[3; 32]).unwrap();
+        let first = pool.take(16).unwrap();
+        let second = pool.take(16).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(pool.take(16), None);
+        assert_eq!(pool.take(8).unwrap().len(), 8);
+
+        pool.reseed(&[3; 32]).unwrap();
+        assert_eq!(pool.take(16).unwrap(), first);
+
+        assert!(pool.reseed(&[3; 16]).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_nonce_reuse_guard() {
+        let guard = NonceReuseGuard::new(DeterministicRandGenerator::new(1), 2);
+        let nonces = [
+            guard.generate_16().await.unwrap(),
+            guard.generate_16().await.unwrap(),
+        ];
+        assert_ne!(nonces[0], nonces[1]);
+
+        struct ConstantRandGenerator;
+
+        #[async_trait::async_trait]
+        impl RandGenerator for ConstantRandGenerator {
+            async fn generate_16(&self) -> Result<Vec<u8>, String> {
+                Ok([0; 16].to_vec())
+            }
+
+            async fn generate_32(&self) -> Result<Vec<u8>, String> {
+                Ok([0; 32].to_vec())
+            }
+        }
+
+        let guard = NonceReuseGuard::new(ConstantRandGenerator, 2);
+        guard.generate_16().await.unwrap();
+        let error = guard.generate_16().await.unwrap_err();
+        assert!(error.starts_with("Nonce reuse detected"));
+        guard.generate_32().await.unwrap();
+    }
+}
@@ -1,11 +1,10 @@
 //! Error type for the RequestId calculation.
 use thiserror::Error;
 
-/// Errors from reading a RequestId from a string. This is not the same as
-/// deserialization.
+/// Errors from reading a RequestId from a string or bytes.
 #[derive(Error, Debug)]
 pub enum RequestIdFromStringError {
-    #[error("Invalid string size: {0}. Must be even.")]
+    #[error("Invalid request id size: {0} bytes, must be 32.")]
     InvalidSize(usize),
 
     #[error("Error while decoding hex: {0}")]
@@ -26,8 +25,11 @@ pub enum RequestIdError {
     #[error("RequestId Serializer was in an invalid state")]
     InvalidState,
 
-    #[error("RequestId does not support struct inside other structs")]
-    UnsupportedStructInsideStruct,
+    #[error("RequestId must be computed from a struct or a map")]
+    UnsupportedRootType,
+
+    #[error("Absent (None) values are not supported inside sequences")]
+    UnsupportedNoneInsideSequence,
 
     #[error("Unsupported type: Bool")]
     UnsupportedTypeBool,
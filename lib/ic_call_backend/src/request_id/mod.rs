@@ -1,15 +1,19 @@
-use std::{collections::BTreeMap, iter::Extend, str::FromStr};
+use std::{collections::BTreeMap, fmt, str::FromStr};
 
-use crate::sha256::{Sha256Algorithm, Sha256Hash};
-use serde::{ser, Deserialize, Serialize};
+use crate::sha256::{Sha256Algorithm, Sha256Hash, Sha256Hasher};
+use icgeek_ic_call_api::AgentRequestId;
+use serde::{de, ser, Deserialize, Deserializer, Serialize};
+use std::marker::PhantomData;
 
-pub use error::RequestIdError;
-use error::RequestIdFromStringError;
+pub use error::{RequestIdError, RequestIdFromStringError};
 
 pub mod error;
 
-/// A Request ID.
-#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Deserialize, Serialize)]
+/// A Request ID: the representation-independent hash of the request content.
+///
+/// Displayed and serialized in the human readable formats (e.g. JSON) as the hex string,
+/// serialized as the 32 bytes blob otherwise.
+#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
 pub struct RequestId(Sha256Hash);
 
 impl RequestId {
@@ -21,7 +25,7 @@ impl RequestId {
         &self.0
     }
 
-    pub(crate) fn to_vec(self) -> Vec<u8> {
+    pub fn to_vec(self) -> Vec<u8> {
         self.0.to_vec()
     }
 }
@@ -30,14 +34,32 @@ impl FromStr for RequestId {
     type Err = RequestIdFromStringError;
 
     fn from_str(from: &str) -> Result<Self, Self::Err> {
-        let mut blob: Sha256Hash = [0; 32];
         let vec = hex::decode(from).map_err(RequestIdFromStringError::FromHexError)?;
-        if vec.len() != 32 {
-            return Err(RequestIdFromStringError::InvalidSize(vec.len()));
-        }
+        RequestId::try_from(vec.as_slice())
+    }
+}
+
+impl TryFrom<&[u8]> for RequestId {
+    type Error = RequestIdFromStringError;
+
+    fn try_from(from: &[u8]) -> Result<Self, Self::Error> {
+        from.try_into()
+            .map(RequestId)
+            .map_err(|_| RequestIdFromStringError::InvalidSize(from.len()))
+    }
+}
+
+impl TryFrom<&AgentRequestId> for RequestId {
+    type Error = RequestIdFromStringError;
+
+    fn try_from(from: &AgentRequestId) -> Result<Self, Self::Error> {
+        RequestId::try_from(from.as_slice())
+    }
+}
 
-        blob.copy_from_slice(vec.as_slice());
-        Ok(RequestId::new(&blob))
+impl From<RequestId> for AgentRequestId {
+    fn from(id: RequestId) -> AgentRequestId {
+        id.to_vec()
     }
 }
 
@@ -47,180 +69,162 @@ impl From<RequestId> for String {
     }
 }
 
-enum Hasher {
-    /// The hasher for the overall request id.  This is the only part
-    /// that may directly contain a Struct.
-    RequestId(Sha256Algorithm),
-
-    /// A structure to be included in the hash.  May not contain other structures.
-    Struct {
-        // We use a BTreeMap here as there is no indication that keys might not be duplicated,
-        // and we want to make sure they're overwritten in that case.
-        fields: BTreeMap<Sha256Hash, Sha256Hash>,
-        parent: Box<Hasher>,
-    },
-
-    /// The hasher for a value.  Array elements will append the hash of their
-    /// contents into the hasher of the array.
-    Value(Sha256Algorithm),
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
 }
 
-impl Hasher {
-    fn request_id() -> Hasher {
-        Hasher::RequestId(Sha256Algorithm::default())
+impl Serialize for RequestId {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
     }
+}
 
-    fn fields(parent: Box<Hasher>) -> Hasher {
-        Hasher::Struct {
-            fields: BTreeMap::new(),
-            parent,
+impl<'de> Deserialize<'de> for RequestId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            RequestId::from_str(&hex).map_err(de::Error::custom)
+        } else {
+            let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
+            RequestId::try_from(bytes.as_slice()).map_err(de::Error::custom)
         }
     }
+}
 
-    fn value() -> Hasher {
-        Hasher::Value(Sha256Algorithm::default())
+/// Result of hashing a single value.
+enum Hashed {
+    /// `None` value: the field is absent from the map.
+    Absent,
+    Value(Sha256Hash),
+    /// Hash of a structure or a map, the only values that may be request ids.
+    Map(Sha256Hash),
+}
+
+impl Hashed {
+    fn into_hash(self) -> Option<Sha256Hash> {
+        match self {
+            Hashed::Absent => None,
+            Hashed::Value(hash) | Hashed::Map(hash) => Some(hash),
+        }
     }
 }
 
-/// A Serde Serializer that collects fields and values in order to hash them later.
-/// We serialize the type to this structure, then use the trait to hash its content.
-/// It is a simple state machine that contains 3 states:
-///   1. The root value, which is a structure. If a value other than a structure is
-///      serialized, this errors. This is determined by whether `fields` is Some(_).
-///   2. The structure is being processed, and the value of a field is being
-///      serialized. The field_value_hash will be set to Some(_).
-///   3. The finish() function has been called and the hasher cannot be reused. The
-///      hash should have been gotten at this point.
+/// A Serde Serializer computing the representation-independent hash of a value, see
+/// https://internetcomputer.org/docs/current/references/ic-interface-spec#hash-of-map
 ///
-/// Inconsistent state are when a field is being serialized and `fields` is None, or
-/// when a value (not struct) is being serialized and field_value_hash is None.
+/// The supported types are:
+///   . Strings, byte strings (blobs) and principals: the hash of their bytes.
+///   . Unsigned integers: the hash of their LEB128 encoding.
+///   . Signed integers: the hash of their SLEB128 encoding.
+///   . Sequences and tuples: the hash of the concatenation of the element hashes.
+///   . Structures and maps (nested at any level): the hash of the sorted concatenation
+///     of the key and value hashes. The fields with `None` values are absent.
+///   . Newtype structs are transparent. Enum variants are hashed as the map from the
+///     variant name to their content, unit variants as their name.
 ///
-/// This will always fail on types that are unknown to the Request format (e.g. i8).
-/// An UnsupportedTypeXXX error will be returned.
-///
-/// The only types that are supported right now are:
-///   . Strings and string slices.
-///   . Vector of u8 (byte strings).
-///   . A structure as the base level. Its typename and fields are not validated.
-///
-/// Additionally, this will fail if there are unsupported data structure, for example
-/// if a UnitVariant of another type than Blob is used, or a structure inside a
-/// structure.
+/// Booleans, floats, chars and units are not defined by the specification and
+/// an UnsupportedTypeXXX error is returned for them.
 ///
 /// This does not validate whether a message is valid. This is very important as
 /// the message format might change faster than the ID calculation.
-struct RequestIdSerializer {
-    element_encoder: Option<Hasher>,
+struct RequestIdSerializer<H>(PhantomData<H>);
+
+/// Hasher of the elements of a sequence, a tuple or a tuple variant.
+struct SeqHasher<H> {
+    hasher: H,
+    variant: Option<&'static str>,
 }
 
-impl RequestIdSerializer {
-    pub fn new() -> RequestIdSerializer {
-        Default::default()
-    }
+/// Hasher of the fields of a structure, a map or a struct variant.
+struct MapHasher<H> {
+    // We use a BTreeMap here as there is no indication that keys might not be duplicated,
+    // and we want to make sure they're overwritten in that case.
+    // It also keeps the fields sorted by the key hash.
+    fields: BTreeMap<Sha256Hash, Sha256Hash>,
+    key: Option<Sha256Hash>,
+    variant: Option<&'static str>,
+    hasher: PhantomData<H>,
+}
 
-    /// Finish the hashing and returns the RequestId for the structure that was
-    /// serialized.
-    ///
-    /// This can only be called once (it borrows self). Since this whole class is not public,
-    /// it should not be a problem.
-    pub fn finish(self) -> Result<RequestId, RequestIdError> {
-        match self.element_encoder {
-            Some(Hasher::RequestId(hasher)) => Ok(RequestId(hasher.finish())),
-            _ => Err(RequestIdError::EmptySerializer),
-        }
-    }
+fn hash_value<H, T>(value: &T) -> Result<Hashed, RequestIdError>
+where
+    H: Sha256Hasher,
+    T: ?Sized + Serialize,
+{
+    value.serialize(RequestIdSerializer::<H>(PhantomData))
+}
 
-    /// Hash a single value, returning its sha256_hash. If there is already a value
-    /// being hashed it will return an InvalidState. This cannot happen currently
-    /// as we don't allow embedded structures, but is left as a safeguard when
-    /// making changes.
-    fn hash_value<T>(&mut self, value: &T) -> Result<Sha256Hash, RequestIdError>
-    where
-        T: ?Sized + Serialize,
-    {
-        let prev_encoder = self.element_encoder.take();
+fn hash_bytes<H: Sha256Hasher>(v: &[u8]) -> Hashed {
+    let mut hasher = H::default();
+    hasher.update(v);
+    Hashed::Value(hasher.finish())
+}
 
-        self.element_encoder = Some(Hasher::value());
+fn hash_fields<'a, H: Sha256Hasher>(
+    fields: impl IntoIterator<Item = (&'a Sha256Hash, &'a Sha256Hash)>,
+) -> Sha256Hash {
+    let mut hasher = H::default();
+    for (key, value) in fields {
+        hasher.update(key);
+        hasher.update(value);
+    }
+    hasher.finish()
+}
 
-        value.serialize(&mut *self)?;
-        let result = match self.element_encoder.take() {
-            Some(Hasher::Value(hasher)) => Ok(hasher.finish()),
-            _ => Err(RequestIdError::InvalidState),
-        };
-        self.element_encoder = prev_encoder;
-        result
-    }
-
-    fn hash_fields(&mut self) -> Result<(), RequestIdError> {
-        match self.element_encoder.take() {
-            Some(Hasher::Struct { fields, parent }) => {
-                // Sort the fields.
-                let mut keyvalues: Vec<Vec<u8>> = fields
-                    .keys()
-                    .zip(fields.values())
-                    .map(|(k, v)| {
-                        let mut x = k.to_vec();
-                        x.extend(v);
-                        x
-                    })
-                    .collect();
-                keyvalues.sort();
-
-                let mut parent = *parent;
-
-                match &mut parent {
-                    Hasher::RequestId(hasher) => {
-                        for kv in keyvalues {
-                            hasher.update(&kv);
-                        }
-                        Ok(())
-                    }
-                    _ => Err(RequestIdError::InvalidState),
-                }?;
-
-                self.element_encoder = Some(parent);
-                Ok(())
-            }
-            _ => Err(RequestIdError::InvalidState),
+/// Map of the single `variant` name to the `content` hash.
+fn hash_variant<H: Sha256Hasher>(variant: &'static str, content: Sha256Hash) -> Hashed {
+    let key = hash_bytes::<H>(variant.as_bytes())
+        .into_hash()
+        .unwrap_or_default();
+    Hashed::Map(hash_fields::<H>([(&key, &content)]))
+}
+
+fn leb128_unsigned(mut v: u128) -> Vec<u8> {
+    let mut buffer = vec![];
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buffer.push(byte);
+            return buffer;
         }
+        buffer.push(byte | 0x80);
     }
 }
 
-impl Default for RequestIdSerializer {
-    fn default() -> RequestIdSerializer {
-        RequestIdSerializer {
-            element_encoder: Some(Hasher::request_id()),
+fn leb128_signed(mut v: i128) -> Vec<u8> {
+    let mut buffer = vec![];
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        let sign_bit_clear = byte & 0x40 == 0;
+        if (v == 0 && sign_bit_clear) || (v == -1 && !sign_bit_clear) {
+            buffer.push(byte);
+            return buffer;
         }
+        buffer.push(byte | 0x80);
     }
 }
 
 /// See https://serde.rs/data-format.html for more information on how to implement a
 /// custom data format.
-impl<'a> ser::Serializer for &'a mut RequestIdSerializer {
-    /// The output type produced by this `Serializer` during successful
-    /// serialization. Most serializers that produce text or binary output
-    /// should set `Ok = ()` and serialize into an [`io::Write`] or buffer
-    /// contained within the `Serializer` instance. Serializers that build
-    /// in-memory data structures may be simplified by using `Ok` to propagate
-    /// the data structure around.
-    ///
-    /// [`io::Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
-    type Ok = ();
-
-    /// The error type when some error occurs during serialization.
+impl<H: Sha256Hasher> ser::Serializer for RequestIdSerializer<H> {
+    type Ok = Hashed;
     type Error = RequestIdError;
 
-    // Associated types for keeping track of additional state while serializing
-    // compound data structures like sequences and maps. In this case no
-    // additional state is required beyond what is already stored in the
-    // Serializer struct.
-    type SerializeSeq = Self;
-    type SerializeTuple = Self;
-    type SerializeTupleStruct = Self;
-    type SerializeTupleVariant = Self;
-    type SerializeMap = Self;
-    type SerializeStruct = Self;
-    type SerializeStructVariant = Self;
+    type SerializeSeq = SeqHasher<H>;
+    type SerializeTuple = SeqHasher<H>;
+    type SerializeTupleStruct = SeqHasher<H>;
+    type SerializeTupleVariant = SeqHasher<H>;
+    type SerializeMap = MapHasher<H>;
+    type SerializeStruct = MapHasher<H>;
+    type SerializeStructVariant = MapHasher<H>;
 
     /// Serialize a `bool` value.
     fn serialize_bool(self, _v: bool) -> Result<Self::Ok, Self::Error> {
@@ -228,48 +232,53 @@ impl<'a> ser::Serializer for &'a mut RequestIdSerializer {
     }
 
     /// Serialize an `i8` value.
-    fn serialize_i8(self, _v: i8) -> Result<Self::Ok, Self::Error> {
-        Err(RequestIdError::UnsupportedTypeI8)
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i128(v as i128)
     }
 
     /// Serialize an `i16` value.
-    fn serialize_i16(self, _v: i16) -> Result<Self::Ok, Self::Error> {
-        Err(RequestIdError::UnsupportedTypeI16)
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i128(v as i128)
     }
 
     /// Serialize an `i32` value.
-    fn serialize_i32(self, _v: i32) -> Result<Self::Ok, Self::Error> {
-        Err(RequestIdError::UnsupportedTypeI32)
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i128(v as i128)
     }
 
     /// Serialize an `i64` value.
-    fn serialize_i64(self, _v: i64) -> Result<Self::Ok, Self::Error> {
-        Err(RequestIdError::UnsupportedTypeI64)
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        self.serialize_i128(v as i128)
+    }
+
+    /// Serialize an `i128` value.
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+        self.serialize_bytes(&leb128_signed(v))
     }
 
     /// Serialize a `u8` value.
     fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
-        self.serialize_u64(v as u64)
+        self.serialize_u128(v as u128)
     }
 
     /// Serialize a `u16` value.
     fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
-        self.serialize_u64(v as u64)
+        self.serialize_u128(v as u128)
     }
 
     /// Serialize a `u32` value.
     fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
-        self.serialize_u64(v as u64)
+        self.serialize_u128(v as u128)
     }
 
     /// Serialize a `u64` value.
     fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
-        // 10 bytes is enough for a 64-bit number in leb128.
-        let mut buffer = [0; 10];
-        let mut writable = &mut buffer[..];
-        let n_bytes =
-            leb128::write::unsigned(&mut writable, v).expect("Could not serialize number.");
-        self.serialize_bytes(&buffer[..n_bytes])
+        self.serialize_u128(v as u128)
+    }
+
+    /// Serialize a `u128` value.
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        self.serialize_bytes(&leb128_unsigned(v))
     }
 
     /// Serialize an `f32` value.
@@ -294,23 +303,13 @@ impl<'a> ser::Serializer for &'a mut RequestIdSerializer {
 
     /// Serialize a chunk of raw byte data.
     fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
-        match &mut self.element_encoder {
-            Some(Hasher::RequestId(hasher)) => {
-                hasher.update(v);
-                Ok(())
-            }
-            Some(Hasher::Value(hasher)) => {
-                hasher.update(v);
-                Ok(())
-            }
-            _ => Err(RequestIdError::InvalidState),
-        }
+        Ok(hash_bytes::<H>(v))
     }
 
     /// Serialize a [`None`] value.
     fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
-        // Compute the hash as if it was empty string or blob.
-        Ok(())
+        // The field is absent.
+        Ok(Hashed::Absent)
     }
 
     /// Serialize a [`Some(T)`] value.
@@ -337,23 +336,21 @@ impl<'a> ser::Serializer for &'a mut RequestIdSerializer {
         self,
         _name: &'static str,
         _variant_index: u32,
-        _variant: &'static str,
+        variant: &'static str,
     ) -> Result<Self::Ok, Self::Error> {
-        Err(RequestIdError::UnsupportedTypeUnitVariant)
+        self.serialize_str(variant)
     }
 
     /// Serialize a newtype struct like `struct Millimeters(u8)`.
     fn serialize_newtype_struct<T: ?Sized>(
         self,
-        name: &'static str,
-        _value: &T,
+        _name: &'static str,
+        value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
         T: Serialize,
     {
-        Err(RequestIdError::UnsupportedTypeNewtypeStruct(
-            name.to_owned(),
-        ))
+        value.serialize(self)
     }
 
     /// Serialize a newtype variant like `E::N` in `enum E { N(u8) }`.
@@ -361,58 +358,55 @@ impl<'a> ser::Serializer for &'a mut RequestIdSerializer {
         self,
         _name: &'static str,
         _variant_index: u32,
-        _variant: &'static str,
-        _value: &T,
+        variant: &'static str,
+        value: &T,
     ) -> Result<Self::Ok, Self::Error>
     where
         T: Serialize,
     {
-        Err(RequestIdError::UnsupportedTypeNewTypeVariant)
+        match hash_value::<H, T>(value)?.into_hash() {
+            Some(content) => Ok(hash_variant::<H>(variant, content)),
+            None => Ok(Hashed::Map(hash_fields::<H>([]))),
+        }
     }
 
     /// Begin to serialize a variably sized sequence. This call must be
     /// followed by zero or more calls to `serialize_element`, then a call to
     /// `end`.
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
-        Ok(self)
+        Ok(SeqHasher::new(None))
     }
 
-    /// Begin to serialize a statically sized sequence whose length will be
-    /// known at deserialization time without looking at the serialized data.
-    /// This call must be followed by zero or more calls to `serialize_element`,
-    /// then a call to `end`.
+    /// Begin to serialize a statically sized sequence, hashed as an array.
     fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
-        Err(RequestIdError::UnsupportedTypeTuple)
+        Ok(SeqHasher::new(None))
     }
 
-    /// Begin to serialize a tuple struct like `struct Rgb(u8, u8, u8)`. This
-    /// call must be followed by zero or more calls to `serialize_field`, then a
-    /// call to `end`.
+    /// Begin to serialize a tuple struct like `struct Rgb(u8, u8, u8)`, hashed as an array.
     fn serialize_tuple_struct(
         self,
         _name: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeTupleStruct, Self::Error> {
-        Err(RequestIdError::UnsupportedTypeTupleStruct)
+        Ok(SeqHasher::new(None))
     }
 
-    /// Begin to serialize a tuple variant like `E::T` in `enum E { T(u8, u8)
-    /// }`. This call must be followed by zero or more calls to
-    /// `serialize_field`, then a call to `end`.
+    /// Begin to serialize a tuple variant like `E::T` in `enum E { T(u8, u8) }`,
+    /// hashed as the map from the variant name to the array.
     fn serialize_tuple_variant(
         self,
         _name: &'static str,
         _variant_index: u32,
-        _variant: &'static str,
+        variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeTupleVariant, Self::Error> {
-        Err(RequestIdError::UnsupportedTypeTupleVariant)
+        Ok(SeqHasher::new(Some(variant)))
     }
 
     /// Begin to serialize a map. This call must be followed by zero or more
     /// calls to `serialize_key` and `serialize_value`, then a call to `end`.
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
-        Err(RequestIdError::UnsupportedTypeMap)
+        Ok(MapHasher::new(None))
     }
 
     /// Begin to serialize a struct like `struct Rgb { r: u8, g: u8, b: u8 }`.
@@ -423,27 +417,19 @@ impl<'a> ser::Serializer for &'a mut RequestIdSerializer {
         _name: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeStruct, Self::Error> {
-        let parent_encoder = self.element_encoder.take();
-        match &parent_encoder {
-            Some(Hasher::RequestId(_)) => {
-                self.element_encoder = Some(Hasher::fields(Box::new(parent_encoder.unwrap())));
-                Ok(self)
-            }
-            _ => Err(RequestIdError::UnsupportedStructInsideStruct),
-        }
+        Ok(MapHasher::new(None))
     }
 
     /// Begin to serialize a struct variant like `E::S` in `enum E { S { r: u8,
-    /// g: u8, b: u8 } }`. This call must be followed by zero or more calls to
-    /// `serialize_field`, then a call to `end`.
+    /// g: u8, b: u8 } }`, hashed as the map from the variant name to the structure.
     fn serialize_struct_variant(
         self,
         _name: &'static str,
         _variant_index: u32,
-        _variant: &'static str,
+        variant: &'static str,
         _len: usize,
     ) -> Result<Self::SerializeStructVariant, Self::Error> {
-        Err(RequestIdError::UnsupportedTypeStructVariant)
+        Ok(MapHasher::new(Some(variant)))
     }
 
     fn is_human_readable(&self) -> bool {
@@ -451,204 +437,198 @@ impl<'a> ser::Serializer for &'a mut RequestIdSerializer {
     }
 }
 
-// The following 7 impls deal with the serialization of compound types like
-// sequences and maps. Serialization of such types is begun by a Serializer
-// method and followed by zero or more calls to serialize individual elements of
-// the compound type and one call to end the compound type.
-//
-// This impl is SerializeSeq so these methods are called after `serialize_seq`
-// is called on the Serializer.
-impl<'a> ser::SerializeSeq for &'a mut RequestIdSerializer {
-    // Must match the `Ok` type of the serializer.
-    type Ok = ();
-    // Must match the `Error` type of the serializer.
-    type Error = RequestIdError;
+impl<H: Sha256Hasher> SeqHasher<H> {
+    fn new(variant: Option<&'static str>) -> Self {
+        Self {
+            hasher: H::default(),
+            variant,
+        }
+    }
 
-    // Serialize a single element of the sequence.
-    fn serialize_element<T>(&mut self, value: &T) -> Result<Self::Ok, Self::Error>
+    fn add_element<T>(&mut self, value: &T) -> Result<(), RequestIdError>
     where
         T: ?Sized + Serialize,
     {
-        let mut prev_encoder = self.element_encoder.take();
+        let hash = hash_value::<H, T>(value)?
+            .into_hash()
+            .ok_or(RequestIdError::UnsupportedNoneInsideSequence)?;
+        self.hasher.update(hash);
+        Ok(())
+    }
 
-        self.element_encoder = Some(Hasher::value());
+    fn finish(self) -> Hashed {
+        let hash = self.hasher.finish();
+        match self.variant {
+            Some(variant) => hash_variant::<H>(variant, hash),
+            None => Hashed::Value(hash),
+        }
+    }
+}
 
-        value.serialize(&mut **self)?;
+impl<H: Sha256Hasher> MapHasher<H> {
+    fn new(variant: Option<&'static str>) -> Self {
+        Self {
+            fields: BTreeMap::new(),
+            key: None,
+            variant,
+            hasher: PhantomData,
+        }
+    }
 
-        let value_encoder = self.element_encoder.take();
-        let hash = match value_encoder {
-            Some(Hasher::Value(hasher)) => Ok(hasher.finish()),
-            _ => Err(RequestIdError::InvalidState),
-        }?;
+    fn add_field(&mut self, key: Sha256Hash, value: Hashed) {
+        if let Some(value) = value.into_hash() {
+            self.fields.insert(key, value);
+        }
+    }
 
-        self.element_encoder = prev_encoder.take();
-        match &mut self.element_encoder {
-            Some(Hasher::Value(hasher)) => {
-                hasher.update(hash);
-                Ok(())
-            }
-            _ => Err(RequestIdError::InvalidState),
+    fn finish(self) -> Hashed {
+        let hash = hash_fields::<H>(&self.fields);
+        match self.variant {
+            Some(variant) => hash_variant::<H>(variant, hash),
+            None => Hashed::Map(hash),
         }
     }
+}
+
+impl<H: Sha256Hasher> ser::SerializeSeq for SeqHasher<H> {
+    type Ok = Hashed;
+    type Error = RequestIdError;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+    where
+        T: ?Sized + Serialize,
+    {
+        self.add_element(value)
+    }
 
-    // Close the sequence.
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+        Ok(self.finish())
     }
 }
 
-// Same thing but for tuples.
-impl<'a> ser::SerializeTuple for &'a mut RequestIdSerializer {
-    type Ok = ();
+impl<H: Sha256Hasher> ser::SerializeTuple for SeqHasher<H> {
+    type Ok = Hashed;
     type Error = RequestIdError;
 
-    fn serialize_element<T>(&mut self, _value: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        Err(RequestIdError::UnsupportedTypeTuple)
+        self.add_element(value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+        Ok(self.finish())
     }
 }
 
-// Same thing but for tuple structs.
-impl<'a> ser::SerializeTupleStruct for &'a mut RequestIdSerializer {
-    type Ok = ();
+impl<H: Sha256Hasher> ser::SerializeTupleStruct for SeqHasher<H> {
+    type Ok = Hashed;
     type Error = RequestIdError;
 
-    fn serialize_field<T>(&mut self, _value: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        Err(RequestIdError::UnsupportedTypeTupleStruct)
+        self.add_element(value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+        Ok(self.finish())
     }
 }
 
-// Tuple variants are a little different. Refer back to the
-// `serialize_tuple_variant` method above:
-//
-//    self.output += "{";
-//    variant.serialize(&mut *self)?;
-//    self.output += ":[";
-//
-// So the `end` method in this impl is responsible for closing both the `]` and
-// the `}`.
-impl<'a> ser::SerializeTupleVariant for &'a mut RequestIdSerializer {
-    type Ok = ();
+impl<H: Sha256Hasher> ser::SerializeTupleVariant for SeqHasher<H> {
+    type Ok = Hashed;
     type Error = RequestIdError;
 
-    fn serialize_field<T>(&mut self, _value: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        Err(RequestIdError::UnsupportedTypeTupleVariant)
+        self.add_element(value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+        Ok(self.finish())
     }
 }
 
-// Some `Serialize` types are not able to hold a key and value in memory at the
-// same time so `SerializeMap` implementations are required to support
-// `serialize_key` and `serialize_value` individually.
-//
-// There is a third optional method on the `SerializeMap` trait. The
-// `serialize_entry` method allows serializers to optimize for the case where
-// key and value are both available simultaneously. In JSON it doesn't make a
-// difference so the default behavior for `serialize_entry` is fine.
-impl<'a> ser::SerializeMap for &'a mut RequestIdSerializer {
-    type Ok = ();
+impl<H: Sha256Hasher> ser::SerializeMap for MapHasher<H> {
+    type Ok = Hashed;
     type Error = RequestIdError;
 
-    // The Serde data model allows map keys to be any serializable type. JSON
-    // only allows string keys so the implementation below will produce invalid
-    // JSON if the key serializes as something other than a string.
-    //
-    // A real JSON serializer would need to validate that map keys are strings.
-    // This can be done by using a different Serializer to serialize the key
-    // (instead of `&mut **self`) and having that other serializer only
-    // implement `serialize_str` and return an error on any other data type.
-    fn serialize_key<T>(&mut self, _key: &T) -> Result<Self::Ok, Self::Error>
+    // Keys are hashed like any other value, the interface specification only uses
+    // strings as keys.
+    fn serialize_key<T>(&mut self, key: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        Err(RequestIdError::UnsupportedTypeMap)
+        let key = hash_value::<H, _>(key)?
+            .into_hash()
+            .ok_or(RequestIdError::InvalidState)?;
+        self.key = Some(key);
+        Ok(())
     }
 
-    // It doesn't make a difference whether the colon is printed at the end of
-    // `serialize_key` or at the beginning of `serialize_value`. In this case
-    // the code is a bit simpler having it here.
-    fn serialize_value<T>(&mut self, _value: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        Err(RequestIdError::UnsupportedTypeMap)
+        let key = self.key.take().ok_or(RequestIdError::InvalidState)?;
+        let value = hash_value::<H, _>(value)?;
+        self.add_field(key, value);
+        Ok(())
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        self.hash_fields()
+        match self.key {
+            Some(_) => Err(RequestIdError::InvalidState),
+            None => Ok(self.finish()),
+        }
     }
 }
 
-// Structs are like maps in which the keys are constrained to be compile-time
-// constant strings.
-impl<'a> ser::SerializeStruct for &'a mut RequestIdSerializer {
-    type Ok = ();
+impl<H: Sha256Hasher> ser::SerializeStruct for MapHasher<H> {
+    type Ok = Hashed;
     type Error = RequestIdError;
 
-    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        let key_hash = self.hash_value(key)?;
-        let value_hash = self.hash_value(value)?;
-        match &mut self.element_encoder {
-            Some(Hasher::Struct { fields, .. }) => {
-                fields.insert(key_hash, value_hash);
-                Ok(())
-            }
-            _ => Err(RequestIdError::InvalidState),
-        }
+        let key = hash_value::<H, _>(key)?
+            .into_hash()
+            .ok_or(RequestIdError::InvalidState)?;
+        let value = hash_value::<H, _>(value)?;
+        self.add_field(key, value);
+        Ok(())
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        self.hash_fields()
+        Ok(self.finish())
     }
 }
 
-// Similar to `SerializeTupleVariant`, here the `end` method is responsible for
-// closing both of the curly braces opened by `serialize_struct_variant`.
-impl<'a> ser::SerializeStructVariant for &'a mut RequestIdSerializer {
-    type Ok = ();
+impl<H: Sha256Hasher> ser::SerializeStructVariant for MapHasher<H> {
+    type Ok = Hashed;
     type Error = RequestIdError;
 
-    fn serialize_field<T>(
-        &mut self,
-        _key: &'static str,
-        _value: &T,
-    ) -> Result<Self::Ok, Self::Error>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        Err(RequestIdError::UnsupportedTypeStructVariant)
+        ser::SerializeStruct::serialize_field(self, key, value)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+        Ok(self.finish())
     }
 }
 
 /// Derive the request ID from a serializable data structure.
 ///
-/// See https://hydra.dfinity.systems//build/268411/download/1/dfinity/spec/public/index.html#api-request-id
+/// See https://internetcomputer.org/docs/current/references/ic-interface-spec#request-id
 ///
 /// # Warnings
 ///
@@ -658,16 +638,49 @@ impl<'a> ser::SerializeStructVariant for &'a mut RequestIdSerializer {
 /// envelope and should be included in the calculation of the request
 /// id.
 ///
-/// # Panics
+/// # Errors
 ///
-/// This function panics if the value provided is not a struct or a map.
+/// The value must be a struct or a map, any other value returns
+/// `RequestIdError::UnsupportedRootType`.
 pub fn to_request_id<'a, V>(value: &V) -> Result<RequestId, RequestIdError>
 where
-    V: 'a + Serialize,
+    V: 'a + ?Sized + Serialize,
+{
+    to_request_id_with::<Sha256Algorithm, V>(value)
+}
+
+/// Same as `to_request_id`, hashing with the `H` implementation.
+pub fn to_request_id_with<'a, H, V>(value: &V) -> Result<RequestId, RequestIdError>
+where
+    H: Sha256Hasher,
+    V: 'a + ?Sized + Serialize,
+{
+    match hash_value::<H, V>(value)? {
+        Hashed::Map(hash) => Ok(RequestId(hash)),
+        Hashed::Absent | Hashed::Value(_) => Err(RequestIdError::UnsupportedRootType),
+    }
+}
+
+/// Representation-independent hash of any supported value, e.g. the hash of
+/// a `Delegation` signed by the delegating key.
+pub fn to_representation_independent_hash<'a, V>(value: &V) -> Result<Sha256Hash, RequestIdError>
+where
+    V: 'a + ?Sized + Serialize,
 {
-    let mut serializer = RequestIdSerializer::new();
-    value.serialize(&mut serializer)?;
-    serializer.finish()
+    to_representation_independent_hash_with::<Sha256Algorithm, V>(value)
+}
+
+/// Same as `to_representation_independent_hash`, hashing with the `H` implementation.
+pub fn to_representation_independent_hash_with<'a, H, V>(
+    value: &V,
+) -> Result<Sha256Hash, RequestIdError>
+where
+    H: Sha256Hasher,
+    V: 'a + ?Sized + Serialize,
+{
+    hash_value::<H, V>(value)?
+        .into_hash()
+        .ok_or(RequestIdError::EmptySerializer)
 }
 
 #[cfg(test)]
@@ -833,20 +846,193 @@ mod tests {
         */
     }
 
-    /// We do not support creating a request id from a map.
-    /// It adds complexity, and isn't that useful anyway because a real request would
-    /// have to have different kinds of values (strings, principals, arrays) and
-    /// we don't support the wrappers that would be required to make that work
-    /// with rust maps.
+    /// Maps are hashed the same way as structures.
     #[test]
-    fn maps_are_not_supported() {
+    fn maps_are_supported() {
+        #[derive(Serialize)]
+        struct Struct {
+            request_type: &'static str,
+            method_name: &'static str,
+        }
+
         let mut data = BTreeMap::new();
         data.insert("request_type", "call");
-        data.insert("canister_id", "a principal / the canister id");
         data.insert("method_name", "hello");
-        data.insert("arg", "some argument value");
 
-        let error = to_request_id(&data).unwrap_err();
-        assert_eq!(error, RequestIdError::UnsupportedTypeMap);
+        assert_eq!(
+            to_request_id(&data).unwrap(),
+            to_request_id(&Struct {
+                request_type: "call",
+                method_name: "hello",
+            })
+            .unwrap()
+        );
+    }
+
+    /// Values other than maps are not request ids.
+    #[test]
+    fn root_must_be_a_map() {
+        assert_eq!(
+            to_request_id("hello").unwrap_err(),
+            RequestIdError::UnsupportedRootType
+        );
+        assert_eq!(
+            to_request_id(&vec![1_u64]).unwrap_err(),
+            RequestIdError::UnsupportedRootType
+        );
+        assert_eq!(
+            to_request_id(&true).unwrap_err(),
+            RequestIdError::UnsupportedTypeBool
+        );
+    }
+
+    /// Nested maps, arrays of maps, signed integers and absent fields.
+    #[test]
+    fn nested_example() {
+        #[derive(Serialize)]
+        struct Inner {
+            name: &'static str,
+            answer: i64,
+        }
+        #[derive(Serialize)]
+        struct Outer {
+            count: u64,
+            delta: i32,
+            inner: Inner,
+            list: Vec<Inner>,
+            absent: Option<u64>,
+        }
+        let data = Outer {
+            count: 624_485,
+            delta: -123_456,
+            inner: Inner {
+                name: "foo",
+                answer: 42,
+            },
+            list: vec![Inner {
+                name: "bar",
+                answer: -1,
+            }],
+            absent: None,
+        };
+
+        // Generated with `ic_agent::to_request_id` of the same structure (ic-agent 0.49).
+        let request_id = to_request_id(&data).unwrap();
+        assert_eq!(
+            hex::encode(request_id.0),
+            "9cef5ab371458a9efe96f72773e0f0d3a2c65c5eb95b8f3443a50d3e649646f9"
+        );
+    }
+
+    /// Delegations are hashed the same way as requests, see `construct_delegation_message`.
+    #[test]
+    fn delegation_example() {
+        let delegation = crate::types::Delegation {
+            pubkey: vec![1, 2, 3],
+            expiration: 1_000_000_000,
+            targets: Some(vec![Principal::management_canister()]),
+        };
+
+        // Generated with `ic_agent::to_request_id` of the same delegation (ic-agent 0.49).
+        let expected = "2eb765bef1e53d8ab947a4d2ab6d57373cdc12118bdb06aa6288fcb7f6ccdee7";
+        assert_eq!(
+            hex::encode(to_representation_independent_hash(&delegation).unwrap()),
+            expected
+        );
+        assert_eq!(hex::encode(to_request_id(&delegation).unwrap().0), expected);
+    }
+
+    /// Unit variants are hashed as their names, other variants as single field maps.
+    #[test]
+    fn enum_variants() {
+        #[derive(Serialize)]
+        enum Variant {
+            Unit,
+            Newtype(u64),
+            Struct { value: u64 },
+        }
+
+        // Generated with `ic_agent::to_request_id` of the same variants (ic-agent 0.49).
+        assert_eq!(
+            hex::encode(to_representation_independent_hash(&Variant::Unit).unwrap()),
+            "4e545960f1bffc134026127ef92963e136ec84b24bb2a6103c0731a64843a40b"
+        );
+        assert_eq!(
+            hex::encode(to_request_id(&Variant::Newtype(1)).unwrap().0),
+            "034bf6a84adcb0b20a642ec2b024710917ccdaf2407decbab3bd748f21abfee4"
+        );
+        assert_eq!(
+            hex::encode(to_request_id(&Variant::Struct { value: 1 }).unwrap().0),
+            "b2e9e724c2acb33f4596ee0cec92edd7eb533eb06e97414c132bac141b573a64"
+        );
+    }
+
+    /// Any `Sha256Hasher` implementation may be plugged in.
+    #[test]
+    fn custom_hasher() {
+        #[derive(Default)]
+        struct RecordingHasher {
+            hasher: Sha256Algorithm,
+        }
+
+        thread_local! {
+            static UPDATES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+        }
+
+        impl Sha256Hasher for RecordingHasher {
+            fn update(&mut self, data: impl AsRef<[u8]>) {
+                UPDATES.with(|updates| updates.set(updates.get() + 1));
+                self.hasher.update(data);
+            }
+
+            fn finish(&self) -> Sha256Hash {
+                self.hasher.finish()
+            }
+        }
+
+        let delegation = crate::types::Delegation {
+            pubkey: vec![1, 2, 3],
+            expiration: 1_000_000_000,
+            targets: None,
+        };
+
+        assert_eq!(
+            to_request_id_with::<RecordingHasher, _>(&delegation).unwrap(),
+            to_request_id(&delegation).unwrap()
+        );
+        assert!(UPDATES.with(|updates| updates.get()) > 0);
+    }
+
+    #[test]
+    fn request_id_value() {
+        let request_id = RequestId::new(&[0xab; 32]);
+        let hex = "ab".repeat(32);
+
+        assert_eq!(request_id.to_string(), hex);
+        assert_eq!(String::from(request_id), hex);
+        assert_eq!(RequestId::from_str(&hex).unwrap(), request_id);
+        assert!(RequestId::from_str("abab").is_err());
+        assert!(RequestId::from_str("not hex").is_err());
+
+        let agent_request_id: AgentRequestId = request_id.into();
+        assert_eq!(RequestId::try_from(&agent_request_id).unwrap(), request_id);
+        assert!(RequestId::try_from(&vec![1_u8; 31]).is_err());
+
+        let json = serde_json::to_string(&request_id).unwrap();
+        assert_eq!(json, format!("\"{hex}\""));
+        assert_eq!(
+            serde_json::from_str::<RequestId>(&json).unwrap(),
+            request_id
+        );
+
+        let cbor = serde_cbor::to_vec(&request_id).unwrap();
+        assert_eq!(cbor, [[0x58, 32].as_slice(), &[0xab; 32]].concat());
+        assert_eq!(
+            serde_cbor::from_slice::<RequestId>(&cbor).unwrap(),
+            request_id
+        );
+
+        let ids: std::collections::HashSet<RequestId> = [request_id, request_id].into();
+        assert_eq!(ids.len(), 1);
     }
 }
@@ -0,0 +1,238 @@
+use crate::delegation::construct_delegation_message_with;
+use crate::operations::prepare_sign_message;
+use crate::public_key::public_key_to_der;
+use crate::render::RandGenerator;
+use crate::sha256::{Sha256Algorithm, Sha256Hasher};
+use crate::signer::{KeyAlgorithm, RawPublicKey, Signature, Signer};
+use crate::types::{Delegation, DeviceKey, IngressExpiryDatetimeNanos, SignedDelegation};
+use crate::RequestCtx;
+use async_trait::async_trait;
+use candid::Principal;
+use ed25519_dalek::SigningKey;
+
+/// Local Ed25519 key, cheap to sign many requests with.
+pub struct SessionKey {
+    signing_key: SigningKey,
+}
+
+impl SessionKey {
+    pub async fn generate(rand_generator: &dyn RandGenerator) -> Result<Self, String> {
+        let seed: [u8; 32] = rand_generator
+            .generate_32()
+            .await?
+            .try_into()
+            .map_err(|_| "session key seed must be 32 bytes".to_owned())?;
+
+        Ok(Self {
+            signing_key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    pub fn get_der_public_key(&self) -> DeviceKey {
+        public_key_to_der(
+            KeyAlgorithm::Ed25519,
+            self.signing_key.verifying_key().as_bytes(),
+        )
+    }
+}
+
+#[async_trait]
+impl<C> Signer<C> for SessionKey {
+    fn get_key_algorithm(&self, _ctx: &C) -> KeyAlgorithm {
+        KeyAlgorithm::Ed25519
+    }
+
+    fn get_public_key(&self, _ctx: &C) -> RawPublicKey {
+        self.signing_key.verifying_key().to_bytes().to_vec()
+    }
+
+    async fn sign(&self, _ctx: &C, message: &[u8]) -> Result<Signature, String> {
+        use ed25519_dalek::Signer;
+        Ok(self.signing_key.sign(message).to_bytes().to_vec())
+    }
+}
+
+/// Session key together with the delegation chain authorizing it
+/// to sign requests on behalf of the sender.
+pub struct Session {
+    pub key: SessionKey,
+    pub sender_pubkey: DeviceKey,
+    pub delegations: Vec<SignedDelegation>,
+}
+
+impl Session {
+    /// Generate a fresh session key and sign (with one `signer` call) the delegation
+    /// to it. If the `ctx` has its own delegation chain, the new delegation extends it.
+    pub async fn issue<C: RequestCtx>(
+        ctx: &C,
+        signer: &dyn Signer<C>,
+        rand_generator: &dyn RandGenerator,
+        expiration: u64,
+        targets: Option<Vec<Principal>>,
+    ) -> Result<Self, String> {
+        Self::issue_with::<Sha256Algorithm, C>(ctx, signer, rand_generator, expiration, targets)
+            .await
+    }
+
+    /// Same as `issue`, hashing with the `H` hasher.
+    pub async fn issue_with<H: Sha256Hasher, C: RequestCtx>(
+        ctx: &C,
+        signer: &dyn Signer<C>,
+        rand_generator: &dyn RandGenerator,
+        expiration: u64,
+        targets: Option<Vec<Principal>>,
+    ) -> Result<Self, String> {
+        let key = SessionKey::generate(rand_generator).await?;
+
+        let delegation = Delegation {
+            pubkey: key.get_der_public_key(),
+            expiration,
+            targets,
+        };
+
+        let message = prepare_sign_message::<H>(
+            signer.get_key_algorithm(ctx),
+            construct_delegation_message_with::<H>(&delegation)?,
+        );
+        let signature = signer.sign(ctx, &message).await?;
+
+        let (sender_pubkey, mut delegations) = ctx.get_delegation_chain().unwrap_or_else(|| {
+            let signer_public_key = public_key_to_der(
+                signer.get_key_algorithm(ctx),
+                signer.get_public_key(ctx).as_slice(),
+            );
+            (signer_public_key, vec![])
+        });
+        delegations.push(SignedDelegation {
+            delegation,
+            signature,
+        });
+
+        Ok(Self {
+            key,
+            sender_pubkey,
+            delegations,
+        })
+    }
+
+    pub fn get_sender(&self) -> Principal {
+        Principal::self_authenticating(&self.sender_pubkey)
+    }
+
+    pub fn get_expiration(&self) -> u64 {
+        self.delegations
+            .iter()
+            .map(|d| d.delegation.expiration)
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Request context to be used together with the session `key` as the signer.
+    pub fn get_request_ctx(&self, ingress_expiry: IngressExpiryDatetimeNanos) -> SessionCtx<'_> {
+        SessionCtx {
+            session: self,
+            ingress_expiry,
+        }
+    }
+}
+
+pub struct SessionCtx<'a> {
+    session: &'a Session,
+    ingress_expiry: IngressExpiryDatetimeNanos,
+}
+
+impl RequestCtx for SessionCtx<'_> {
+    fn get_ingress_expiry(&self) -> IngressExpiryDatetimeNanos {
+        self.ingress_expiry
+    }
+
+    fn get_delegation_chain(&self) -> Option<(DeviceKey, Vec<SignedDelegation>)> {
+        Some((
+            self.session.sender_pubkey.clone(),
+            self.session.delegations.clone(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::delegation::construct_delegation_message;
+    use crate::public_key::public_key_to_der;
+    use crate::session::Session;
+    use crate::signer::{KeyAlgorithm, Signer};
+    use crate::test_utils::{Ctx, DevRandGenerator, Ed25519Signer};
+    use crate::types::{Envelope, QueryContent};
+    use candid::Principal;
+    use ed25519_dalek::Verifier;
+    use std::sync::atomic::Ordering;
+
+    #[actix_rt::test]
+    async fn test() {
+        let signer = Ed25519Signer::default();
+        let canister_id = Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap();
+
+        let session = Session::issue(
+            &Ctx,
+            &signer,
+            &DevRandGenerator,
+            1_000,
+            Some(vec![canister_id]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(signer.sign_count.load(Ordering::Relaxed), 1);
+        assert_eq!(session.get_expiration(), 1_000);
+
+        let signer_public_key =
+            public_key_to_der(KeyAlgorithm::Ed25519, &signer.get_public_key(&Ctx));
+        assert_eq!(session.sender_pubkey, signer_public_key);
+        assert_eq!(
+            session.get_sender(),
+            Principal::self_authenticating(&signer_public_key)
+        );
+
+        let signed_delegation = &session.delegations[0];
+        assert_eq!(
+            signed_delegation.delegation.pubkey,
+            session.key.get_der_public_key()
+        );
+        let signature = ed25519_dalek::Signature::from_slice(&signed_delegation.signature).unwrap();
+        Ed25519Signer::signing_key()
+            .verifying_key()
+            .verify(
+                &construct_delegation_message(&signed_delegation.delegation).unwrap(),
+                &signature,
+            )
+            .unwrap();
+
+        for method_name in ["first", "second", "third"] {
+            let query_request = crate::create_query_request(
+                &session.get_request_ctx(500),
+                &session.key,
+                &canister_id,
+                method_name,
+                vec![],
+            )
+            .await
+            .unwrap();
+
+            let envelope: Envelope<QueryContent> =
+                serde_cbor::from_slice(&query_request.request_sign).unwrap();
+            let QueryContent::QueryRequest { sender, .. } = &envelope.content;
+            assert_eq!(*sender, session.get_sender());
+            assert_eq!(envelope.sender_pubkey, Some(signer_public_key.clone()));
+        }
+        assert_eq!(signer.sign_count.load(Ordering::Relaxed), 1);
+
+        // the request expires after the session
+        let expired = crate::create_query_request(
+            &session.get_request_ctx(2_000),
+            &session.key,
+            &canister_id,
+            "late",
+            vec![],
+        )
+        .await;
+        assert!(expired.is_err());
+    }
+}
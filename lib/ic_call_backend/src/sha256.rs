@@ -4,17 +4,27 @@ const HASH_SIZE: usize = 32;
 
 pub type Sha256Hash = [u8; HASH_SIZE];
 
+/// Incremental SHA-256 implementation used for the request ids and the signed messages.
+/// Implement it to plug in a cheaper hasher, e.g. an accelerated host one or
+/// a precompiled wasm one inside a canister.
+pub trait Sha256Hasher: Default {
+    fn update(&mut self, data: impl AsRef<[u8]>);
+
+    fn finish(&self) -> Sha256Hash;
+}
+
+/// Default hasher backed by the `sha2` crate.
 #[derive(Default)]
 pub struct Sha256Algorithm {
     sha256: Sha256,
 }
 
-impl Sha256Algorithm {
-    pub fn update(&mut self, data: impl AsRef<[u8]>) {
+impl Sha256Hasher for Sha256Algorithm {
+    fn update(&mut self, data: impl AsRef<[u8]>) {
         sha2::Digest::update(&mut self.sha256, data);
     }
 
-    pub fn finish(&self) -> Sha256Hash {
+    fn finish(&self) -> Sha256Hash {
         let array = self.sha256.clone().finalize();
         hash_from_slice(array.as_slice()).unwrap()
     }
@@ -32,7 +42,11 @@ fn hash_from_slice(data: &[u8]) -> Result<Sha256Hash, String> {
 }
 
 pub fn get_sha256(data: impl AsRef<[u8]>) -> Sha256Hash {
-    let mut algorithm = Sha256Algorithm::default();
+    get_sha256_with::<Sha256Algorithm>(data)
+}
+
+pub fn get_sha256_with<H: Sha256Hasher>(data: impl AsRef<[u8]>) -> Sha256Hash {
+    let mut algorithm = H::default();
     algorithm.update(data);
     algorithm.finish()
 }
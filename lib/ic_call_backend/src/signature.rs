@@ -0,0 +1,92 @@
+use crate::signer::{EcdsaSignatureCompact, Signature};
+use sec1::der::asn1::UIntBytes;
+use sec1::der::Decoder;
+use serde::{Deserialize, Serialize};
+
+const ECDSA_SCALAR_SIZE: usize = 32;
+
+/// Compact `r || s` signature of the DER encoded ECDSA signature,
+/// as produced by HSMs and most crypto libraries.
+pub fn ecdsa_der_to_compact(der_signature: &[u8]) -> Result<EcdsaSignatureCompact, String> {
+    let mut decoder = Decoder::new(der_signature).map_err(|e| e.to_string())?;
+    let (r, s): (UIntBytes, UIntBytes) = decoder
+        .sequence(|decoder| Ok((decoder.decode()?, decoder.decode()?)))
+        .map_err(|e| e.to_string())?;
+    decoder.finish(()).map_err(|e| e.to_string())?;
+
+    let mut compact = Vec::with_capacity(2 * ECDSA_SCALAR_SIZE);
+    for scalar in [r.as_bytes(), s.as_bytes()] {
+        if scalar.len() > ECDSA_SCALAR_SIZE {
+            return Err("ECDSA signature scalar is too long".to_owned());
+        }
+        compact.resize(compact.len() + ECDSA_SCALAR_SIZE - scalar.len(), 0);
+        compact.extend_from_slice(scalar);
+    }
+    Ok(compact)
+}
+
+/// Signature of the WebAuthn authenticator, see
+/// https://internetcomputer.org/docs/current/references/ic-interface-spec#webauthn
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WebAuthnSignature {
+    #[serde(with = "serde_bytes")]
+    pub authenticator_data: Vec<u8>,
+    /// Must contain the base64url encoded request message as the challenge.
+    pub client_data_json: String,
+    /// DER encoded ECDSA P-256 signature.
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl WebAuthnSignature {
+    /// CBOR envelope to be used as the `sender_sig`.
+    pub fn to_sender_sig(&self) -> Result<Signature, String> {
+        let mut serialized_bytes = Vec::new();
+        let mut serializer = serde_cbor::Serializer::new(&mut serialized_bytes);
+
+        serializer.self_describe().map_err(|e| e.to_string())?;
+        self.serialize(&mut serializer).map_err(|e| e.to_string())?;
+
+        Ok(serialized_bytes)
+    }
+
+    pub fn from_sender_sig(sender_sig: &[u8]) -> Result<Self, String> {
+        serde_cbor::from_slice(sender_sig).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::signature::WebAuthnSignature;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::{Signature, SigningKey};
+
+    #[test]
+    fn test_der_to_compact() {
+        let signing_key = SigningKey::from_slice(&[5_u8; 32]).unwrap();
+        for message in [b"first".as_slice(), b"second", b"third"] {
+            let signature: Signature = signing_key.sign(message);
+            let compact = super::ecdsa_der_to_compact(signature.to_der().as_bytes()).unwrap();
+            assert_eq!(compact, signature.to_bytes().to_vec());
+        }
+
+        assert!(super::ecdsa_der_to_compact(&[48, 2, 1]).is_err());
+    }
+
+    #[test]
+    fn test_webauthn_signature() {
+        let signature = WebAuthnSignature {
+            authenticator_data: vec![1, 2, 3],
+            client_data_json: r#"{"type":"webauthn.get"}"#.to_owned(),
+            signature: vec![4, 5, 6],
+        };
+
+        let sender_sig = signature.to_sender_sig().unwrap();
+        assert_eq!(sender_sig[..3], [217, 217, 247]);
+
+        let decoded = WebAuthnSignature::from_sender_sig(&sender_sig).unwrap();
+        assert_eq!(decoded.authenticator_data, signature.authenticator_data);
+        assert_eq!(decoded.client_data_json, signature.client_data_json);
+        assert_eq!(decoded.signature, signature.signature);
+    }
+}
@@ -2,15 +2,52 @@ use async_trait::async_trait;
 
 pub type MessageHash = [u8; 32];
 pub type UncompressedPublicKey = [u8; 65];
+pub type RawPublicKey = Vec<u8>;
 pub type EcdsaSignatureCompact = Vec<u8>;
+pub type Signature = Vec<u8>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyAlgorithm {
+    /// ECDSA over secp256k1: the raw key is the 65 bytes uncompressed SEC1 point,
+    /// the message to sign is the SHA-256 hash of the request message.
+    EcdsaSecp256k1,
+    /// ECDSA over secp256r1 (P-256): same encodings as for secp256k1.
+    EcdsaSecp256r1,
+    /// Ed25519: the raw key is the 32 bytes compressed Edwards point,
+    /// the message to sign is the request message itself.
+    Ed25519,
+    /// WebAuthn: the raw key is the COSE encoded P-256 key, the message to sign
+    /// is the request message itself, passed to the authenticator as the challenge.
+    /// The signature is the CBOR encoded `WebAuthnSignature` envelope.
+    WebAuthn,
+    /// IC canister signature: the raw key is the length prefixed canister id followed
+    /// by the seed, the message to sign is the request message itself.
+    /// The signature is the CBOR encoded certificate and the certified data witness.
+    CanisterSignature,
+}
 
 #[async_trait]
 pub trait Signer<C>: Sync + Send {
-    fn get_uncompressed_public_key(&self, ctx: &C) -> UncompressedPublicKey;
+    fn get_key_algorithm(&self, _ctx: &C) -> KeyAlgorithm {
+        KeyAlgorithm::EcdsaSecp256k1
+    }
+
+    /// Raw public key in the encoding of the signer key algorithm.
+    fn get_public_key(&self, ctx: &C) -> RawPublicKey;
+
+    /// Sign the message prepared for the signer key algorithm.
+    async fn sign(&self, ctx: &C, message: &[u8]) -> Result<Signature, String>;
 
-    async fn sign(
-        &self,
-        ctx: &C,
-        message_hash: &MessageHash,
-    ) -> Result<EcdsaSignatureCompact, String>;
+    /// Sign all messages, returning the signatures in the same order.
+    /// Signers with an expensive round-trip should override it to sign at once.
+    async fn sign_batch(&self, ctx: &C, messages: &[Vec<u8>]) -> Result<Vec<Signature>, String>
+    where
+        C: Sync,
+    {
+        let mut signatures = Vec::with_capacity(messages.len());
+        for message in messages {
+            signatures.push(self.sign(ctx, message).await?);
+        }
+        Ok(signatures)
+    }
 }
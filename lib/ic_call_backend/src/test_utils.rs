@@ -0,0 +1,69 @@
+//! Fixtures shared by the unit tests.
+
+use crate::render::RandGenerator;
+use crate::signer::{KeyAlgorithm, RawPublicKey, Signature, Signer};
+use crate::RequestCtx;
+use async_trait::async_trait;
+use ed25519_dalek::SigningKey;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Ctx;
+
+impl RequestCtx for Ctx {
+    fn get_ingress_expiry(&self) -> u64 {
+        100
+    }
+}
+
+/// Ed25519 signer of the fixed key, counting the signed messages and batches.
+#[derive(Default)]
+pub struct Ed25519Signer {
+    pub sign_count: AtomicUsize,
+    pub batch_count: AtomicUsize,
+}
+
+impl Ed25519Signer {
+    pub fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[3; 32])
+    }
+}
+
+#[async_trait]
+impl Signer<Ctx> for Ed25519Signer {
+    fn get_key_algorithm(&self, _ctx: &Ctx) -> KeyAlgorithm {
+        KeyAlgorithm::Ed25519
+    }
+
+    fn get_public_key(&self, _ctx: &Ctx) -> RawPublicKey {
+        Self::signing_key().verifying_key().to_bytes().to_vec()
+    }
+
+    async fn sign(&self, _ctx: &Ctx, message: &[u8]) -> Result<Signature, String> {
+        use ed25519_dalek::Signer;
+        self.sign_count.fetch_add(1, Ordering::Relaxed);
+        Ok(Self::signing_key().sign(message).to_bytes().to_vec())
+    }
+
+    async fn sign_batch(&self, _ctx: &Ctx, messages: &[Vec<u8>]) -> Result<Vec<Signature>, String> {
+        use ed25519_dalek::Signer;
+        self.batch_count.fetch_add(1, Ordering::Relaxed);
+        Ok(messages
+            .iter()
+            .map(|message| Self::signing_key().sign(message).to_bytes().to_vec())
+            .collect())
+    }
+}
+
+/// Zero nonces, as in the request id vectors of the operations tests.
+pub struct DevRandGenerator;
+
+#[async_trait]
+impl RandGenerator for DevRandGenerator {
+    async fn generate_16(&self) -> Result<Vec<u8>, String> {
+        Ok([0; 16].to_vec())
+    }
+
+    async fn generate_32(&self) -> Result<Vec<u8>, String> {
+        Ok([0; 32].to_vec())
+    }
+}
@@ -0,0 +1,169 @@
+use crate::render::RandGenerator;
+use crate::signer::Signer;
+use crate::{create_call_request, create_query_request, RequestCtx};
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::Principal;
+use icgeek_ic_call_api::{
+    AgentCallRequest, AgentCallResponse, AgentCallResponseData, AgentQueryRequest,
+};
+use std::marker::PhantomData;
+
+/// Signed query request together with the Candid type `R` of its response tuple.
+#[derive(Debug, Clone)]
+pub struct TypedQueryRequest<R> {
+    pub request: AgentQueryRequest,
+    response: PhantomData<fn() -> R>,
+}
+
+/// Signed call request together with the Candid type `R` of its response tuple.
+#[derive(Debug, Clone)]
+pub struct TypedCallRequest<R> {
+    pub request: AgentCallRequest,
+    response: PhantomData<fn() -> R>,
+}
+
+impl<R: for<'a> ArgumentDecoder<'a>> TypedQueryRequest<R> {
+    pub fn decode_response(&self, data: &AgentCallResponseData) -> Result<R, String> {
+        decode_response_data(data)
+    }
+
+    pub fn decode_call_response(&self, response: &AgentCallResponse) -> Result<R, String> {
+        decode_call_response(response)
+    }
+}
+
+impl<R: for<'a> ArgumentDecoder<'a>> TypedCallRequest<R> {
+    pub fn decode_response(&self, data: &AgentCallResponseData) -> Result<R, String> {
+        decode_response_data(data)
+    }
+
+    pub fn decode_call_response(&self, response: &AgentCallResponse) -> Result<R, String> {
+        decode_call_response(response)
+    }
+}
+
+/// Candid encode the `args` tuple and sign the query request.
+pub async fn create_typed_query_request<C, A, R>(
+    ctx: &C,
+    signer: &dyn Signer<C>,
+    canister_id: &Principal,
+    method_name: &str,
+    args: A,
+) -> Result<TypedQueryRequest<R>, String>
+where
+    C: RequestCtx,
+    A: ArgumentEncoder,
+    R: for<'a> ArgumentDecoder<'a>,
+{
+    let arg = candid::encode_args(args).map_err(|e| e.to_string())?;
+    let request = create_query_request(ctx, signer, canister_id, method_name, arg).await?;
+
+    Ok(TypedQueryRequest {
+        request,
+        response: PhantomData,
+    })
+}
+
+/// Candid encode the `args` tuple and sign the call request.
+pub async fn create_typed_call_request<C, A, R>(
+    ctx: &C,
+    signer: &dyn Signer<C>,
+    rand_generator: &dyn RandGenerator,
+    canister_id: &Principal,
+    method_name: &str,
+    args: A,
+) -> Result<TypedCallRequest<R>, String>
+where
+    C: RequestCtx,
+    A: ArgumentEncoder,
+    R: for<'a> ArgumentDecoder<'a>,
+{
+    let arg = candid::encode_args(args).map_err(|e| e.to_string())?;
+    let request =
+        create_call_request(ctx, signer, rand_generator, canister_id, method_name, arg).await?;
+
+    Ok(TypedCallRequest {
+        request,
+        response: PhantomData,
+    })
+}
+
+fn decode_response_data<R: for<'a> ArgumentDecoder<'a>>(
+    data: &AgentCallResponseData,
+) -> Result<R, String> {
+    candid::decode_args(data).map_err(|e| e.to_string())
+}
+
+fn decode_call_response<R: for<'a> ArgumentDecoder<'a>>(
+    response: &AgentCallResponse,
+) -> Result<R, String> {
+    match response {
+        AgentCallResponse::Ok(data) => decode_response_data(data),
+        AgentCallResponse::Error(error) => Err(error.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::envelope::{inspect_call_request, inspect_query_request, RequestContent};
+    use crate::test_utils::{Ctx, DevRandGenerator, Ed25519Signer};
+    use crate::typed::{create_typed_call_request, create_typed_query_request};
+    use crate::types::{CallRequestContent, QueryContent};
+    use candid::{Encode, Nat, Principal};
+    use icgeek_ic_call_api::AgentCallResponse;
+
+    #[actix_rt::test]
+    async fn test() {
+        let canister_id = Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap();
+
+        let query_request = create_typed_query_request::<_, _, (Nat,)>(
+            &Ctx,
+            &Ed25519Signer::default(),
+            &canister_id,
+            "balance",
+            (Principal::anonymous(),),
+        )
+        .await
+        .unwrap();
+        let envelope = inspect_query_request(&query_request.request).unwrap();
+        let RequestContent::Query(QueryContent::QueryRequest { arg, .. }) = &envelope.content
+        else {
+            panic!("unexpected content");
+        };
+        assert_eq!(*arg, Encode!(&Principal::anonymous()).unwrap());
+
+        let (balance,) = query_request
+            .decode_response(&Encode!(&Nat::from(42_u64)).unwrap())
+            .unwrap();
+        assert_eq!(balance, Nat::from(42_u64));
+        assert!(query_request
+            .decode_response(&Encode!(&"not a nat").unwrap())
+            .is_err());
+
+        let call_request = create_typed_call_request::<_, _, (Result<u64, String>,)>(
+            &Ctx,
+            &Ed25519Signer::default(),
+            &DevRandGenerator,
+            &canister_id,
+            "transfer",
+            (Principal::anonymous(), 10_u64),
+        )
+        .await
+        .unwrap();
+        let (envelope, _) = inspect_call_request(&call_request.request).unwrap();
+        let RequestContent::Call(CallRequestContent::CallRequest { arg, .. }) = &envelope.content
+        else {
+            panic!("unexpected content");
+        };
+        assert_eq!(*arg, Encode!(&Principal::anonymous(), &10_u64).unwrap());
+
+        let response = AgentCallResponse::Ok(Encode!(&Result::<u64, String>::Ok(7)).unwrap());
+        assert_eq!(call_request.decode_call_response(&response), Ok((Ok(7),)));
+
+        let response = AgentCallResponse::Error("rejected".to_owned());
+        assert_eq!(
+            call_request.decode_call_response(&response),
+            Err("rejected".to_owned())
+        );
+    }
+}
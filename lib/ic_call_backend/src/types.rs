@@ -1,3 +1,4 @@
+use crate::request_id::{to_request_id, RequestId, RequestIdError};
 use candid::{CandidType, Principal};
 use ic_certification::Label;
 use serde::{Deserialize, Serialize};
@@ -23,6 +24,12 @@ pub enum CallRequestContent {
     },
 }
 
+impl CallRequestContent {
+    pub fn request_id(&self) -> Result<RequestId, RequestIdError> {
+        to_request_id(self)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "request_type")]
 pub enum QueryContent {
@@ -37,6 +44,12 @@ pub enum QueryContent {
     },
 }
 
+impl QueryContent {
+    pub fn request_id(&self) -> Result<RequestId, RequestIdError> {
+        to_request_id(self)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "request_type")]
 pub enum ReadStateContent {
@@ -48,8 +61,16 @@ pub enum ReadStateContent {
     },
 }
 
+impl ReadStateContent {
+    pub fn request_id(&self) -> Result<RequestId, RequestIdError> {
+        to_request_id(self)
+    }
+}
+
+/// Serialized without a type tag, as in the interface specification, so that the envelope
+/// and the delegation hash contain only these fields. The `request_type` tag of the
+/// earlier format is ignored when deserializing.
 #[derive(CandidType, Debug, Clone, Deserialize, Serialize)]
-#[serde(tag = "request_type")]
 pub struct Delegation {
     #[serde(with = "serde_bytes")]
     pub pubkey: DeviceKey,
@@ -59,13 +80,25 @@ pub struct Delegation {
 }
 
 #[derive(CandidType, Debug, Clone, Deserialize, Serialize)]
-#[serde(tag = "request_type")]
 pub struct SignedDelegation {
     pub delegation: Delegation,
     #[serde(with = "serde_bytes")]
     pub signature: Vec<u8>,
 }
 
+/// Information about the sender attached to the request, signed by the canister
+/// signature key `signer` (see `canister_sig::sender_info_message`).
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SenderInfo {
+    #[serde(with = "serde_bytes")]
+    pub info: Vec<u8>,
+    /// DER encoded canister signature public key.
+    #[serde(with = "serde_bytes")]
+    pub signer: DeviceKey,
+    #[serde(with = "serde_bytes")]
+    pub sig: Vec<u8>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub struct Envelope<T: Serialize> {
@@ -82,4 +115,7 @@ pub struct Envelope<T: Serialize> {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(with = "serde_bytes")]
     pub sender_sig: Option<Vec<u8>>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_info: Option<SenderInfo>,
 }
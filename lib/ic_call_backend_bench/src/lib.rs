@@ -0,0 +1,65 @@
+//! Canister instructions of the request hashing: `canbench` in this directory,
+//! see https://github.com/dfinity/canbench. The results are persisted with
+//! `canbench --persist` to `canbench_results.yml`, any change of them is a regression
+//! (or an improvement) of the hashing cost.
+
+use canbench_rs::bench;
+use candid::Principal;
+use ic_certification::Label;
+use icgeek_ic_call_backend::delegation::construct_delegation_message;
+use icgeek_ic_call_backend::request_id::to_request_id;
+use icgeek_ic_call_backend::types::{CallRequestContent, Delegation, ReadStateContent};
+use std::hint::black_box;
+
+const INGRESS_EXPIRY: u64 = 1_700_000_000_000_000_000;
+
+fn canister_id() -> Principal {
+    Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()
+}
+
+fn sender() -> Principal {
+    Principal::self_authenticating([7_u8; 44])
+}
+
+fn call_request(method_name: &str, arg: Vec<u8>) -> CallRequestContent {
+    CallRequestContent::CallRequest {
+        nonce: Some(vec![1; 16]),
+        ingress_expiry: INGRESS_EXPIRY,
+        sender: sender(),
+        canister_id: canister_id(),
+        method_name: method_name.to_owned(),
+        arg,
+    }
+}
+
+#[bench]
+fn request_id_call() {
+    let request = call_request("transfer", vec![0; 128]);
+    black_box(to_request_id(black_box(&request)).unwrap());
+}
+
+#[bench]
+fn request_id_read_state() {
+    let request = ReadStateContent::ReadStateRequest {
+        ingress_expiry: INGRESS_EXPIRY,
+        sender: sender(),
+        paths: vec![vec![Label::from("request_status"), Label::from([9_u8; 32])]],
+    };
+    black_box(to_request_id(black_box(&request)).unwrap());
+}
+
+#[bench]
+fn request_id_call_64kib_argument() {
+    let request = call_request("upload_chunk", vec![0; 64 * 1024]);
+    black_box(to_request_id(black_box(&request)).unwrap());
+}
+
+#[bench]
+fn delegation_message() {
+    let delegation = Delegation {
+        pubkey: vec![5; 44],
+        expiration: INGRESS_EXPIRY,
+        targets: Some(vec![canister_id()]),
+    };
+    black_box(construct_delegation_message(black_box(&delegation)).unwrap());
+}
@@ -1,5 +1,6 @@
 use backoff::backoff::Backoff;
 use backoff::ExponentialBackoffBuilder;
+use ic_agent::agent::agent_error::HttpErrorPayload;
 use ic_agent::agent::http_transport::ReqwestHttpReplicaV2Transport;
 use ic_agent::agent::{
     PollResult, RejectCode, RejectResponse, Replied, RequestStatusResponse, Transport,
@@ -10,6 +11,8 @@ use ic_agent::{lookup_value, Agent, AgentError, Certificate, RequestId};
 use icgeek_ic_call_api::{
     AgentCallRequest, AgentCallResponseData, AgentQueryRequest, AgentRequest,
 };
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{StatusCode, Url};
 use serde::{Deserialize, Serialize};
 use std::str::from_utf8;
 use std::time::Duration;
@@ -70,6 +73,136 @@ async fn perform_call(
     .await
 }
 
+/// Client of the v3 call endpoint, the other endpoints are served by the v2 transport.
+pub struct CallV3Transport {
+    url: Url,
+    client: reqwest::Client,
+}
+
+impl CallV3Transport {
+    /// Transport for the replica url, e.g. `https://icp0.io`.
+    #[allow(clippy::result_large_err)]
+    pub fn create<U: Into<String>>(url: U) -> Result<Self, AgentError> {
+        let url = url.into();
+        let url = Url::parse(&url)
+            .and_then(|url| url.join("api/v3/"))
+            .map_err(|_| AgentError::InvalidReplicaUrl(url))?;
+        Ok(Self {
+            url,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// Certificate of the call request, or `None` when the call
+    /// was accepted but not completed in time.
+    pub async fn call(
+        &self,
+        effective_canister_id: Principal,
+        envelope: Vec<u8>,
+    ) -> Result<Option<Certificate>, AgentError> {
+        let url = self
+            .url
+            .join(&format!("canister/{effective_canister_id}/call"))?;
+        let response = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/cbor")
+            .body(envelope)
+            .send()
+            .await
+            .map_err(|e| AgentError::TransportError(Box::new(e)))?;
+
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_owned());
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| AgentError::TransportError(Box::new(e)))?;
+
+        match status {
+            StatusCode::OK => {
+                match serde_cbor::from_slice(&body).map_err(AgentError::InvalidCborData)? {
+                    CallV3Response::Replied { certificate } => serde_cbor::from_slice(&certificate)
+                        .map(Some)
+                        .map_err(AgentError::InvalidCborData),
+                    CallV3Response::NonReplicatedRejection(response) => {
+                        Err(AgentError::ReplicaError(response))
+                    }
+                }
+            }
+            StatusCode::ACCEPTED => Ok(None),
+            status => Err(AgentError::HttpError(HttpErrorPayload {
+                status: status.as_u16(),
+                content_type,
+                content: body.to_vec(),
+            })),
+        }
+    }
+}
+
+/// Perform the call by the v3 endpoint, the certificate is verified by the agent.
+/// When the call is not completed in time, its status is polled by the v2 transport.
+pub async fn perform_call_v3(
+    agent: &Agent,
+    call_transport: &ReqwestHttpReplicaV2Transport,
+    call_v3_transport: &CallV3Transport,
+    request: AgentCallRequest,
+) -> Result<AgentCallResponseData, AgentError> {
+    let request_id = build_request_id(&request);
+
+    if let Some(cert) = call_v3_transport
+        .call(request.canister_id, request.request_sign)
+        .await?
+    {
+        agent.verify(&cert, request.canister_id)?;
+        match lookup_request_status(cert, &request_id)? {
+            // the certificate may be taken before the call is completed
+            RequestStatusResponse::Unknown
+            | RequestStatusResponse::Received
+            | RequestStatusResponse::Processing => {}
+            RequestStatusResponse::Replied {
+                reply: Replied::CallReplied(arg),
+            } => return Ok(arg),
+            RequestStatusResponse::Rejected(response) => {
+                return Err(AgentError::ReplicaError(response))
+            }
+            RequestStatusResponse::Done => {
+                return Err(AgentError::RequestStatusDoneNoReply(String::from(
+                    request_id,
+                )))
+            }
+        }
+    }
+
+    wait(
+        agent,
+        call_transport,
+        &request_id,
+        request.canister_id,
+        &request.read_state_request_sign,
+    )
+    .await
+}
+
+/// `perform_request` with the calls performed by the v3 endpoint.
+pub async fn perform_request_v3(
+    agent: &Agent,
+    call_transport: &ReqwestHttpReplicaV2Transport,
+    call_v3_transport: &CallV3Transport,
+    request: AgentRequest,
+) -> Result<AgentCallResponseData, AgentError> {
+    match request {
+        AgentRequest::Query(query) => perform_query(call_transport, query).await,
+        AgentRequest::Call(call) => {
+            perform_call_v3(agent, call_transport, call_v3_transport, call).await
+        }
+    }
+}
+
 fn build_request_id(request: &AgentCallRequest) -> RequestId {
     let mut request_id = [0_u8; 32];
     request_id.copy_from_slice(request.request_id.as_slice());
@@ -212,6 +345,19 @@ pub struct ReadStateResponse {
     pub certificate: Vec<u8>,
 }
 
+/// Response of the v3 call endpoint completed in time.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "status")]
+pub enum CallV3Response {
+    #[serde(rename = "replied")]
+    Replied {
+        #[serde(with = "serde_bytes")]
+        certificate: Vec<u8>,
+    },
+    #[serde(rename = "non_replicated_rejection")]
+    NonReplicatedRejection(RejectResponse),
+}
+
 async fn read_state_raw(
     agent: &Agent,
     transport: &ReqwestHttpReplicaV2Transport,
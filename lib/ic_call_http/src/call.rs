@@ -1,43 +1,43 @@
-use crate::types::{AgentError, ReadStateResponse};
-use crate::{deserialize_cbor_data, execute_ic_request};
+use crate::cost::HttpOutcallCost;
+use crate::sleeper::{get_current_time, DurationSleeper, Sleeper};
+use crate::types::{
+    AgentError, ReadStateResponse, RejectCode, RejectResponse, RequestStatusResponse,
+};
+use crate::verify::lookup_value;
+use crate::{build_http_request, deserialize_cbor_data, execute_ic_request};
 use candid::Principal;
 use ic_cdk::api::management_canister::http_request::HttpMethod;
 use ic_certification::{Certificate, Label, LookupResult};
 use icgeek_ic_call_api::{AgentCallRequest, AgentCallResponseData};
-use std::future::Future;
-use std::pin::Pin;
+use icgeek_ic_call_backend::get_request_sign_ingress_expiry;
 use std::str::from_utf8;
+use std::time::Duration;
 
 #[allow(clippy::too_many_arguments)]
 pub async fn execute_ic_call<F>(
     ic_url: String,
     request: AgentCallRequest,
     call_max_response_bytes: u64,
-    call_cycles: u128,
+    call_cycles: Option<u128>,
     transform_canister_id: Principal,
     transform_method: String,
     transformer_ctx: Vec<u8>,
-    sleeper: Box<dyn Fn() -> Pin<Box<dyn Future<Output = ()>>>>,
+    sleeper: Sleeper,
     read_state_transform_ctx_builder: F,
     pool_max_response_bytes: u64,
-    pool_cycles: u128,
+    pool_cycles: Option<u128>,
     ic_root_key: Vec<u8>,
 ) -> Result<AgentCallResponseData, AgentError>
 where
     F: FnOnce(Principal, Vec<u8>, Vec<u8>) -> Vec<u8>,
 {
     let request_id = request.request_id.clone();
-
     let effective_canister_id = request.canister_id;
-    let envelope = request.request_sign;
-
-    // send call request
 
-    execute_ic_request(
+    send_call(
         ic_url.clone(),
-        HttpMethod::POST,
-        &format!("canister/{effective_canister_id}/call"),
-        Some(envelope),
+        effective_canister_id,
+        request.request_sign,
         transform_canister_id,
         transform_method.clone(),
         transformer_ctx,
@@ -68,6 +68,245 @@ where
     .await
 }
 
+/// How the read_state requests are repeated while the call is being processed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PollingConfig {
+    /// Delay before the first read_state request.
+    pub initial_backoff: Duration,
+    /// The delay grows by the multiplier after every attempt, up to this value.
+    pub max_backoff: Duration,
+    pub backoff_multiplier: u32,
+    /// No read_state request is sent after this time since the call was sent.
+    pub timeout: Duration,
+    /// Maximum cycles spent on the read_state outcalls.
+    pub cycles_budget: u128,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(30),
+            backoff_multiplier: 2,
+            timeout: Duration::from_secs(300),
+            cycles_budget: u128::MAX,
+        }
+    }
+}
+
+/// Backoff, deadline and cycles spent of the polling attempts.
+#[derive(Clone, Debug)]
+pub(crate) struct PollingState {
+    polling: PollingConfig,
+    deadline: u128,
+    backoff: Duration,
+    spent_cycles: u128,
+}
+
+impl PollingState {
+    /// The deadline is capped by the expiry of the signed read_state request,
+    /// the replica rejects it afterwards.
+    pub(crate) fn new(polling: PollingConfig, now_nanos: u128, ingress_expiry: u64) -> Self {
+        Self {
+            polling,
+            deadline: now_nanos
+                .saturating_add(polling.timeout.as_nanos())
+                .min(ingress_expiry as u128),
+            backoff: polling.initial_backoff,
+            spent_cycles: 0,
+        }
+    }
+
+    /// Delay before the next attempt, growing by the multiplier up to the max backoff.
+    fn next_backoff(&mut self) -> Duration {
+        let backoff = self.backoff;
+        self.backoff = backoff
+            .saturating_mul(self.polling.backoff_multiplier)
+            .min(self.polling.max_backoff);
+        backoff
+    }
+
+    /// Charge the attempt started at `now_nanos`, unless it is past the deadline
+    /// or exceeds the cycles budget.
+    fn start_attempt(&mut self, now_nanos: u128, cycles: u128) -> Result<(), AgentError> {
+        if now_nanos > self.deadline {
+            return Err(AgentError::TimeoutWaitingForResponse());
+        }
+        let spent_cycles = self.spent_cycles.saturating_add(cycles);
+        if spent_cycles > self.polling.cycles_budget {
+            return Err(AgentError::CyclesBudgetExceeded(self.polling.cycles_budget));
+        }
+        self.spent_cycles = spent_cycles;
+        Ok(())
+    }
+}
+
+/// Whether the outcall may succeed when repeated: the transient rejections (including
+/// the replicas not reaching consensus on the transformed response), the server errors
+/// and the rate limiting.
+pub fn is_transient_error(error: &AgentError) -> bool {
+    match error {
+        AgentError::HttpError(payload) => payload.status >= 500 || payload.status == 429,
+        AgentError::ReplicaError(reject) => reject.reject_code == RejectCode::SysTransient,
+        _ => false,
+    }
+}
+
+/// Send the call and poll its status until it is replied, rejected or done.
+/// The transient read_state failures are retried while the call is in flight,
+/// until the deadline (at most the expiry of the read_state request) or the cycles budget.
+///
+/// The read_state transform must reduce the response to a body that
+/// `decode_status` turns into the request status, e.g. with `lookup_request_status`.
+#[allow(clippy::too_many_arguments)]
+pub async fn execute_ic_call_with_polling<F, D>(
+    ic_url: String,
+    request: AgentCallRequest,
+    call_max_response_bytes: u64,
+    call_cycles: Option<u128>,
+    transform_canister_id: Principal,
+    transform_method: String,
+    transformer_ctx: Vec<u8>,
+    sleeper: DurationSleeper,
+    read_state_transform_ctx_builder: F,
+    decode_status: D,
+    polling: PollingConfig,
+    pool_max_response_bytes: u64,
+    pool_cycles: Option<u128>,
+    ic_root_key: Vec<u8>,
+) -> Result<AgentCallResponseData, AgentError>
+where
+    F: FnOnce(Principal, Vec<u8>, Vec<u8>) -> Vec<u8>,
+    D: Fn(&[u8]) -> Result<RequestStatusResponse, AgentError>,
+{
+    let request_id = request.request_id.clone();
+    let effective_canister_id = request.canister_id;
+    let ingress_expiry = get_request_sign_ingress_expiry(&request.read_state_request_sign)
+        .map_err(AgentError::InvalidEnvelope)?;
+    let state = PollingState::new(polling, get_current_time(), ingress_expiry);
+
+    send_call(
+        ic_url.clone(),
+        effective_canister_id,
+        request.request_sign,
+        transform_canister_id,
+        transform_method.clone(),
+        transformer_ctx,
+        call_max_response_bytes,
+        call_cycles,
+    )
+    .await?;
+
+    let read_state_transformer_ctx =
+        read_state_transform_ctx_builder(request.canister_id, request_id.clone(), ic_root_key);
+
+    poll_request_status(
+        state,
+        ic_url,
+        effective_canister_id,
+        &request_id,
+        request.read_state_request_sign,
+        transform_canister_id,
+        transform_method,
+        read_state_transformer_ctx,
+        sleeper,
+        decode_status,
+        pool_max_response_bytes,
+        pool_cycles,
+    )
+    .await
+}
+
+/// Poll the status of the sent call until it is replied, rejected or done,
+/// see `execute_ic_call_with_polling`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn poll_request_status<D>(
+    mut state: PollingState,
+    ic_url: String,
+    effective_canister_id: Principal,
+    request_id: &[u8],
+    read_state_envelope: Vec<u8>,
+    transform_canister_id: Principal,
+    transform_method: String,
+    read_state_transformer_ctx: Vec<u8>,
+    sleeper: DurationSleeper,
+    decode_status: D,
+    pool_max_response_bytes: u64,
+    pool_cycles: Option<u128>,
+) -> Result<AgentCallResponseData, AgentError>
+where
+    D: Fn(&[u8]) -> Result<RequestStatusResponse, AgentError>,
+{
+    // the fee is needed to keep the cycles budget
+    let pool_cycles = pool_cycles.unwrap_or_else(|| {
+        HttpOutcallCost::default().request_fee(&build_http_request(
+            &ic_url,
+            HttpMethod::POST,
+            &format!("canister/{effective_canister_id}/read_state"),
+            Some(read_state_envelope.clone()),
+            transform_canister_id,
+            transform_method.clone(),
+            read_state_transformer_ctx.clone(),
+            pool_max_response_bytes,
+        ))
+    });
+
+    loop {
+        sleeper(state.next_backoff()).await;
+        state.start_attempt(get_current_time(), pool_cycles)?;
+
+        let status = request_response_data(
+            ic_url.clone(),
+            effective_canister_id,
+            read_state_envelope.clone(),
+            transform_canister_id,
+            transform_method.clone(),
+            read_state_transformer_ctx.clone(),
+            pool_max_response_bytes,
+            Some(pool_cycles),
+        )
+        .await
+        .and_then(|body| decode_status(&body));
+
+        match status {
+            Ok(
+                RequestStatusResponse::Unknown
+                | RequestStatusResponse::Received
+                | RequestStatusResponse::Processing,
+            ) => {}
+            Ok(status) => return status.into_reply(request_id),
+            // the call is still in flight, its result is not lost
+            Err(error) if is_transient_error(&error) => {}
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_call(
+    ic_url: String,
+    effective_canister_id: Principal,
+    envelope: Vec<u8>,
+    transform_canister_id: Principal,
+    transform_method: String,
+    transformer_ctx: Vec<u8>,
+    max_response_bytes: u64,
+    cycles: Option<u128>,
+) -> Result<Vec<u8>, AgentError> {
+    execute_ic_request(
+        ic_url,
+        HttpMethod::POST,
+        &format!("canister/{effective_canister_id}/call"),
+        Some(envelope),
+        transform_canister_id,
+        transform_method,
+        transformer_ctx,
+        max_response_bytes,
+        cycles,
+    )
+    .await
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn request_response_data(
     ic_url: String,
@@ -77,7 +316,7 @@ async fn request_response_data(
     transform_method: String,
     transformer_ctx: Vec<u8>,
     max_response_bytes: u64,
-    cycles: u128,
+    cycles: Option<u128>,
 ) -> Result<Vec<u8>, AgentError> {
     execute_ic_request(
         ic_url,
@@ -106,145 +345,261 @@ pub fn get_reply_from_call_response_certificate(
     certificate: Certificate,
     request_id: &[u8],
 ) -> Option<AgentCallResponseData> {
-    let path_status: [Label; 3] = ["request_status".into(), request_id.into(), "status".into()];
+    if let Ok(RequestStatusResponse::Replied(reply)) =
+        lookup_request_status(&certificate, request_id)
+    {
+        Some(reply)
+    } else {
+        None
+    }
+}
+
+/// Status of the call request in the certified state tree.
+pub fn lookup_request_status(
+    certificate: &Certificate,
+    request_id: &[u8],
+) -> Result<RequestStatusResponse, AgentError> {
+    let path_status = request_status_path(request_id, "status");
+
+    match certificate.tree.lookup_path(&path_status) {
+        LookupResult::Absent => Ok(RequestStatusResponse::Unknown),
+        LookupResult::Unknown => Ok(RequestStatusResponse::Unknown),
+        LookupResult::Found(status) => match from_utf8(status)? {
+            "done" => Ok(RequestStatusResponse::Done),
+            "processing" => Ok(RequestStatusResponse::Processing),
+            "received" => Ok(RequestStatusResponse::Received),
+            "rejected" => lookup_rejection(certificate, request_id),
+            "replied" => lookup_reply(certificate, request_id),
+            other => Err(AgentError::InvalidRequestStatus(
+                path_status.into(),
+                other.to_string(),
+            )),
+        },
+        LookupResult::Error => Err(AgentError::LookupPathError(path_status.into())),
+    }
+}
 
-    if let LookupResult::Found(status) = certificate.tree.lookup_path(&path_status) {
-        if let Ok("replied") = from_utf8(status) {
-            let path_reply: [Label; 3] =
-                ["request_status".into(), request_id.into(), "reply".into()];
+fn lookup_rejection(
+    certificate: &Certificate,
+    request_id: &[u8],
+) -> Result<RequestStatusResponse, AgentError> {
+    let reject_code = lookup_reject_code(certificate, request_id)?;
+    let reject_message = lookup_reject_message(certificate, request_id)?;
+    let error_code = lookup_error_code(certificate, request_id)?;
 
-            if let LookupResult::Found(reply_data) = certificate.tree.lookup_path(&path_reply) {
-                return Some(Vec::from(reply_data));
-            }
+    Ok(RequestStatusResponse::Rejected(RejectResponse {
+        reject_code,
+        reject_message,
+        error_code,
+    }))
+}
+
+fn lookup_reject_code(
+    certificate: &Certificate,
+    request_id: &[u8],
+) -> Result<RejectCode, AgentError> {
+    let path = request_status_path(request_id, "reject_code");
+    let mut readable = lookup_value(&certificate.tree, path)?;
+    let code_digit = leb128::read::unsigned(&mut readable)
+        .map_err(|error| AgentError::Leb128ReadError(format!("{error:?}")))?;
+    RejectCode::try_from(code_digit).map_err(AgentError::Leb128ReadError)
+}
+
+fn lookup_reject_message(
+    certificate: &Certificate,
+    request_id: &[u8],
+) -> Result<String, AgentError> {
+    let path = request_status_path(request_id, "reject_message");
+    let message = lookup_value(&certificate.tree, path)?;
+    Ok(from_utf8(message)?.to_string())
+}
+
+/// The error code is optional, older replicas do not certify it.
+fn lookup_error_code(
+    certificate: &Certificate,
+    request_id: &[u8],
+) -> Result<Option<String>, AgentError> {
+    let path = request_status_path(request_id, "error_code");
+    match lookup_value(&certificate.tree, path) {
+        Ok(error_code) => Ok(Some(from_utf8(error_code)?.to_string())),
+        Err(AgentError::LookupPathAbsent(_)) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+fn lookup_reply(
+    certificate: &Certificate,
+    request_id: &[u8],
+) -> Result<RequestStatusResponse, AgentError> {
+    let path = request_status_path(request_id, "reply");
+    let reply_data = lookup_value(&certificate.tree, path)?;
+    Ok(RequestStatusResponse::Replied(Vec::from(reply_data)))
+}
+
+fn request_status_path(request_id: &[u8], field: &str) -> [Label; 3] {
+    ["request_status".into(), request_id.into(), field.into()]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::call::{is_transient_error, lookup_request_status, PollingConfig, PollingState};
+    use crate::types::{
+        AgentError, HttpErrorPayload, RejectCode, RejectResponse, RequestStatusResponse,
+    };
+    use ic_certification::{fork, label, leaf, Certificate, HashTree};
+    use std::time::Duration;
+
+    const SECOND: u128 = 1_000_000_000;
+
+    fn polling() -> PollingConfig {
+        PollingConfig {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            backoff_multiplier: 3,
+            timeout: Duration::from_secs(100),
+            cycles_budget: 250,
         }
     }
 
-    None
-}
-
-// pub fn get_reply_from_call_response_certificate(
-//     certificate: Certificate,
-//     request_id: &Vec<u8>,
-// ) -> Option<AgentCallResponseData> {
-//     if let Ok(RequestStatusResponse::Replied {
-//         reply: Replied::CallReplied(data),
-//     }) = lookup_request_status(certificate, request_id)
-//     {
-//         Some(data)
-//     } else {
-//         None
-//     }
-// }
-
-// pub fn lookup_request_status(
-//     certificate: Certificate,
-//     request_id: &Vec<u8>,
-// ) -> Result<RequestStatusResponse, AgentError> {
-//     let path_status = [
-//         "request_status".into(),
-//         request_id.clone().into(),
-//         "status".into(),
-//     ];
-//
-//     match certificate.tree.lookup_path(&path_status) {
-//         LookupResult::Absent => Ok(RequestStatusResponse::Unknown),
-//         LookupResult::Unknown => Ok(RequestStatusResponse::Unknown),
-//         LookupResult::Found(status) => {
-//             match from_utf8(status).map_err(AgentError::Utf8ReadError)? {
-//                 "done" => Ok(RequestStatusResponse::Done),
-//                 "processing" => Ok(RequestStatusResponse::Processing),
-//                 "received" => Ok(RequestStatusResponse::Received),
-//                 "rejected" => lookup_rejection(&certificate, request_id),
-//                 "replied" => lookup_reply(&certificate, request_id),
-//                 other => Err(AgentError::InvalidRequestStatus(
-//                     path_status.into(),
-//                     other.to_string(),
-//                 )),
-//             }
-//         }
-//         LookupResult::Error => Err(AgentError::LookupPathError(path_status.into())),
-//     }
-// }
-//
-// fn lookup_rejection(
-//     certificate: &Certificate,
-//     request_id: &Vec<u8>,
-// ) -> Result<RequestStatusResponse, AgentError> {
-//     let reject_code = lookup_reject_code(certificate, request_id)?;
-//     let reject_message = lookup_reject_message(certificate, request_id)?;
-//
-//     Ok(RequestStatusResponse::Rejected(RejectResponse {
-//         reject_code,
-//         reject_message,
-//         error_code: None,
-//     }))
-// }
-//
-// fn lookup_reject_code(
-//     certificate: &Certificate,
-//     request_id: &Vec<u8>,
-// ) -> Result<RejectCode, AgentError> {
-//     let path = [
-//         "request_status".into(),
-//         request_id.as_slice().to_vec().into(),
-//         "reject_code".into(),
-//     ];
-//     let code = lookup_value(&certificate.tree, path)?;
-//     let mut readable = code;
-//     let code_digit = leb128::read::unsigned(&mut readable)
-//         .map_err(|error| AgentError::Leb128ReadError(format!("{error:?}")))?;
-//     RejectCode::try_from(code_digit).map_err(AgentError::Leb128ReadError)
-// }
-//
-// fn lookup_reject_message(
-//     certificate: &Certificate,
-//     request_id: &Vec<u8>,
-// ) -> Result<String, AgentError> {
-//     let path = [
-//         "request_status".into(),
-//         request_id.as_slice().to_vec().into(),
-//         "reject_message".into(),
-//     ];
-//     let msg = lookup_value(&certificate.tree, path)?;
-//     Ok(from_utf8(msg)
-//         .map_err(AgentError::Utf8ReadError)?
-//         .to_string())
-// }
-//
-// fn lookup_reply(
-//     certificate: &Certificate,
-//     request_id: &Vec<u8>,
-// ) -> Result<RequestStatusResponse, AgentError> {
-//     let path = [
-//         "request_status".into(),
-//         request_id.as_slice().to_vec().into(),
-//         "reply".into(),
-//     ];
-//     let reply_data = lookup_value(&certificate.tree, path)?;
-//     let reply = Replied::CallReplied(Vec::from(reply_data));
-//     Ok(RequestStatusResponse::Replied { reply })
-// }
-//
-// #[derive(Debug)]
-// pub enum RequestStatusResponse {
-//     /// The status of the request is unknown.
-//     Unknown,
-//     /// The request has been received, and will probably get processed.
-//     Received,
-//     /// The request is currently being processed.
-//     Processing,
-//     /// The request has been successfully replied to.
-//     Replied {
-//         /// The reply from the replica.
-//         reply: Replied,
-//     },
-//     /// The request has been rejected.
-//     Rejected(RejectResponse),
-//     /// The call has been completed, and it has been long enough that the reply/reject data has been purged, but the call has not expired yet.
-//     Done,
-// }
-//
-// #[derive(Debug)]
-// pub enum Replied {
-//     CallReplied(Vec<u8>),
-// }
+    #[test]
+    fn test_backoff() {
+        let mut state = PollingState::new(polling(), 0, u64::MAX);
+        let backoffs: Vec<u64> = (0..5).map(|_| state.next_backoff().as_secs()).collect();
+        assert_eq!(backoffs, vec![1, 3, 9, 10, 10]);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut state = PollingState::new(polling(), 10 * SECOND, u64::MAX);
+        assert!(state.start_attempt(110 * SECOND, 0).is_ok());
+        assert!(matches!(
+            state.start_attempt(110 * SECOND + 1, 0),
+            Err(AgentError::TimeoutWaitingForResponse())
+        ));
+
+        // the read_state request expires before the timeout
+        let mut state = PollingState::new(polling(), 10 * SECOND, 50 * SECOND as u64);
+        assert!(state.start_attempt(50 * SECOND, 0).is_ok());
+        assert!(matches!(
+            state.start_attempt(51 * SECOND, 0),
+            Err(AgentError::TimeoutWaitingForResponse())
+        ));
+    }
+
+    #[test]
+    fn test_cycles_budget() {
+        let mut state = PollingState::new(polling(), 0, u64::MAX);
+        assert!(state.start_attempt(0, 100).is_ok());
+        assert!(state.start_attempt(0, 100).is_ok());
+        assert!(matches!(
+            state.start_attempt(0, 100),
+            Err(AgentError::CyclesBudgetExceeded(250))
+        ));
+        // the rejected attempt is not charged
+        assert!(state.start_attempt(0, 50).is_ok());
+    }
+
+    #[test]
+    fn test_is_transient_error() {
+        let reject = |reject_code| {
+            AgentError::ReplicaError(RejectResponse {
+                reject_code,
+                reject_message: "No consensus could be reached".to_owned(),
+                error_code: None,
+            })
+        };
+        let http_error =
+            |status| AgentError::HttpError(HttpErrorPayload::new(status, None, vec![]));
+
+        assert!(is_transient_error(&reject(RejectCode::SysTransient)));
+        assert!(!is_transient_error(&reject(RejectCode::CanisterReject)));
+        assert!(is_transient_error(&http_error(503)));
+        assert!(is_transient_error(&http_error(429)));
+        assert!(!is_transient_error(&http_error(400)));
+        assert!(!is_transient_error(&AgentError::CertificateNotAuthorized()));
+    }
+
+    const REQUEST_ID: [u8; 32] = [7; 32];
+
+    /// Certificate of the request status with the `(name, value)` fields in the label order.
+    fn status_certificate(fields: &[(&str, &[u8])]) -> Certificate {
+        let subtree = fields
+            .iter()
+            .map(|(name, value)| label(*name, leaf(value.to_vec())))
+            .reduce(fork)
+            .unwrap();
+        let tree: HashTree = label("request_status", label(REQUEST_ID, subtree));
+        Certificate {
+            tree,
+            signature: vec![],
+            delegation: None,
+        }
+    }
+
+    #[test]
+    fn test_lookup_request_status() {
+        let other_request = status_certificate(&[("status", b"replied")]);
+        let other_id = [8; 32];
+        assert_eq!(
+            lookup_request_status(&other_request, &other_id).unwrap(),
+            RequestStatusResponse::Unknown
+        );
+
+        for (status, expected) in [
+            ("received", RequestStatusResponse::Received),
+            ("processing", RequestStatusResponse::Processing),
+            ("done", RequestStatusResponse::Done),
+        ] {
+            let certificate = status_certificate(&[("status", status.as_bytes())]);
+            assert_eq!(
+                lookup_request_status(&certificate, &REQUEST_ID).unwrap(),
+                expected
+            );
+        }
+
+        let certificate = status_certificate(&[("reply", b"DIDL\x00\x00"), ("status", b"replied")]);
+        assert_eq!(
+            lookup_request_status(&certificate, &REQUEST_ID).unwrap(),
+            RequestStatusResponse::Replied(b"DIDL\x00\x00".to_vec())
+        );
+
+        let certificate = status_certificate(&[("status", b"unexpected")]);
+        assert!(matches!(
+            lookup_request_status(&certificate, &REQUEST_ID),
+            Err(AgentError::InvalidRequestStatus(..))
+        ));
+    }
+
+    #[test]
+    fn test_lookup_rejected_request_status() {
+        let certificate = status_certificate(&[
+            ("error_code", b"IC0503"),
+            ("reject_code", &[4]),
+            ("reject_message", b"Canister trapped"),
+            ("status", b"rejected"),
+        ]);
+        assert_eq!(
+            lookup_request_status(&certificate, &REQUEST_ID).unwrap(),
+            RequestStatusResponse::Rejected(RejectResponse {
+                reject_code: RejectCode::CanisterReject,
+                reject_message: "Canister trapped".to_owned(),
+                error_code: Some("IC0503".to_owned()),
+            })
+        );
+
+        // the replicas before the error codes do not certify them
+        let certificate = status_certificate(&[
+            ("reject_code", &[2]),
+            ("reject_message", b"No consensus could be reached"),
+            ("status", b"rejected"),
+        ]);
+        assert_eq!(
+            lookup_request_status(&certificate, &REQUEST_ID).unwrap(),
+            RequestStatusResponse::Rejected(RejectResponse {
+                reject_code: RejectCode::SysTransient,
+                reject_message: "No consensus could be reached".to_owned(),
+                error_code: None,
+            })
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use crate::call::{poll_request_status, PollingConfig, PollingState};
+use crate::sleeper::{get_current_time, DurationSleeper};
+use crate::transform::{
+    build_call_v3_transform_ctx, build_read_state_transform_ctx, decode_request_status,
+};
+use crate::types::{AgentError, HttpErrorPayload, RequestStatusResponse};
+use crate::{build_http_request, send_http_request};
+use candid::Principal;
+use ic_cdk::api::management_canister::http_request::HttpMethod;
+use icgeek_ic_call_api::{AgentCallRequest, AgentCallResponseData};
+use icgeek_ic_call_backend::get_request_sign_ingress_expiry;
+
+const API_V2_PATH: &str = "/api/v2/";
+const API_V3_PATH: &str = "/api/v3/";
+
+/// Url of the v3 API for the v2 API url, e.g. `https://icp0.io/api/v3/`.
+pub fn ic_url_v3(ic_url: &str) -> Result<String, AgentError> {
+    match ic_url.rfind(API_V2_PATH) {
+        Some(index) if index + API_V2_PATH.len() == ic_url.len() => {
+            Ok(format!("{}{API_V3_PATH}", &ic_url[..index]))
+        }
+        _ => Err(AgentError::InvalidReplicaUrl(ic_url.to_owned())),
+    }
+}
+
+/// Send the call to the v3 endpoint, which replies with the certificate of the request status
+/// once the call is completed, so a single outcall is needed.
+///
+/// The transform must reduce the responses with `transform_response`: the certificate is
+/// verified w.r.t. `ic_root_key` in the transform. The `call_max_response_bytes` must fit
+/// the certificate. When the replica accepts the call but does not complete it in time (202),
+/// the status is polled from the v2 read_state endpoint, as by `execute_ic_call_with_polling`.
+#[allow(clippy::too_many_arguments)]
+pub async fn execute_ic_call_v3(
+    ic_url: String,
+    request: AgentCallRequest,
+    call_max_response_bytes: u64,
+    call_cycles: Option<u128>,
+    transform_canister_id: Principal,
+    transform_method: String,
+    sleeper: DurationSleeper,
+    polling: PollingConfig,
+    pool_max_response_bytes: u64,
+    pool_cycles: Option<u128>,
+    ic_root_key: Vec<u8>,
+) -> Result<AgentCallResponseData, AgentError> {
+    let request_id = request.request_id.clone();
+    let effective_canister_id = request.canister_id;
+    let ingress_expiry = get_request_sign_ingress_expiry(&request.read_state_request_sign)
+        .map_err(AgentError::InvalidEnvelope)?;
+    let state = PollingState::new(polling, get_current_time(), ingress_expiry);
+
+    let call_request = build_http_request(
+        &ic_url_v3(&ic_url)?,
+        HttpMethod::POST,
+        &format!("canister/{effective_canister_id}/call"),
+        Some(request.request_sign),
+        transform_canister_id,
+        transform_method.clone(),
+        build_call_v3_transform_ctx(
+            effective_canister_id,
+            request_id.clone(),
+            ic_root_key.clone(),
+        ),
+        call_max_response_bytes,
+    );
+
+    match send_http_request(call_request, call_cycles).await? {
+        (200, body) => match decode_request_status(&body)? {
+            // the certificate may be taken before the call is completed
+            RequestStatusResponse::Unknown
+            | RequestStatusResponse::Received
+            | RequestStatusResponse::Processing => {}
+            status => return status.into_reply(&request_id),
+        },
+        // accepted, but not completed in time
+        (202, _) => {}
+        (status, body) => {
+            return Err(AgentError::HttpError(HttpErrorPayload::new(
+                status, None, body,
+            )))
+        }
+    }
+
+    poll_request_status(
+        state,
+        ic_url,
+        effective_canister_id,
+        &request_id,
+        request.read_state_request_sign,
+        transform_canister_id,
+        transform_method,
+        build_read_state_transform_ctx(effective_canister_id, request_id.clone(), ic_root_key),
+        sleeper,
+        decode_request_status,
+        pool_max_response_bytes,
+        pool_cycles,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::call_v3::ic_url_v3;
+    use crate::types::AgentError;
+
+    #[test]
+    fn test_ic_url_v3() {
+        assert_eq!(
+            ic_url_v3("https://icp0.io/api/v2/").unwrap(),
+            "https://icp0.io/api/v3/"
+        );
+        assert_eq!(
+            ic_url_v3("http://127.0.0.1:4943/api/v2/").unwrap(),
+            "http://127.0.0.1:4943/api/v3/"
+        );
+        // only the trailing api path is replaced
+        assert_eq!(
+            ic_url_v3("https://proxy.example/api/v2/api/v2/").unwrap(),
+            "https://proxy.example/api/v2/api/v3/"
+        );
+
+        for ic_url in [
+            "https://icp0.io/api/v2",
+            "https://icp0.io/",
+            "https://icp0.io/api/v3/",
+        ] {
+            assert!(matches!(
+                ic_url_v3(ic_url),
+                Err(AgentError::InvalidReplicaUrl(url)) if url == ic_url
+            ));
+        }
+    }
+}
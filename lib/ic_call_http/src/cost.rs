@@ -0,0 +1,202 @@
+use crate::build_http_request;
+use candid::Principal;
+use ic_cdk::api::management_canister::http_request::{CanisterHttpRequestArgument, HttpMethod};
+use icgeek_ic_call_api::AgentCallRequest;
+
+/// Number of nodes of the application subnets.
+pub const DEFAULT_SUBNET_SIZE: u64 = 13;
+
+/// Response limit of the HTTPS outcall without `max_response_bytes`.
+pub const DEFAULT_MAX_RESPONSE_BYTES: u64 = 2_000_000;
+
+/// HTTPS outcall fee of the subnet, see
+/// https://internetcomputer.org/docs/current/developer-docs/gas-cost#special-features
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct HttpOutcallCost {
+    pub subnet_size: u64,
+}
+
+impl Default for HttpOutcallCost {
+    fn default() -> Self {
+        Self {
+            subnet_size: DEFAULT_SUBNET_SIZE,
+        }
+    }
+}
+
+/// Fees of the outcalls made by `execute_ic_call`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct IcCallCost {
+    pub call: u128,
+    /// Fee of one read_state outcall, paid for every polling attempt.
+    pub read_state: u128,
+}
+
+impl HttpOutcallCost {
+    /// `(3_000_000 + 60_000 * n) * n + 400 * n * request_size + 800 * n * max_response_bytes`
+    /// cycles for the subnet of `n` nodes.
+    pub fn fee(&self, request_size: u64, max_response_bytes: Option<u64>) -> u128 {
+        let n = self.subnet_size as u128;
+        let request_size = request_size as u128;
+        let max_response_bytes = max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES) as u128;
+
+        (3_000_000 + 60_000 * n) * n + 400 * n * request_size + 800 * n * max_response_bytes
+    }
+
+    pub fn request_fee(&self, request: &CanisterHttpRequestArgument) -> u128 {
+        self.fee(request_size(request), request.max_response_bytes)
+    }
+
+    /// Fees of the call and the read_state outcalls of the signed call request.
+    #[allow(clippy::too_many_arguments)]
+    pub fn ic_call_cost(
+        &self,
+        ic_url: &str,
+        request: &AgentCallRequest,
+        transform_canister_id: Principal,
+        transform_method: &str,
+        transformer_ctx: &[u8],
+        read_state_transformer_ctx: &[u8],
+        call_max_response_bytes: u64,
+        pool_max_response_bytes: u64,
+    ) -> IcCallCost {
+        let effective_canister_id = request.canister_id;
+        let outcall_fee = |endpoint: &str, body: &[u8], ctx: &[u8], max_response_bytes: u64| {
+            self.request_fee(&build_http_request(
+                ic_url,
+                HttpMethod::POST,
+                &format!("canister/{effective_canister_id}/{endpoint}"),
+                Some(body.to_vec()),
+                transform_canister_id,
+                transform_method.to_owned(),
+                ctx.to_vec(),
+                max_response_bytes,
+            ))
+        };
+
+        IcCallCost {
+            call: outcall_fee(
+                "call",
+                &request.request_sign,
+                transformer_ctx,
+                call_max_response_bytes,
+            ),
+            read_state: outcall_fee(
+                "read_state",
+                &request.read_state_request_sign,
+                read_state_transformer_ctx,
+                pool_max_response_bytes,
+            ),
+        }
+    }
+}
+
+/// Size charged for the request: the URL, the headers, the body
+/// and the transform method name with its context.
+pub fn request_size(request: &CanisterHttpRequestArgument) -> u64 {
+    let headers_size: usize = request
+        .headers
+        .iter()
+        .map(|header| header.name.len() + header.value.len())
+        .sum();
+    let body_size = request.body.as_ref().map(Vec::len).unwrap_or(0);
+    let transform_size = request
+        .transform
+        .as_ref()
+        .map(|transform| transform.function.0.method.len() + transform.context.len())
+        .unwrap_or(0);
+
+    (request.url.len() + headers_size + body_size + transform_size) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build_http_request;
+    use crate::cost::{request_size, HttpOutcallCost, IcCallCost};
+    use candid::Principal;
+    use ic_cdk::api::management_canister::http_request::HttpMethod;
+    use icgeek_ic_call_api::AgentCallRequest;
+
+    #[test]
+    fn test_fee() {
+        let application_subnet = HttpOutcallCost { subnet_size: 13 };
+        assert_eq!(application_subnet.fee(1_000, Some(2_000)), 75_140_000);
+        assert_eq!(application_subnet.fee(1_000, None), 20_854_340_000);
+        assert_eq!(application_subnet, HttpOutcallCost::default());
+
+        let fiduciary_subnet = HttpOutcallCost { subnet_size: 34 };
+        assert_eq!(fiduciary_subnet.fee(1_000, Some(2_000)), 239_360_000);
+    }
+
+    #[test]
+    fn test_request_size() {
+        let request = build_http_request(
+            "https://ic0.app/api/v2/",
+            HttpMethod::POST,
+            "canister/ryjl3-tyaaa-aaaaa-aaaba-cai/call",
+            Some(vec![0; 100]),
+            Principal::anonymous(),
+            "transform".to_owned(),
+            vec![0; 10],
+            2_000,
+        );
+        // url 64, content-type header 28, body 100, transform 9 + 10
+        assert_eq!(request_size(&request), 211);
+        assert_eq!(
+            HttpOutcallCost::default().request_fee(&request),
+            HttpOutcallCost::default().fee(211, Some(2_000))
+        );
+    }
+
+    #[test]
+    fn test_ic_call_cost() {
+        let ic_url = "https://ic0.app/api/v2/";
+        let canister_id = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let request = AgentCallRequest {
+            canister_id,
+            request_id: vec![1; 32],
+            request_sign: vec![2; 300],
+            read_state_request_sign: vec![3; 200],
+        };
+        let cost = HttpOutcallCost::default();
+
+        let ic_call_cost = cost.ic_call_cost(
+            ic_url,
+            &request,
+            Principal::anonymous(),
+            "transform",
+            &[4; 10],
+            &[5; 40],
+            1_000,
+            3_000,
+        );
+
+        let outcall_fee = |endpoint: &str, body: &[u8], ctx: &[u8], max_response_bytes| {
+            cost.request_fee(&build_http_request(
+                ic_url,
+                HttpMethod::POST,
+                &format!("canister/{canister_id}/{endpoint}"),
+                Some(body.to_vec()),
+                Principal::anonymous(),
+                "transform".to_owned(),
+                ctx.to_vec(),
+                max_response_bytes,
+            ))
+        };
+        assert_eq!(
+            ic_call_cost,
+            IcCallCost {
+                call: outcall_fee("call", &[2; 300], &[4; 10], 1_000),
+                read_state: outcall_fee("read_state", &[3; 200], &[5; 40], 3_000),
+            }
+        );
+        // request sizes 64 + 28 + 300 + 9 + 10 and 70 + 28 + 200 + 9 + 40
+        assert_eq!(
+            ic_call_cost,
+            IcCallCost {
+                call: cost.fee(411, Some(1_000)),
+                read_state: cost.fee(347, Some(3_000)),
+            }
+        );
+    }
+}
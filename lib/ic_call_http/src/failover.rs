@@ -0,0 +1,439 @@
+use crate::call::is_transient_error;
+use crate::execute_ic_request;
+use crate::sleeper::{get_current_time, Sleeper};
+use crate::types::AgentError;
+use candid::Principal;
+use ic_cdk::api::management_canister::http_request::HttpMethod;
+use icgeek_ic_call_api::{AgentCallRequest, AgentCallResponseData};
+use std::cell::RefCell;
+use std::future::Future;
+use std::thread::LocalKey;
+use std::time::Duration;
+
+/// Boundary nodes kept in the canister state, e.g.
+/// `thread_local! { static NODES: RefCell<BoundaryNodes> = ... }`.
+pub type BoundaryNodesState = &'static LocalKey<RefCell<BoundaryNodes>>;
+
+/// When the boundary node is considered down.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FailoverPolicy {
+    /// Consecutive failures after which the node is skipped.
+    pub failure_threshold: u32,
+    /// How long the failed node is skipped, while other nodes are healthy.
+    pub cooldown: Duration,
+}
+
+impl Default for FailoverPolicy {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 3,
+            cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+/// API boundary nodes with their health, kept in the canister state between the outcalls.
+#[derive(Clone, Debug)]
+pub struct BoundaryNodes {
+    policy: FailoverPolicy,
+    nodes: Vec<BoundaryNode>,
+}
+
+#[derive(Clone, Debug)]
+struct BoundaryNode {
+    ic_url: String,
+    consecutive_failures: u32,
+    unhealthy_until: u128,
+}
+
+impl BoundaryNodes {
+    pub fn new(ic_urls: Vec<String>, policy: FailoverPolicy) -> Self {
+        Self {
+            policy,
+            nodes: ic_urls
+                .into_iter()
+                .map(|ic_url| BoundaryNode {
+                    ic_url,
+                    consecutive_failures: 0,
+                    unhealthy_until: 0,
+                })
+                .collect(),
+        }
+    }
+
+    /// Urls in the order to try: the healthy nodes in the configured order (the `avoid` one
+    /// last among them), then the unhealthy nodes, the earliest to recover first.
+    pub fn candidates(&self, now_nanos: u128, avoid: Option<&str>) -> Vec<String> {
+        let (mut healthy, mut unhealthy): (Vec<_>, Vec<_>) = self
+            .nodes
+            .iter()
+            .partition(|node| node.unhealthy_until <= now_nanos);
+
+        healthy.sort_by_key(|node| Some(node.ic_url.as_str()) == avoid);
+        unhealthy.sort_by_key(|node| node.unhealthy_until);
+
+        healthy
+            .into_iter()
+            .chain(unhealthy)
+            .map(|node| node.ic_url.clone())
+            .collect()
+    }
+
+    pub fn is_healthy(&self, ic_url: &str, now_nanos: u128) -> bool {
+        self.nodes
+            .iter()
+            .any(|node| node.ic_url == ic_url && node.unhealthy_until <= now_nanos)
+    }
+
+    pub fn report_success(&mut self, ic_url: &str) {
+        if let Some(node) = self.node_mut(ic_url) {
+            node.consecutive_failures = 0;
+            node.unhealthy_until = 0;
+        }
+    }
+
+    pub fn report_failure(&mut self, ic_url: &str, now_nanos: u128) {
+        let policy = self.policy;
+        if let Some(node) = self.node_mut(ic_url) {
+            node.consecutive_failures = node.consecutive_failures.saturating_add(1);
+            if node.consecutive_failures >= policy.failure_threshold {
+                node.unhealthy_until = now_nanos.saturating_add(policy.cooldown.as_nanos());
+            }
+        }
+    }
+
+    fn node_mut(&mut self, ic_url: &str) -> Option<&mut BoundaryNode> {
+        self.nodes.iter_mut().find(|node| node.ic_url == ic_url)
+    }
+}
+
+/// Whether the error is caused by the boundary node (or the way to it) rather than
+/// by the request, so that another node may succeed.
+pub fn is_boundary_node_failure(error: &AgentError) -> bool {
+    is_transient_error(error)
+}
+
+/// `execute_ic_request` over the boundary nodes, failing over to the next node
+/// on the boundary node failures. The nodes are not borrowed while awaiting the outcall.
+#[allow(clippy::too_many_arguments)]
+pub async fn execute_ic_request_with_failover(
+    nodes: BoundaryNodesState,
+    method: HttpMethod,
+    endpoint: &str,
+    body: Option<Vec<u8>>,
+    transform_canister_id: Principal,
+    transform_method: String,
+    transformer_ctx: Vec<u8>,
+    max_response_bytes: u64,
+    cycles: Option<u128>,
+) -> Result<Vec<u8>, AgentError> {
+    send_with_failover(
+        nodes,
+        None,
+        method,
+        endpoint,
+        body,
+        transform_canister_id,
+        transform_method,
+        transformer_ctx,
+        max_response_bytes,
+        cycles,
+    )
+    .await
+    .map(|(_, body)| body)
+}
+
+/// `execute_ic_call` over the boundary nodes: both legs fail over independently,
+/// and the read_state leg prefers another node than the one that accepted the call.
+#[allow(clippy::too_many_arguments)]
+pub async fn execute_ic_call_with_failover<F>(
+    nodes: BoundaryNodesState,
+    request: AgentCallRequest,
+    call_max_response_bytes: u64,
+    call_cycles: Option<u128>,
+    transform_canister_id: Principal,
+    transform_method: String,
+    transformer_ctx: Vec<u8>,
+    sleeper: Sleeper,
+    read_state_transform_ctx_builder: F,
+    pool_max_response_bytes: u64,
+    pool_cycles: Option<u128>,
+    ic_root_key: Vec<u8>,
+) -> Result<AgentCallResponseData, AgentError>
+where
+    F: FnOnce(Principal, Vec<u8>, Vec<u8>) -> Vec<u8>,
+{
+    let effective_canister_id = request.canister_id;
+
+    let (call_ic_url, _) = send_with_failover(
+        nodes,
+        None,
+        HttpMethod::POST,
+        &format!("canister/{effective_canister_id}/call"),
+        Some(request.request_sign),
+        transform_canister_id,
+        transform_method.clone(),
+        transformer_ctx,
+        call_max_response_bytes,
+        call_cycles,
+    )
+    .await?;
+
+    let read_state_transformer_ctx =
+        read_state_transform_ctx_builder(effective_canister_id, request.request_id, ic_root_key);
+
+    sleeper().await;
+
+    send_with_failover(
+        nodes,
+        Some(&call_ic_url),
+        HttpMethod::POST,
+        &format!("canister/{effective_canister_id}/read_state"),
+        Some(request.read_state_request_sign),
+        transform_canister_id,
+        transform_method,
+        read_state_transformer_ctx,
+        pool_max_response_bytes,
+        pool_cycles,
+    )
+    .await
+    .map(|(_, body)| body)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_with_failover(
+    nodes: BoundaryNodesState,
+    avoid: Option<&str>,
+    method: HttpMethod,
+    endpoint: &str,
+    body: Option<Vec<u8>>,
+    transform_canister_id: Principal,
+    transform_method: String,
+    transformer_ctx: Vec<u8>,
+    max_response_bytes: u64,
+    cycles: Option<u128>,
+) -> Result<(String, Vec<u8>), AgentError> {
+    send_to_nodes(nodes, avoid, |ic_url| {
+        execute_ic_request(
+            ic_url,
+            method,
+            endpoint,
+            body.clone(),
+            transform_canister_id,
+            transform_method.clone(),
+            transformer_ctx.clone(),
+            max_response_bytes,
+            cycles,
+        )
+    })
+    .await
+}
+
+/// Send to the candidate nodes in turn until one of them does not fail,
+/// reporting the health of the tried nodes.
+async fn send_to_nodes<S, R>(
+    nodes: BoundaryNodesState,
+    avoid: Option<&str>,
+    send: S,
+) -> Result<(String, Vec<u8>), AgentError>
+where
+    S: Fn(String) -> R,
+    R: Future<Output = Result<Vec<u8>, AgentError>>,
+{
+    let candidates = nodes.with(|nodes| nodes.borrow().candidates(get_current_time(), avoid));
+
+    let mut last_error = AgentError::NoBoundaryNodes();
+    for ic_url in candidates {
+        match send(ic_url.clone()).await {
+            Err(error) if is_boundary_node_failure(&error) => {
+                nodes.with(|nodes| {
+                    nodes
+                        .borrow_mut()
+                        .report_failure(&ic_url, get_current_time())
+                });
+                last_error = error;
+            }
+            result => {
+                nodes.with(|nodes| nodes.borrow_mut().report_success(&ic_url));
+                return result.map(|body| (ic_url, body));
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::failover::{is_boundary_node_failure, send_to_nodes, BoundaryNodes, FailoverPolicy};
+    use crate::sleeper::get_current_time;
+    use crate::types::{AgentError, HttpErrorPayload, RejectCode, RejectResponse};
+    use std::cell::RefCell;
+    use std::future::{ready, Future};
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+    use std::time::Duration;
+
+    thread_local! {
+        static NODES: RefCell<BoundaryNodes> = RefCell::new(nodes());
+    }
+
+    const SECOND: u128 = 1_000_000_000;
+
+    fn nodes() -> BoundaryNodes {
+        BoundaryNodes::new(
+            vec!["a".to_owned(), "b".to_owned(), "c".to_owned()],
+            FailoverPolicy {
+                failure_threshold: 2,
+                cooldown: Duration::from_secs(60),
+            },
+        )
+    }
+
+    #[test]
+    fn test_candidates() {
+        let nodes = nodes();
+        assert_eq!(nodes.candidates(0, None), vec!["a", "b", "c"]);
+        assert_eq!(nodes.candidates(0, Some("a")), vec!["b", "c", "a"]);
+        assert_eq!(nodes.candidates(0, Some("d")), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let mut nodes = nodes();
+
+        // below the threshold the node stays healthy
+        nodes.report_failure("a", 0);
+        assert!(nodes.is_healthy("a", 0));
+        assert_eq!(nodes.candidates(0, None), vec!["a", "b", "c"]);
+
+        nodes.report_failure("a", 10 * SECOND);
+        nodes.report_failure("b", 0);
+        nodes.report_failure("b", 0);
+        assert!(!nodes.is_healthy("a", 10 * SECOND));
+        // the unhealthy nodes are the last ones, the earliest to recover first
+        assert_eq!(nodes.candidates(10 * SECOND, None), vec!["c", "b", "a"]);
+        assert_eq!(
+            nodes.candidates(10 * SECOND, Some("c")),
+            vec!["c", "b", "a"]
+        );
+
+        // the cooldown of b is over
+        assert_eq!(nodes.candidates(60 * SECOND, None), vec!["b", "c", "a"]);
+        assert!(nodes.is_healthy("a", 70 * SECOND));
+        assert_eq!(nodes.candidates(70 * SECOND, None), vec!["a", "b", "c"]);
+
+        // the success resets the failures
+        nodes.report_failure("c", 0);
+        nodes.report_success("c");
+        nodes.report_failure("c", 0);
+        assert!(nodes.is_healthy("c", 0));
+
+        assert!(!nodes.is_healthy("d", 0));
+    }
+
+    #[test]
+    fn test_is_boundary_node_failure() {
+        let reject = |reject_code| {
+            AgentError::ReplicaError(RejectResponse {
+                reject_code,
+                reject_message: String::new(),
+                error_code: None,
+            })
+        };
+        let http_error =
+            |status| AgentError::HttpError(HttpErrorPayload::new(status, None, vec![]));
+
+        assert!(is_boundary_node_failure(&http_error(502)));
+        assert!(is_boundary_node_failure(&http_error(429)));
+        assert!(is_boundary_node_failure(&reject(RejectCode::SysTransient)));
+        assert!(!is_boundary_node_failure(&http_error(400)));
+        assert!(!is_boundary_node_failure(&reject(
+            RejectCode::CanisterError
+        )));
+        assert!(!is_boundary_node_failure(
+            &AgentError::CertificateVerificationFailed()
+        ));
+    }
+
+    /// Run the future of the immediately answering outcalls.
+    fn now_or_never<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("the outcall is not answered"),
+        }
+    }
+
+    /// Send over the `NODES` answering with the status of the node url,
+    /// returns the url of the node that answered and the tried urls.
+    fn send(
+        avoid: Option<&str>,
+        status: impl Fn(&str) -> u16,
+    ) -> (Result<String, AgentError>, Vec<String>) {
+        let tried = RefCell::new(vec![]);
+        let result = now_or_never(send_to_nodes(&NODES, avoid, |ic_url| {
+            tried.borrow_mut().push(ic_url.clone());
+            ready(match status(&ic_url) {
+                200 => Ok(ic_url.into_bytes()),
+                status => Err(AgentError::HttpError(HttpErrorPayload::new(
+                    status,
+                    None,
+                    vec![],
+                ))),
+            })
+        }));
+        let result = result.map(|(ic_url, body)| {
+            assert_eq!(body, ic_url.as_bytes());
+            ic_url
+        });
+        (result, tried.into_inner())
+    }
+
+    fn is_healthy(ic_url: &str) -> bool {
+        NODES.with(|nodes| nodes.borrow().is_healthy(ic_url, get_current_time()))
+    }
+
+    #[test]
+    fn test_send_to_nodes() {
+        let a_is_down = |ic_url: &str| if ic_url == "a" { 502 } else { 200 };
+
+        let (result, tried) = send(None, a_is_down);
+        assert_eq!(result.unwrap(), "b");
+        assert_eq!(tried, vec!["a", "b"]);
+        assert!(is_healthy("a"));
+
+        // a reaches the failure threshold and is tried last
+        let (result, tried) = send(None, a_is_down);
+        assert_eq!(result.unwrap(), "b");
+        assert_eq!(tried, vec!["a", "b"]);
+        assert!(!is_healthy("a"));
+
+        let (result, tried) = send(None, a_is_down);
+        assert_eq!(result.unwrap(), "b");
+        assert_eq!(tried, vec!["b"]);
+
+        // the avoided node is tried after the other healthy ones
+        let (result, tried) = send(Some("b"), |_| 200);
+        assert_eq!(result.unwrap(), "c");
+        assert_eq!(tried, vec!["c"]);
+
+        // the request error is not a node failure, it is returned without failing over
+        let (result, tried) = send(None, |_| 400);
+        assert!(matches!(result, Err(AgentError::HttpError(payload)) if payload.status == 400));
+        assert_eq!(tried, vec!["b"]);
+        assert!(is_healthy("b"));
+
+        // all nodes fail, the unhealthy one is still tried and the last error returned
+        let (result, tried) = send(None, |ic_url| if ic_url == "a" { 504 } else { 503 });
+        assert!(matches!(result, Err(AgentError::HttpError(payload)) if payload.status == 504));
+        assert_eq!(tried, vec!["b", "c", "a"]);
+
+        // a successful unhealthy node recovers
+        let (result, tried) = send(None, |ic_url| if ic_url == "a" { 200 } else { 503 });
+        assert_eq!(result.unwrap(), "a");
+        assert_eq!(tried, vec!["b", "c", "a"]);
+        assert!(is_healthy("a"));
+        assert!(!is_healthy("b"));
+        assert!(!is_healthy("c"));
+    }
+}
@@ -1,4 +1,5 @@
-use crate::types::{AgentError, RejectResponse};
+use crate::cost::HttpOutcallCost;
+use crate::types::{is_cbor_content_type, AgentError, HttpErrorPayload, RejectResponse};
 use candid::Principal;
 use ic_cdk::api::management_canister::http_request::{
     http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext,
@@ -8,14 +9,23 @@ use serde::Serialize;
 use std::ops::Add;
 
 pub mod call;
+pub mod call_v3;
+pub mod cost;
+pub mod failover;
+pub mod node_signature;
 pub mod query;
 pub mod sleeper;
+pub mod state;
+pub mod transform;
 pub mod types;
 pub mod verify;
 
 pub use call::*;
+pub use call_v3::*;
 pub use query::*;
 
+/// Send the request to the replica by the HTTPS outcall. Without `cycles` the outcall
+/// fee is estimated for the application subnet size, see `HttpOutcallCost`.
 #[allow(clippy::too_many_arguments)]
 pub async fn execute_ic_request(
     ic_url: String,
@@ -26,16 +36,99 @@ pub async fn execute_ic_request(
     transform_method: String,
     transformer_ctx: Vec<u8>,
     max_response_bytes: u64,
-    cycles: u128,
+    cycles: Option<u128>,
 ) -> Result<Vec<u8>, AgentError> {
-    let url = ic_url.add(endpoint);
+    let request = build_http_request(
+        &ic_url,
+        method,
+        endpoint,
+        body,
+        transform_canister_id,
+        transform_method,
+        transformer_ctx,
+        max_response_bytes,
+    );
+
+    let (status, body) = send_http_request(request, cycles).await?;
+
+    // status == OK means we have an error message for v2 call requests,
+    // the v3 call responses are handled by `execute_ic_call_v3`
+    // see https://internetcomputer.org/docs/current/references/ic-interface-spec#http-call
+    if status == 200 && endpoint.ends_with("call") {
+        let cbor_decoded_body: Result<RejectResponse, serde_cbor::Error> =
+            serde_cbor::from_slice(&body);
+
+        Err(match cbor_decoded_body {
+            Ok(replica_error) => AgentError::ReplicaError(replica_error),
+            Err(cbor_error) => AgentError::InvalidCborData(cbor_error),
+        })
+    } else {
+        Ok(body)
+    }
+}
+
+/// Make the HTTPS outcall, the client and server error statuses are returned as errors.
+pub(crate) async fn send_http_request(
+    request: CanisterHttpRequestArgument,
+    cycles: Option<u128>,
+) -> Result<(u16, Vec<u8>), AgentError> {
+    let cycles = cycles.unwrap_or_else(|| HttpOutcallCost::default().request_fee(&request));
+
+    match http_request(request, cycles).await {
+        //See:https://docs.rs/ic-cdk/latest/ic_cdk/api/management_canister/http_request/struct.HttpResponse.html
+        Ok((response,)) => {
+            let status: u16 = response.status.to_string().parse().unwrap();
+            let content_type = response
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("content-type"))
+                .map(|header| header.value.clone());
+            let body = response.body;
+
+            if status_is_client_error(status) || status_is_server_error(status) {
+                Err(http_error(status, content_type, body))
+            } else {
+                Ok((status, body))
+            }
+        }
+        Err((rejection_code, reject_message)) => Err(AgentError::ReplicaError(RejectResponse {
+            reject_code: rejection_code.into(),
+            reject_message,
+            error_code: None,
+        })),
+    }
+}
+
+/// Error of the client or server error response. Replicas may explain the rejection
+/// in the CBOR body, it is decoded from the whole body, only the HTTP error is truncated.
+pub(crate) fn http_error(status: u16, content_type: Option<String>, body: Vec<u8>) -> AgentError {
+    match serde_cbor::from_slice::<RejectResponse>(&body) {
+        Ok(replica_error) if is_cbor_content_type(content_type.as_deref()) => {
+            AgentError::ReplicaError(replica_error)
+        }
+        _ => AgentError::HttpError(HttpErrorPayload::new(status, content_type, body)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn build_http_request(
+    ic_url: &str,
+    method: HttpMethod,
+    endpoint: &str,
+    body: Option<Vec<u8>>,
+    transform_canister_id: Principal,
+    transform_method: String,
+    transformer_ctx: Vec<u8>,
+    max_response_bytes: u64,
+) -> CanisterHttpRequestArgument {
+    let url = ic_url.to_owned().add(endpoint);
 
     let headers = vec![HttpHeader {
         name: "content-type".to_string(),
         value: "application/cbor".to_string(),
     }];
 
-    let request = CanisterHttpRequestArgument {
+    CanisterHttpRequestArgument {
         url,
         method,
         body,
@@ -48,44 +141,6 @@ pub async fn execute_ic_request(
             context: transformer_ctx,
         }),
         headers,
-    };
-
-    match http_request(request, cycles).await {
-        //See:https://docs.rs/ic-cdk/latest/ic_cdk/api/management_canister/http_request/struct.HttpResponse.html
-        Ok((response,)) => {
-            let status: u16 = response.status.to_string().parse().unwrap();
-            // let headers = response.headers;
-            let body = response.body;
-
-            // status == OK means we have an error message for call requests
-            // see https://internetcomputer.org/docs/current/references/ic-interface-spec#http-call
-            if status == 200 && endpoint.ends_with("call") {
-                let cbor_decoded_body: Result<RejectResponse, serde_cbor::Error> =
-                    serde_cbor::from_slice(&body);
-
-                Err(match cbor_decoded_body {
-                    Ok(replica_error) => AgentError::ReplicaError(replica_error),
-                    Err(cbor_error) => AgentError::InvalidCborData(cbor_error),
-                })
-            } else if status_is_client_error(status) || status_is_server_error(status) {
-                Err(AgentError::InvalidReplicaStatus(status))
-                // Err(AgentError::HttpError(HttpErrorPayload {
-                //     status: status.into(),
-                //     content_type: headers
-                //         .get("content-type")
-                //         .and_then(|value| value.to_str().ok())
-                //         .map(|x| x.to_string()),
-                //     content: body,
-                // }))
-            } else {
-                Ok(body)
-            }
-        }
-        Err((rejection_code, reject_message)) => Err(AgentError::ReplicaError(RejectResponse {
-            reject_code: rejection_code.into(),
-            reject_message,
-            error_code: None,
-        })),
     }
 }
 
@@ -97,11 +152,19 @@ pub(crate) fn status_is_server_error(status: u16) -> bool {
     (500..600).contains(&status)
 }
 
+/// Self-describe tag prefixed by `serialize_cbor_data` (and by the replica).
+const CBOR_SELF_DESCRIBE_TAG: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// The self-describe tag is skipped: serde_cbor does not decode the enums behind a tag,
+/// e.g. the `Result` bodies reduced by the transform.
 pub fn deserialize_cbor_data<A>(serialized_bytes: &[u8]) -> Result<A, AgentError>
 where
     A: serde::de::DeserializeOwned,
 {
-    serde_cbor::from_slice(serialized_bytes).map_err(AgentError::InvalidCborData)
+    let data = serialized_bytes
+        .strip_prefix(&CBOR_SELF_DESCRIBE_TAG)
+        .unwrap_or(serialized_bytes);
+    serde_cbor::from_slice(data).map_err(AgentError::InvalidCborData)
 }
 
 pub fn serialize_cbor_data<'a, V>(data: &V) -> Result<Vec<u8>, serde_cbor::Error>
@@ -115,3 +178,66 @@ where
 
     Ok(serialized_bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{AgentError, RejectCode, RejectResponse, MAX_HTTP_ERROR_CONTENT_BYTES};
+    use crate::{deserialize_cbor_data, http_error, serialize_cbor_data};
+
+    #[test]
+    fn test_cbor_data() {
+        let reject: Result<Vec<u8>, RejectResponse> = Err(RejectResponse {
+            reject_code: RejectCode::SysTransient,
+            reject_message: "No consensus could be reached".to_owned(),
+            error_code: None,
+        });
+        let serialized = serialize_cbor_data(&reject).unwrap();
+        assert_eq!(serialized[..3], [0xd9, 0xd9, 0xf7]);
+        assert_eq!(
+            deserialize_cbor_data::<Result<Vec<u8>, RejectResponse>>(&serialized).unwrap(),
+            reject
+        );
+
+        let ok: Result<u64, String> = Ok(1);
+        let serialized = serde_cbor::to_vec(&ok).unwrap();
+        assert_eq!(
+            deserialize_cbor_data::<Result<u64, String>>(&serialized).unwrap(),
+            ok
+        );
+    }
+
+    #[test]
+    fn test_http_error() {
+        // e.g. the trap message of the canister
+        let reject = RejectResponse {
+            reject_code: RejectCode::CanisterError,
+            reject_message: "x".repeat(2 * MAX_HTTP_ERROR_CONTENT_BYTES),
+            error_code: Some("IC0503".to_owned()),
+        };
+        let body = serialize_cbor_data(&reject).unwrap();
+        assert!(body.len() > MAX_HTTP_ERROR_CONTENT_BYTES);
+
+        match http_error(400, Some("application/cbor".to_owned()), body.clone()) {
+            AgentError::ReplicaError(replica_error) => assert_eq!(replica_error, reject),
+            error => panic!("unexpected error {error:?}"),
+        }
+
+        // not declared as CBOR
+        match http_error(400, Some("text/plain".to_owned()), body) {
+            AgentError::HttpError(payload) => {
+                assert_eq!(payload.status, 400);
+                assert_eq!(payload.content.len(), MAX_HTTP_ERROR_CONTENT_BYTES);
+            }
+            error => panic!("unexpected error {error:?}"),
+        }
+
+        match http_error(
+            503,
+            Some("application/cbor".to_owned()),
+            b"overloaded".to_vec(),
+        ) {
+            AgentError::HttpError(payload) => assert_eq!(payload.content, b"overloaded"),
+            error => panic!("unexpected error {error:?}"),
+        }
+    }
+}
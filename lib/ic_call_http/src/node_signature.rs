@@ -0,0 +1,447 @@
+use crate::types::{AgentError, CallReply, NodeSignature, QueryResponse};
+use crate::verify::lookup_value;
+use crate::{deserialize_cbor_data, execute_ic_request};
+use candid::Principal;
+use ic_cdk::api::management_canister::http_request::HttpMethod;
+use ic_certification::{Certificate, Label, SubtreeLookupResult};
+use icgeek_ic_call_backend::envelope::verify_signature;
+use icgeek_ic_call_backend::request_id::to_representation_independent_hash;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const IC_RESPONSE_DOMAIN_SEPARATOR: &[u8; 12] = b"\x0Bic-response";
+
+/// DER encoded Ed25519 public key of the subnet node.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NodePublicKey {
+    pub node_id: Principal,
+    #[serde(with = "serde_bytes")]
+    pub public_key: Vec<u8>,
+}
+
+/// Public keys of the subnet nodes, refreshed per subnet from the certified state.
+/// The canister keeps the cache in its state, so that its transform query method
+/// can verify the query responses.
+#[derive(Debug, Clone, Default)]
+pub struct NodeKeyCache {
+    subnets: HashMap<Principal, SubnetNodeKeys>,
+}
+
+#[derive(Debug, Clone)]
+struct SubnetNodeKeys {
+    fetched_at_nanos: u64,
+    keys: HashMap<Principal, Vec<u8>>,
+}
+
+impl NodeKeyCache {
+    /// Replace the keys of the subnet, the nodes removed from the subnet are forgotten.
+    pub fn insert_subnet(
+        &mut self,
+        subnet_id: Principal,
+        keys: Vec<NodePublicKey>,
+        fetched_at_nanos: u64,
+    ) {
+        let keys = keys
+            .into_iter()
+            .map(|key| (key.node_id, key.public_key))
+            .collect();
+        self.subnets.insert(
+            subnet_id,
+            SubnetNodeKeys {
+                fetched_at_nanos,
+                keys,
+            },
+        );
+    }
+
+    /// Key of the node of the subnet, the nodes of the other subnets are not considered.
+    pub fn get(&self, subnet_id: &Principal, node_id: &Principal) -> Option<&[u8]> {
+        self.subnets
+            .get(subnet_id)
+            .and_then(|subnet| subnet.keys.get(node_id))
+            .map(|key| key.as_slice())
+    }
+
+    /// Whether the keys of the subnet are absent or older than the `ttl`.
+    pub fn is_expired(&self, subnet_id: &Principal, now_nanos: u64, ttl: Duration) -> bool {
+        match self.subnets.get(subnet_id) {
+            Some(subnet) => {
+                (subnet.fetched_at_nanos as u128).saturating_add(ttl.as_nanos()) < now_nanos as u128
+            }
+            None => true,
+        }
+    }
+}
+
+/// Send the signed read_state request of the `subnet/<subnet_id>/node` path.
+/// The transform must reduce the response with the `TransformCtx::SubnetNodeKeys` context.
+#[allow(clippy::too_many_arguments)]
+pub async fn fetch_subnet_node_keys(
+    ic_url: String,
+    effective_canister_id: Principal,
+    read_state_envelope: Vec<u8>,
+    transform_canister_id: Principal,
+    transform_method: String,
+    transformer_ctx: Vec<u8>,
+    max_response_bytes: u64,
+    cycles: Option<u128>,
+) -> Result<Vec<NodePublicKey>, AgentError> {
+    let body = execute_ic_request(
+        ic_url,
+        HttpMethod::POST,
+        &format!("canister/{effective_canister_id}/read_state"),
+        Some(read_state_envelope),
+        transform_canister_id,
+        transform_method,
+        transformer_ctx,
+        max_response_bytes,
+        cycles,
+    )
+    .await?;
+
+    deserialize_cbor_data::<Result<Vec<NodePublicKey>, String>>(&body)?
+        .map_err(AgentError::TransformError)
+}
+
+/// Node public keys of the subnet in the (verified) read_state certificate.
+pub fn extract_subnet_node_keys(
+    certificate: &Certificate,
+    subnet_id: &Principal,
+) -> Result<Vec<NodePublicKey>, AgentError> {
+    let nodes_path: [Label; 3] = ["subnet".into(), subnet_id.as_slice().into(), "node".into()];
+
+    let nodes = match certificate.tree.lookup_subtree(&nodes_path) {
+        SubtreeLookupResult::Found(nodes) => nodes,
+        SubtreeLookupResult::Absent => return Err(AgentError::LookupPathAbsent(nodes_path.into())),
+        SubtreeLookupResult::Unknown => {
+            return Err(AgentError::LookupPathUnknown(nodes_path.into()))
+        }
+    };
+
+    nodes
+        .list_paths()
+        .into_iter()
+        .filter(|path| path.len() == 2 && path[1].as_bytes() == b"public_key")
+        .map(|path| {
+            let node_id = Principal::try_from_slice(path[0].as_bytes())
+                .map_err(|_| AgentError::LookupPathError(path.clone()))?;
+            let public_key = lookup_value(&nodes, path)?.to_vec();
+            Ok(NodePublicKey {
+                node_id,
+                public_key,
+            })
+        })
+        .collect()
+}
+
+/// Verify that the query response is signed by the known nodes of the subnet hosting
+/// the canister, every signature must be valid.
+pub fn verify_query_response(
+    response: &QueryResponse,
+    request_id: &[u8],
+    subnet_id: &Principal,
+    node_keys: &NodeKeyCache,
+) -> Result<(), AgentError> {
+    let signatures = response.signatures();
+    if signatures.is_empty() {
+        return Err(AgentError::QuerySignatureMissing());
+    }
+
+    for signature in signatures {
+        let public_key = node_keys
+            .get(subnet_id, &signature.identity)
+            .ok_or(AgentError::NodeKeyNotFound(signature.identity))?;
+        let message = construct_query_response_message(response, signature, request_id)?;
+
+        verify_signature(public_key, &message, &signature.signature)
+            .map_err(|e| AgentError::QuerySignatureVerificationFailed(e.to_string()))?;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum SignedQueryResponse<'a> {
+    Replied {
+        status: &'static str,
+        reply: &'a CallReply,
+        timestamp: u64,
+        #[serde(with = "serde_bytes")]
+        request_id: &'a [u8],
+    },
+    Rejected {
+        status: &'static str,
+        reject_code: u64,
+        reject_message: &'a str,
+        error_code: &'a Option<String>,
+        timestamp: u64,
+        #[serde(with = "serde_bytes")]
+        request_id: &'a [u8],
+    },
+}
+
+/// Message signed by the node: the domain separator followed by the representation-independent
+/// hash of the response (without the signatures), the signature timestamp and the request id.
+fn construct_query_response_message(
+    response: &QueryResponse,
+    signature: &NodeSignature,
+    request_id: &[u8],
+) -> Result<Vec<u8>, AgentError> {
+    let signed = match response {
+        QueryResponse::Replied { reply, .. } => SignedQueryResponse::Replied {
+            status: "replied",
+            reply,
+            timestamp: signature.timestamp,
+            request_id,
+        },
+        QueryResponse::Rejected {
+            reject_code,
+            reject_message,
+            error_code,
+            ..
+        } => SignedQueryResponse::Rejected {
+            status: "rejected",
+            reject_code: *reject_code as u64,
+            reject_message,
+            error_code,
+            timestamp: signature.timestamp,
+            request_id,
+        },
+    };
+
+    let hash = to_representation_independent_hash(&signed)
+        .map_err(|e| AgentError::QuerySignatureVerificationFailed(e.to_string()))?;
+
+    let mut buf = vec![];
+    buf.extend_from_slice(IC_RESPONSE_DOMAIN_SEPARATOR);
+    buf.extend_from_slice(&hash);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node_signature::{
+        construct_query_response_message, extract_subnet_node_keys, verify_query_response,
+        NodeKeyCache, NodePublicKey,
+    };
+    use crate::types::{AgentError, CallReply, NodeSignature, QueryResponse, RejectCode};
+    use candid::Principal;
+    use ed25519_dalek::{Signer, SigningKey};
+    use ic_certification::{fork, label, leaf, pruned, Certificate, HashTree};
+    use icgeek_ic_call_backend::public_key::public_key_to_der;
+    use icgeek_ic_call_backend::sha256::get_sha256;
+    use icgeek_ic_call_backend::signer::KeyAlgorithm;
+
+    const REQUEST_ID: [u8; 32] = [9; 32];
+    const TIMESTAMP: u64 = 1_700_000_000_000_000_000;
+
+    fn subnet_id() -> Principal {
+        Principal::from_slice(&[1; 29])
+    }
+
+    fn node_id() -> Principal {
+        Principal::from_slice(&[2; 29])
+    }
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[21; 32])
+    }
+
+    fn node_public_key() -> Vec<u8> {
+        public_key_to_der(
+            KeyAlgorithm::Ed25519,
+            signing_key().verifying_key().as_bytes(),
+        )
+    }
+
+    fn node_keys() -> NodeKeyCache {
+        let mut node_keys = NodeKeyCache::default();
+        node_keys.insert_subnet(
+            subnet_id(),
+            vec![NodePublicKey {
+                node_id: node_id(),
+                public_key: node_public_key(),
+            }],
+            0,
+        );
+        node_keys
+    }
+
+    fn replied(arg: &[u8]) -> QueryResponse {
+        QueryResponse::Replied {
+            reply: CallReply { arg: arg.to_vec() },
+            signatures: vec![],
+        }
+    }
+
+    fn rejected() -> QueryResponse {
+        QueryResponse::Rejected {
+            reject_code: RejectCode::CanisterError,
+            reject_message: "Canister trapped".to_owned(),
+            error_code: Some("IC0503".to_owned()),
+            signatures: vec![],
+        }
+    }
+
+    /// The response signed by the node with the fixed key.
+    fn signed(mut response: QueryResponse) -> QueryResponse {
+        let mut signature = NodeSignature {
+            timestamp: TIMESTAMP,
+            signature: vec![],
+            identity: node_id(),
+        };
+        let message = construct_query_response_message(&response, &signature, &REQUEST_ID).unwrap();
+        signature.signature = signing_key().sign(&message).to_bytes().to_vec();
+        match &mut response {
+            QueryResponse::Replied { signatures, .. } => signatures.push(signature),
+            QueryResponse::Rejected { signatures, .. } => signatures.push(signature),
+        }
+        response
+    }
+
+    /// Representation-independent hash of the map with the hashed values.
+    fn hash_of_map(fields: &[(&str, [u8; 32])]) -> [u8; 32] {
+        let mut fields: Vec<Vec<u8>> = fields
+            .iter()
+            .map(|(name, value_hash)| [get_sha256(name).as_slice(), value_hash].concat())
+            .collect();
+        fields.sort();
+        get_sha256(fields.concat())
+    }
+
+    #[test]
+    fn test_query_response_message() {
+        let signature = NodeSignature {
+            timestamp: TIMESTAMP,
+            signature: vec![],
+            identity: node_id(),
+        };
+        let mut timestamp = vec![];
+        leb128::write::unsigned(&mut timestamp, TIMESTAMP).unwrap();
+
+        let hash = hash_of_map(&[
+            ("status", get_sha256("replied")),
+            (
+                "reply",
+                hash_of_map(&[("arg", get_sha256(b"DIDL\x00\x00"))]),
+            ),
+            ("timestamp", get_sha256(&timestamp)),
+            ("request_id", get_sha256(REQUEST_ID)),
+        ]);
+        let message =
+            construct_query_response_message(&replied(b"DIDL\x00\x00"), &signature, &REQUEST_ID)
+                .unwrap();
+        assert_eq!(message[..12], b"\x0Bic-response"[..]);
+        assert_eq!(message[12..], hash);
+
+        let hash = hash_of_map(&[
+            ("status", get_sha256("rejected")),
+            ("reject_code", get_sha256([5])),
+            ("reject_message", get_sha256("Canister trapped")),
+            ("error_code", get_sha256("IC0503")),
+            ("timestamp", get_sha256(&timestamp)),
+            ("request_id", get_sha256(REQUEST_ID)),
+        ]);
+        let message =
+            construct_query_response_message(&rejected(), &signature, &REQUEST_ID).unwrap();
+        assert_eq!(message[..12], b"\x0Bic-response"[..]);
+        assert_eq!(message[12..], hash);
+    }
+
+    #[test]
+    fn test_verify_query_response() {
+        let node_keys = node_keys();
+        for response in [signed(replied(b"DIDL\x00\x00")), signed(rejected())] {
+            assert!(
+                verify_query_response(&response, &REQUEST_ID, &subnet_id(), &node_keys).is_ok()
+            );
+        }
+
+        // the reply is forged
+        let mut response = signed(replied(b"DIDL\x00\x00"));
+        if let QueryResponse::Replied { reply, .. } = &mut response {
+            reply.arg = b"DIDL\x00\x01\x7e\x01".to_vec();
+        }
+        assert!(matches!(
+            verify_query_response(&response, &REQUEST_ID, &subnet_id(), &node_keys),
+            Err(AgentError::QuerySignatureVerificationFailed(_))
+        ));
+
+        // signed for another request
+        let response = signed(replied(b"DIDL\x00\x00"));
+        assert!(matches!(
+            verify_query_response(&response, &[8; 32], &subnet_id(), &node_keys),
+            Err(AgentError::QuerySignatureVerificationFailed(_))
+        ));
+
+        // the node is not known
+        assert!(matches!(
+            verify_query_response(&response, &REQUEST_ID, &subnet_id(), &NodeKeyCache::default()),
+            Err(AgentError::NodeKeyNotFound(node)) if node == node_id()
+        ));
+
+        // the node of another subnet does not vouch for the canister
+        let other_subnet_id = Principal::from_slice(&[3; 29]);
+        assert!(matches!(
+            verify_query_response(&response, &REQUEST_ID, &other_subnet_id, &node_keys),
+            Err(AgentError::NodeKeyNotFound(node)) if node == node_id()
+        ));
+
+        assert!(matches!(
+            verify_query_response(
+                &replied(b"DIDL\x00\x00"),
+                &REQUEST_ID,
+                &subnet_id(),
+                &node_keys
+            ),
+            Err(AgentError::QuerySignatureMissing())
+        ));
+    }
+
+    #[test]
+    fn test_extract_subnet_node_keys() {
+        let other_node_id = Principal::from_slice(&[4; 29]);
+        let node = |public_key: &[u8]| {
+            fork(
+                label("public_key", leaf(public_key.to_vec())),
+                label("status", leaf(vec![1])),
+            )
+        };
+        let nodes = fork(
+            label(node_id().as_slice(), node(&node_public_key())),
+            label(other_node_id.as_slice(), node(&[7; 44])),
+        );
+        let tree: HashTree = fork(
+            label(
+                "subnet",
+                label(subnet_id().as_slice(), label("node", nodes)),
+            ),
+            label("time", pruned([0; 32])),
+        );
+        let certificate = Certificate {
+            tree,
+            signature: vec![],
+            delegation: None,
+        };
+
+        assert_eq!(
+            extract_subnet_node_keys(&certificate, &subnet_id()).unwrap(),
+            vec![
+                NodePublicKey {
+                    node_id: node_id(),
+                    public_key: node_public_key(),
+                },
+                NodePublicKey {
+                    node_id: other_node_id,
+                    public_key: vec![7; 44],
+                },
+            ]
+        );
+        assert!(matches!(
+            extract_subnet_node_keys(&certificate, &Principal::from_slice(&[3; 29])),
+            Err(AgentError::LookupPathAbsent(_))
+        ));
+    }
+}
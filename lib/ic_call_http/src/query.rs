@@ -12,7 +12,7 @@ pub async fn execute_ic_query(
     transform_method: String,
     transformer_ctx: Vec<u8>,
     max_response_bytes: u64,
-    cycles: u128,
+    cycles: Option<u128>,
 ) -> Result<AgentCallResponseData, AgentError> {
     let effective_canister_id = request.canister_id;
     let envelope = request.request_sign;
@@ -35,7 +35,7 @@ pub async fn execute_ic_query(
 
 pub fn get_reply_from_query_response_body(response_body: &[u8]) -> Option<Vec<u8>> {
     let query_response: Result<QueryResponse, AgentError> = deserialize_cbor_data(response_body);
-    if let Ok(QueryResponse::Replied { reply }) = query_response {
+    if let Ok(QueryResponse::Replied { reply, .. }) = query_response {
         Some(reply.arg)
     } else {
         None
@@ -1,6 +1,89 @@
 use candid::Principal;
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
 use std::time::Duration;
 
+/// Sleeper waiting while the replicas execute the call, as accepted by `execute_ic_call`.
+pub type Sleeper = Box<dyn Fn() -> Pin<Box<dyn Future<Output = ()>>>>;
+
+/// Sleeper waiting for the given duration, used between the polling attempts.
+pub type DurationSleeper = Box<dyn Fn(Duration) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+/// How the canister waits.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SleepStrategy {
+    /// Canister global timer, resolved after the real duration without spending cycles on calls.
+    Timer,
+    /// Repeated `raw_rand` calls to the management canister, for the canisters
+    /// that already use the global timer for something else.
+    RawRand,
+}
+
+impl SleepStrategy {
+    pub fn sleeper(self, duration: Duration) -> Sleeper {
+        let sleeper = self.duration_sleeper();
+        Box::new(move || sleeper(duration))
+    }
+
+    pub fn duration_sleeper(self) -> DurationSleeper {
+        match self {
+            SleepStrategy::Timer => Box::new(|duration| Box::pin(timer_sleep(duration))),
+            SleepStrategy::RawRand => Box::new(|duration| {
+                Box::pin(async move {
+                    sleep(duration).await;
+                })
+            }),
+        }
+    }
+}
+
+/// Resolve after the duration, woken by the canister global timer.
+pub fn timer_sleep(duration: Duration) -> impl Future<Output = ()> {
+    let state = Rc::new(RefCell::new(TimerState::default()));
+
+    let timer_state = state.clone();
+    ic_cdk_timers::set_timer(duration, move || timer_state.borrow_mut().elapse());
+
+    TimerSleep { state }
+}
+
+#[derive(Default)]
+struct TimerState {
+    elapsed: bool,
+    waker: Option<Waker>,
+}
+
+impl TimerState {
+    /// Called by the timer: the sleep is over, the waiting task is woken.
+    fn elapse(&mut self) {
+        self.elapsed = true;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct TimerSleep {
+    state: Rc<RefCell<TimerState>>,
+}
+
+impl Future for TimerSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.borrow_mut();
+        if state.elapsed {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
 pub async fn sleep(duration: Duration) -> u16 {
     let start = get_current_time();
     let mut try_count = 1;
@@ -15,7 +98,7 @@ pub async fn sleep(duration: Duration) -> u16 {
     }
 }
 
-fn get_current_time() -> u128 {
+pub(crate) fn get_current_time() -> u128 {
     #[cfg(target_arch = "wasm32")]
     {
         ic_cdk::api::time().into()
@@ -34,3 +117,56 @@ async fn sleep_random() {
         .await
         .expect("Can not sleep over raw rand")
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::sleeper::{TimerSleep, TimerState};
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    #[derive(Default)]
+    struct FlagWaker {
+        woken: AtomicBool,
+    }
+
+    impl Wake for FlagWaker {
+        fn wake(self: Arc<Self>) {
+            self.woken.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_timer_sleep() {
+        let state = Rc::new(RefCell::new(TimerState::default()));
+        let mut sleep = TimerSleep {
+            state: state.clone(),
+        };
+
+        let mut noop_context = Context::from_waker(Waker::noop());
+        assert_eq!(Pin::new(&mut sleep).poll(&mut noop_context), Poll::Pending);
+
+        // the waker of the last poll is stored
+        let flag = Arc::new(FlagWaker::default());
+        let waker = Waker::from(flag.clone());
+        assert_eq!(
+            Pin::new(&mut sleep).poll(&mut Context::from_waker(&waker)),
+            Poll::Pending
+        );
+        assert!(state.borrow().waker.is_some());
+        assert!(!flag.woken.load(Ordering::Relaxed));
+
+        state.borrow_mut().elapse();
+        assert!(flag.woken.load(Ordering::Relaxed));
+        assert!(state.borrow().waker.is_none());
+
+        assert_eq!(
+            Pin::new(&mut sleep).poll(&mut noop_context),
+            Poll::Ready(())
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use crate::types::AgentError;
+use crate::verify::lookup_value;
+use crate::{deserialize_cbor_data, execute_ic_request};
+use candid::{CandidType, Principal};
+use ic_cdk::api::management_canister::http_request::HttpMethod;
+use ic_certification::Certificate;
+use icgeek_ic_call_backend::read_state::{
+    canister_controllers_path, canister_metadata_path, canister_module_hash_path, subnet_path,
+    time_path, StatePath,
+};
+use serde::{Deserialize, Serialize};
+
+/// Certified value to be read from the state tree, see
+/// https://internetcomputer.org/docs/current/references/ic-interface-spec#state-tree
+///
+/// The values of the canister are certified by its subnet only, so the
+/// `effective_canister_id` of the read_state request must be on the same subnet.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum StateValueRequest {
+    /// `time`
+    Time,
+    /// `subnet/<subnet_id>/canister_ranges`
+    SubnetCanisterRanges { subnet_id: Principal },
+    /// `subnet/<subnet_id>/public_key`
+    SubnetPublicKey { subnet_id: Principal },
+    /// `canister/<canister_id>/module_hash`
+    CanisterModuleHash { canister_id: Principal },
+    /// `canister/<canister_id>/controllers`
+    CanisterControllers { canister_id: Principal },
+    /// `canister/<canister_id>/metadata/<name>`, e.g. `candid:service`
+    CanisterMetadata {
+        canister_id: Principal,
+        name: String,
+    },
+}
+
+/// Decoded certified value, in the order of the requests.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum StateValue {
+    /// Nanoseconds since the epoch.
+    Time(u64),
+    /// Inclusive ranges of the canister ids of the subnet.
+    SubnetCanisterRanges(Vec<(Principal, Principal)>),
+    /// DER encoded BLS public key of the subnet.
+    SubnetPublicKey(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// SHA-256 of the installed module, `None` for the empty canister.
+    CanisterModuleHash(Option<serde_bytes::ByteBuf>),
+    CanisterControllers(Vec<Principal>),
+    /// Content of the public metadata section, `None` if the canister has no such section.
+    CanisterMetadata(Option<serde_bytes::ByteBuf>),
+}
+
+impl StateValueRequest {
+    /// Path to be signed in the read_state request.
+    pub fn path(&self) -> StatePath {
+        match self {
+            StateValueRequest::Time => time_path(),
+            StateValueRequest::SubnetCanisterRanges { subnet_id } => {
+                subnet_path(subnet_id, "canister_ranges")
+            }
+            StateValueRequest::SubnetPublicKey { subnet_id } => {
+                subnet_path(subnet_id, "public_key")
+            }
+            StateValueRequest::CanisterModuleHash { canister_id } => {
+                canister_module_hash_path(canister_id)
+            }
+            StateValueRequest::CanisterControllers { canister_id } => {
+                canister_controllers_path(canister_id)
+            }
+            StateValueRequest::CanisterMetadata { canister_id, name } => {
+                canister_metadata_path(canister_id, name)
+            }
+        }
+    }
+
+    /// Value of the path in the (verified) read_state certificate.
+    pub fn extract(&self, certificate: &Certificate) -> Result<StateValue, AgentError> {
+        let path = self.path();
+        match self {
+            StateValueRequest::Time => {
+                let mut readable = lookup_value(&certificate.tree, path)?;
+                let time = leb128::read::unsigned(&mut readable)
+                    .map_err(|error| AgentError::Leb128ReadError(format!("{error:?}")))?;
+                Ok(StateValue::Time(time))
+            }
+            StateValueRequest::SubnetCanisterRanges { .. } => {
+                let ranges = lookup_value(&certificate.tree, path)?;
+                Ok(StateValue::SubnetCanisterRanges(deserialize_cbor_data(
+                    ranges,
+                )?))
+            }
+            StateValueRequest::SubnetPublicKey { .. } => {
+                let public_key = lookup_value(&certificate.tree, path)?;
+                Ok(StateValue::SubnetPublicKey(public_key.to_vec()))
+            }
+            StateValueRequest::CanisterModuleHash { .. } => Ok(StateValue::CanisterModuleHash(
+                lookup_optional_value(certificate, path)?,
+            )),
+            StateValueRequest::CanisterControllers { .. } => {
+                let controllers = lookup_value(&certificate.tree, path)?;
+                Ok(StateValue::CanisterControllers(deserialize_cbor_data(
+                    controllers,
+                )?))
+            }
+            StateValueRequest::CanisterMetadata { .. } => Ok(StateValue::CanisterMetadata(
+                lookup_optional_value(certificate, path)?,
+            )),
+        }
+    }
+}
+
+/// Values of all the requests in the (verified) read_state certificate.
+pub fn extract_state_values(
+    certificate: &Certificate,
+    requests: &[StateValueRequest],
+) -> Result<Vec<StateValue>, AgentError> {
+    requests
+        .iter()
+        .map(|request| request.extract(certificate))
+        .collect()
+}
+
+/// Send the signed read_state request of the `StateValueRequest::path` paths.
+/// The transform must reduce the response with the `TransformCtx::StateValues` context
+/// of the same requests, see `build_state_values_transform_ctx`.
+#[allow(clippy::too_many_arguments)]
+pub async fn fetch_state_values(
+    ic_url: String,
+    effective_canister_id: Principal,
+    read_state_envelope: Vec<u8>,
+    transform_canister_id: Principal,
+    transform_method: String,
+    transformer_ctx: Vec<u8>,
+    max_response_bytes: u64,
+    cycles: Option<u128>,
+) -> Result<Vec<StateValue>, AgentError> {
+    let body = execute_ic_request(
+        ic_url,
+        HttpMethod::POST,
+        &format!("canister/{effective_canister_id}/read_state"),
+        Some(read_state_envelope),
+        transform_canister_id,
+        transform_method,
+        transformer_ctx,
+        max_response_bytes,
+        cycles,
+    )
+    .await?;
+
+    deserialize_cbor_data::<Result<Vec<StateValue>, String>>(&body)?
+        .map_err(AgentError::TransformError)
+}
+
+/// The absent path is certified as absent, unlike the pruned one.
+fn lookup_optional_value(
+    certificate: &Certificate,
+    path: StatePath,
+) -> Result<Option<serde_bytes::ByteBuf>, AgentError> {
+    match lookup_value(&certificate.tree, path) {
+        Ok(value) => Ok(Some(serde_bytes::ByteBuf::from(value))),
+        Err(AgentError::LookupPathAbsent(_)) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::{extract_state_values, StateValue, StateValueRequest};
+    use crate::types::AgentError;
+    use candid::Principal;
+    use ic_certification::{fork, label, leaf, pruned, Certificate, HashTree};
+    use serde_bytes::ByteBuf;
+
+    fn canister_id() -> Principal {
+        Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()
+    }
+
+    /// Certificate of the `canister/<canister_id>` subtree and the time.
+    fn certificate(canister: HashTree) -> Certificate {
+        let tree = fork(
+            label("canister", label(canister_id().as_slice(), canister)),
+            label("time", leaf(vec![0x80, 0x80, 0x80, 0x80, 0x10])),
+        );
+        Certificate {
+            tree,
+            signature: vec![],
+            delegation: None,
+        }
+    }
+
+    fn requests() -> Vec<StateValueRequest> {
+        vec![
+            StateValueRequest::CanisterModuleHash {
+                canister_id: canister_id(),
+            },
+            StateValueRequest::CanisterMetadata {
+                canister_id: canister_id(),
+                name: "candid:service".to_owned(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_extract() {
+        let installed = certificate(fork(
+            label(
+                "metadata",
+                label("candid:service", leaf(b"service : {}".to_vec())),
+            ),
+            label("module_hash", leaf(vec![1; 32])),
+        ));
+        assert_eq!(
+            extract_state_values(&installed, &requests()).unwrap(),
+            vec![
+                StateValue::CanisterModuleHash(Some(ByteBuf::from(vec![1; 32]))),
+                StateValue::CanisterMetadata(Some(ByteBuf::from(b"service : {}".to_vec()))),
+            ]
+        );
+        assert_eq!(
+            StateValueRequest::Time.extract(&installed).unwrap(),
+            StateValue::Time(1 << 32)
+        );
+
+        // the empty canister has neither the module hash nor the metadata
+        let empty = certificate(label("controllers", leaf(vec![])));
+        assert_eq!(
+            extract_state_values(&empty, &requests()).unwrap(),
+            vec![
+                StateValue::CanisterModuleHash(None),
+                StateValue::CanisterMetadata(None),
+            ]
+        );
+
+        // another metadata section only
+        let other_section = certificate(label(
+            "metadata",
+            label("candid:args", leaf(b"()".to_vec())),
+        ));
+        assert_eq!(
+            requests()[1].extract(&other_section).unwrap(),
+            StateValue::CanisterMetadata(None)
+        );
+    }
+
+    #[test]
+    fn test_extract_pruned() {
+        // the pruned value is not certified as absent
+        let pruned_values = certificate(fork(
+            label("metadata", pruned([2; 32])),
+            label("module_hash", pruned([3; 32])),
+        ));
+        for request in requests() {
+            assert!(matches!(
+                request.extract(&pruned_values),
+                Err(AgentError::LookupPathUnknown(_))
+            ));
+        }
+
+        let pruned_canister = certificate(pruned([4; 32]));
+        for request in requests() {
+            assert!(matches!(
+                request.extract(&pruned_canister),
+                Err(AgentError::LookupPathUnknown(_))
+            ));
+        }
+    }
+}
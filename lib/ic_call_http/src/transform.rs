@@ -0,0 +1,441 @@
+use crate::call::{get_certificate_from_state_response_body, lookup_request_status};
+use crate::node_signature::{extract_subnet_node_keys, verify_query_response, NodeKeyCache};
+use crate::state::{extract_state_values, StateValueRequest};
+use crate::types::{
+    AgentError, CallV3Response, QueryResponse, RejectResponse, RequestStatusResponse,
+};
+use crate::verify::verify_state_response_certificate;
+use crate::{deserialize_cbor_data, serialize_cbor_data};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use ic_certification::Certificate;
+use icgeek_ic_call_api::{AgentCallResponseData, AgentQueryRequest};
+use icgeek_ic_call_backend::envelope::decode_envelope;
+use serde::Deserialize;
+
+/// Candid encoded context of the transform, tells how to reduce the response.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransformCtx {
+    /// Response of the call endpoint: empty on success, CBOR reject otherwise.
+    Call,
+    /// Response of the query endpoint.
+    Query,
+    /// Response of the query endpoint, the node signatures are verified
+    /// with the keys of the `subnet_id` nodes known to the transforming canister.
+    VerifiedQuery {
+        subnet_id: Principal,
+        request_id: Vec<u8>,
+    },
+    /// Response of the read_state endpoint with the status of the call request.
+    ReadState {
+        effective_canister_id: Principal,
+        request_id: Vec<u8>,
+        ic_root_key: Vec<u8>,
+    },
+    /// Response of the v3 call endpoint: the certificate with the status of the call request,
+    /// or the non-replicated rejection.
+    CallV3 {
+        effective_canister_id: Principal,
+        request_id: Vec<u8>,
+        ic_root_key: Vec<u8>,
+    },
+    /// Response of the read_state endpoint with the public keys of the subnet nodes.
+    SubnetNodeKeys {
+        effective_canister_id: Principal,
+        subnet_id: Principal,
+        ic_root_key: Vec<u8>,
+    },
+    /// Response of the read_state endpoint with the certified values of the requests.
+    StateValues {
+        effective_canister_id: Principal,
+        requests: Vec<StateValueRequest>,
+        ic_root_key: Vec<u8>,
+    },
+}
+
+impl TransformCtx {
+    pub fn encode(&self) -> Result<Vec<u8>, AgentError> {
+        Encode!(self).map_err(|e| AgentError::CandidError(e.to_string()))
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, AgentError> {
+        Decode!(bytes, Self).map_err(|e| AgentError::CandidError(e.to_string()))
+    }
+}
+
+/// Read state transform context, to be passed as the `read_state_transform_ctx_builder`.
+pub fn build_read_state_transform_ctx(
+    effective_canister_id: Principal,
+    request_id: Vec<u8>,
+    ic_root_key: Vec<u8>,
+) -> Vec<u8> {
+    TransformCtx::ReadState {
+        effective_canister_id,
+        request_id,
+        ic_root_key,
+    }
+    .encode()
+    .expect("Can not encode the transform context")
+}
+
+/// Transform context of the v3 call request, see `execute_ic_call_v3`.
+pub fn build_call_v3_transform_ctx(
+    effective_canister_id: Principal,
+    request_id: Vec<u8>,
+    ic_root_key: Vec<u8>,
+) -> Vec<u8> {
+    TransformCtx::CallV3 {
+        effective_canister_id,
+        request_id,
+        ic_root_key,
+    }
+    .encode()
+    .expect("Can not encode the transform context")
+}
+
+/// Transform context of the read_state request, see `fetch_state_values`.
+pub fn build_state_values_transform_ctx(
+    effective_canister_id: Principal,
+    requests: Vec<StateValueRequest>,
+    ic_root_key: Vec<u8>,
+) -> Vec<u8> {
+    TransformCtx::StateValues {
+        effective_canister_id,
+        requests,
+        ic_root_key,
+    }
+    .encode()
+    .expect("Can not encode the transform context")
+}
+
+/// Verified query transform context of the signed query request,
+/// `subnet_id` is the subnet hosting the queried canister.
+pub fn build_verified_query_transform_ctx(
+    request: &AgentQueryRequest,
+    subnet_id: Principal,
+) -> Result<Vec<u8>, AgentError> {
+    let envelope = decode_envelope(&request.request_sign)
+        .map_err(|e| AgentError::TransformError(e.to_string()))?;
+    TransformCtx::VerifiedQuery {
+        subnet_id,
+        request_id: envelope.request_id.to_vec(),
+    }
+    .encode()
+}
+
+/// Transform reducing the responses of all replicas to the same body.
+/// The headers except the content type are stripped and the successful bodies are re-encoded:
+/// * the call reject as CBOR `RejectResponse`,
+/// * the query response as CBOR `QueryResponse` without the node signatures,
+/// * the verified query response as CBOR `Result<QueryResponse, String>`,
+/// * the read_state response as CBOR `Result<RequestStatusResponse, String>`,
+///   after the certificate is verified w.r.t. the root key of the context,
+/// * the v3 call response the same way, the non-replicated rejection as the rejected status,
+/// * the subnet node keys as CBOR `Result<Vec<NodePublicKey>, String>`, the same way,
+/// * the certified state values as CBOR `Result<Vec<StateValue>, String>`, the same way.
+///
+/// The canister exposes it as its transform query method:
+/// `#[query] fn transform(args: TransformArgs) -> HttpResponse { transform_response(args) }`.
+/// Without node keys the verified queries fail, see `transform_response_with_node_keys`.
+pub fn transform_response(args: TransformArgs) -> HttpResponse {
+    transform_response_with_node_keys(args, &NodeKeyCache::default())
+}
+
+/// Transform verifying the query responses with the node keys kept by the canister.
+pub fn transform_response_with_node_keys(
+    args: TransformArgs,
+    node_keys: &NodeKeyCache,
+) -> HttpResponse {
+    let TransformArgs { response, context } = args;
+    let success = response.status == 200_u16;
+
+    let body = match TransformCtx::decode(&context) {
+        Ok(TransformCtx::Call) if success => {
+            reencode::<RejectResponse>(&response.body).unwrap_or(response.body)
+        }
+        Ok(TransformCtx::Query) if success => {
+            deserialize_cbor_data::<QueryResponse>(&response.body)
+                .ok()
+                .and_then(|query_response| {
+                    serialize_cbor_data(&query_response.without_signatures()).ok()
+                })
+                .unwrap_or(response.body)
+        }
+        Ok(TransformCtx::VerifiedQuery {
+            subnet_id,
+            request_id,
+        }) if success => {
+            let query_response = deserialize_cbor_data::<QueryResponse>(&response.body)
+                .and_then(|query_response| {
+                    verify_query_response(&query_response, &request_id, &subnet_id, node_keys)?;
+                    Ok(query_response.without_signatures())
+                })
+                .map_err(|e| e.to_string());
+            serialize_cbor_data(&query_response).unwrap_or(response.body)
+        }
+        Ok(TransformCtx::ReadState {
+            effective_canister_id,
+            request_id,
+            ic_root_key,
+        }) if success => {
+            let status = get_certificate_from_state_response_body(&response.body)
+                .and_then(|certificate| {
+                    verified_request_status(
+                        &certificate,
+                        effective_canister_id,
+                        &request_id,
+                        ic_root_key,
+                    )
+                })
+                .map_err(|e| e.to_string());
+            serialize_cbor_data(&status).unwrap_or(response.body)
+        }
+        Ok(TransformCtx::CallV3 {
+            effective_canister_id,
+            request_id,
+            ic_root_key,
+        }) if success => {
+            let status = deserialize_cbor_data::<CallV3Response>(&response.body)
+                .and_then(|call_response| match call_response {
+                    CallV3Response::Replied { certificate } => verified_request_status(
+                        &deserialize_cbor_data(&certificate)?,
+                        effective_canister_id,
+                        &request_id,
+                        ic_root_key,
+                    ),
+                    CallV3Response::NonReplicatedRejection(reject) => {
+                        Ok(RequestStatusResponse::Rejected(reject))
+                    }
+                })
+                .map_err(|e| e.to_string());
+            serialize_cbor_data(&status).unwrap_or(response.body)
+        }
+        Ok(TransformCtx::SubnetNodeKeys {
+            effective_canister_id,
+            subnet_id,
+            ic_root_key,
+        }) if success => {
+            let keys = get_certificate_from_state_response_body(&response.body)
+                .and_then(|certificate| {
+                    verify_state_response_certificate(
+                        &certificate,
+                        effective_canister_id,
+                        ic_root_key,
+                    )?;
+                    extract_subnet_node_keys(&certificate, &subnet_id)
+                })
+                .map_err(|e| e.to_string());
+            serialize_cbor_data(&keys).unwrap_or(response.body)
+        }
+        Ok(TransformCtx::StateValues {
+            effective_canister_id,
+            requests,
+            ic_root_key,
+        }) if success => {
+            let values = get_certificate_from_state_response_body(&response.body)
+                .and_then(|certificate| {
+                    verify_state_response_certificate(
+                        &certificate,
+                        effective_canister_id,
+                        ic_root_key,
+                    )?;
+                    extract_state_values(&certificate, &requests)
+                })
+                .map_err(|e| e.to_string());
+            serialize_cbor_data(&values).unwrap_or(response.body)
+        }
+        _ => response.body,
+    };
+
+    HttpResponse {
+        status: response.status,
+        headers: response
+            .headers
+            .into_iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("content-type"))
+            .collect(),
+        body,
+    }
+}
+
+fn verified_request_status(
+    certificate: &Certificate,
+    effective_canister_id: Principal,
+    request_id: &[u8],
+    ic_root_key: Vec<u8>,
+) -> Result<RequestStatusResponse, AgentError> {
+    verify_state_response_certificate(certificate, effective_canister_id, ic_root_key)?;
+    lookup_request_status(certificate, request_id)
+}
+
+/// Request status from the read_state or the v3 call response body reduced by `transform_response`.
+pub fn decode_request_status(body: &[u8]) -> Result<RequestStatusResponse, AgentError> {
+    deserialize_cbor_data::<Result<RequestStatusResponse, String>>(body)?
+        .map_err(AgentError::TransformError)
+}
+
+/// Reply of the verified query response body reduced by `transform_response_with_node_keys`.
+pub fn decode_verified_query_reply(body: &[u8]) -> Result<AgentCallResponseData, AgentError> {
+    match deserialize_cbor_data::<Result<QueryResponse, String>>(body)?
+        .map_err(AgentError::TransformError)?
+    {
+        QueryResponse::Replied { reply, .. } => Ok(reply.arg),
+        QueryResponse::Rejected {
+            reject_code,
+            reject_message,
+            error_code,
+            ..
+        } => Err(AgentError::ReplicaError(RejectResponse {
+            reject_code,
+            reject_message,
+            error_code,
+        })),
+    }
+}
+
+fn reencode<T>(body: &[u8]) -> Option<Vec<u8>>
+where
+    T: serde::de::DeserializeOwned + serde::Serialize,
+{
+    let value: T = deserialize_cbor_data(body).ok()?;
+    serialize_cbor_data(&value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transform::{
+        build_call_v3_transform_ctx, decode_request_status, transform_response,
+    };
+    use crate::types::{
+        AgentError, CallV3Response, RejectCode, RejectResponse, RequestStatusResponse,
+    };
+    use crate::{deserialize_cbor_data, serialize_cbor_data};
+    use candid::Principal;
+    use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpResponse, TransformArgs};
+    use ic_certification::{empty, Certificate, HashTree};
+    use serde_cbor::Value;
+    use std::collections::BTreeMap;
+
+    /// CBOR map of the v3 call response, as sent by the replica.
+    fn call_v3_body(fields: Vec<(&str, Value)>) -> Vec<u8> {
+        let map: BTreeMap<Value, Value> = fields
+            .into_iter()
+            .map(|(name, value)| (Value::Text(name.to_owned()), value))
+            .collect();
+        serde_cbor::to_vec(&Value::Map(map)).unwrap()
+    }
+
+    fn rejection_body() -> Vec<u8> {
+        call_v3_body(vec![
+            ("status", Value::Text("non_replicated_rejection".to_owned())),
+            ("reject_code", Value::Integer(5)),
+            (
+                "reject_message",
+                Value::Text("Canister is stopped".to_owned()),
+            ),
+            ("error_code", Value::Text("IC0508".to_owned())),
+        ])
+    }
+
+    fn rejection() -> RejectResponse {
+        RejectResponse {
+            reject_code: RejectCode::CanisterError,
+            reject_message: "Canister is stopped".to_owned(),
+            error_code: Some("IC0508".to_owned()),
+        }
+    }
+
+    fn unsigned_certificate() -> Vec<u8> {
+        let certificate: Certificate = Certificate {
+            tree: HashTree::from(empty()),
+            signature: vec![],
+            delegation: None,
+        };
+        serialize_cbor_data(&certificate).unwrap()
+    }
+
+    fn transform_call_v3(status: u16, body: Vec<u8>) -> HttpResponse {
+        transform_response(TransformArgs {
+            response: HttpResponse {
+                status: status.into(),
+                headers: vec![
+                    HttpHeader {
+                        name: "Content-Type".to_owned(),
+                        value: "application/cbor".to_owned(),
+                    },
+                    HttpHeader {
+                        name: "date".to_owned(),
+                        value: "Sat, 17 Oct 2026 10:00:00 GMT".to_owned(),
+                    },
+                ],
+                body,
+            },
+            context: build_call_v3_transform_ctx(Principal::anonymous(), vec![7; 32], vec![]),
+        })
+    }
+
+    #[test]
+    fn test_call_v3_response() {
+        let certificate = unsigned_certificate();
+        let body = call_v3_body(vec![
+            ("status", Value::Text("replied".to_owned())),
+            ("certificate", Value::Bytes(certificate.clone())),
+        ]);
+        match deserialize_cbor_data::<CallV3Response>(&body).unwrap() {
+            CallV3Response::Replied {
+                certificate: decoded,
+            } => assert_eq!(decoded, certificate),
+            response => panic!("unexpected response {response:?}"),
+        }
+
+        match deserialize_cbor_data::<CallV3Response>(&rejection_body()).unwrap() {
+            CallV3Response::NonReplicatedRejection(reject) => assert_eq!(reject, rejection()),
+            response => panic!("unexpected response {response:?}"),
+        }
+
+        // the replicas before the error codes do not send them
+        let body = call_v3_body(vec![
+            ("status", Value::Text("non_replicated_rejection".to_owned())),
+            ("reject_code", Value::Integer(3)),
+            (
+                "reject_message",
+                Value::Text("Canister not found".to_owned()),
+            ),
+        ]);
+        match deserialize_cbor_data::<CallV3Response>(&body).unwrap() {
+            CallV3Response::NonReplicatedRejection(reject) => {
+                assert_eq!(reject.reject_code, RejectCode::DestinationInvalid);
+                assert_eq!(reject.error_code, None);
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+
+        let body = call_v3_body(vec![("status", Value::Text("accepted".to_owned()))]);
+        assert!(deserialize_cbor_data::<CallV3Response>(&body).is_err());
+    }
+
+    #[test]
+    fn test_transform_call_v3() {
+        let response = transform_call_v3(200, rejection_body());
+        assert_eq!(response.headers.len(), 1);
+        assert_eq!(
+            decode_request_status(&response.body).unwrap(),
+            RequestStatusResponse::Rejected(rejection())
+        );
+
+        // the certificate without a valid signature is not trusted
+        let body = call_v3_body(vec![
+            ("status", Value::Text("replied".to_owned())),
+            ("certificate", Value::Bytes(unsigned_certificate())),
+        ]);
+        let response = transform_call_v3(200, body);
+        assert!(matches!(
+            decode_request_status(&response.body),
+            Err(AgentError::TransformError(_))
+        ));
+
+        // the accepted call is not reduced
+        let response = transform_call_v3(202, vec![]);
+        assert!(response.body.is_empty());
+    }
+}
@@ -1,3 +1,5 @@
+use candid::types::Type;
+use candid::{CandidType, Principal};
 use ic_cdk::api::call::RejectionCode;
 use ic_certification::Label;
 use serde::{Deserialize, Serialize};
@@ -9,13 +11,16 @@ use thiserror::Error;
 /// An error that occurred when using the http request.
 #[derive(Error, Debug)]
 pub enum AgentError {
-    // /// The replica URL was invalid.
-    // #[error(r#"Invalid Replica URL: "{0}""#)]
-    // InvalidReplicaUrl(String),
-    //
+    /// The replica URL was invalid.
+    #[error(r#"Invalid Replica URL: "{0}""#)]
+    InvalidReplicaUrl(String),
+
     // /// The request timed out.
     #[error("The request timed out.")]
     TimeoutWaitingForResponse(),
+
+    #[error("The request exceeded the cycles budget of {0} cycles.")]
+    CyclesBudgetExceeded(u128),
     //
     // /// An error occurred when signing with the identity.
     // #[error("Identity had a signing error: {0}")]
@@ -27,10 +32,6 @@ pub enum AgentError {
     // #[error("Cannot calculate a RequestID: {0}")]
     // CannotCalculateRequestId(#[from] RequestIdError),
     //
-    // /// There was an error when de/serializing with Candid.
-    // #[error("Candid returned an error: {0}")]
-    // CandidError(Box<dyn Send + Sync + std::error::Error>),
-    //
     // /// There was an error parsing a URL.
     // #[error(r#"Cannot parse url: "{0}""#)]
     // UrlParseError(#[from] url::ParseError),
@@ -45,17 +46,31 @@ pub enum AgentError {
     #[error("The replica returned a replica error: {0}")]
     ReplicaError(RejectResponse),
 
-    // #[error("The replica returned an HTTP Error: {0}")]
-    // HttpError(HttpErrorPayload),
-    #[error("Status endpoint returned an invalid status: {0}")]
-    InvalidReplicaStatus(u16),
+    #[error("The replica returned an HTTP Error: {0}")]
+    HttpError(HttpErrorPayload),
+
+    #[error("No boundary nodes are configured.")]
+    NoBoundaryNodes(),
 
     #[error("Call was marked as done but we never saw the reply. Request ID: {0}")]
     RequestStatusDoneNoReply(String),
 
+    #[error("Call is not completed yet, request status: {1}. Request ID: {0}")]
+    RequestStatusNotCompleted(String, String),
+
     // /// A string error occurred in an external tool.
     // #[error("A tool returned a string message error: {0}")]
     // MessageError(String),
+    /// There was an error when de/serializing with Candid.
+    #[error("Candid returned an error: {0}")]
+    CandidError(String),
+
+    #[error("The transform function failed: {0}")]
+    TransformError(String),
+
+    #[error("Invalid signed request envelope: {0}")]
+    InvalidEnvelope(String),
+
     #[error("Error reading LEB128 value: {0}")]
     Leb128ReadError(String),
 
@@ -78,6 +93,15 @@ pub enum AgentError {
     #[error("Certificate verification failed.")]
     CertificateVerificationFailed(),
 
+    #[error("Query response is not signed by any node.")]
+    QuerySignatureMissing(),
+
+    #[error("Public key of the node {0} is not known.")]
+    NodeKeyNotFound(Principal),
+
+    #[error("Query response signature verification failed: {0}")]
+    QuerySignatureVerificationFailed(String),
+
     #[error("Certificate is not authorized to respond to queries for this canister. While developing: Did you forget to set effective_canister_id?")]
     CertificateNotAuthorized(),
 
@@ -138,7 +162,7 @@ pub enum AgentError {
     // },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq)]
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize, Ord, PartialOrd, Eq, PartialEq)]
 pub struct RejectResponse {
     /// The [reject code](https://smartcontracts.org/docs/interface-spec/index.html#reject-codes) returned by the replica.
     pub reject_code: RejectCode,
@@ -178,6 +202,20 @@ pub enum RejectCode {
     Unknown,
 }
 
+/// Candid `nat8`, the same as the CBOR encoding.
+impl CandidType for RejectCode {
+    fn _ty() -> Type {
+        u8::ty()
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: candid::types::Serializer,
+    {
+        (*self as u8).idl_serialize(serializer)
+    }
+}
+
 impl TryFrom<u64> for RejectCode {
     type Error = String;
 
@@ -207,23 +245,168 @@ impl From<RejectionCode> for RejectCode {
     }
 }
 
-// #[derive(Debug)]
-// pub struct HttpErrorPayload {
-//     /// The HTTP status code.
-//     pub status: u16,
-//     /// The MIME type of `content`.
-//     pub content_type: Option<String>,
-//     /// The body of the error.
-//     pub content: Vec<u8>,
-// }
+/// Longest body kept in the `HttpErrorPayload`.
+pub const MAX_HTTP_ERROR_CONTENT_BYTES: usize = 1024;
+
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HttpErrorPayload {
+    /// The HTTP status code.
+    pub status: u16,
+    /// The MIME type of `content`.
+    pub content_type: Option<String>,
+    /// The body of the error, truncated to `MAX_HTTP_ERROR_CONTENT_BYTES`.
+    #[serde(with = "serde_bytes")]
+    pub content: Vec<u8>,
+}
+
+impl HttpErrorPayload {
+    pub fn new(status: u16, content_type: Option<String>, mut content: Vec<u8>) -> Self {
+        content.truncate(MAX_HTTP_ERROR_CONTENT_BYTES);
+        Self {
+            status,
+            content_type,
+            content,
+        }
+    }
+
+    /// Whether the content is the CBOR encoded body, as the replica errors are.
+    pub fn is_cbor(&self) -> bool {
+        is_cbor_content_type(self.content_type.as_deref())
+    }
+}
+
+pub(crate) fn is_cbor_content_type(content_type: Option<&str>) -> bool {
+    content_type
+        .map(|content_type| content_type.starts_with("application/cbor"))
+        .unwrap_or(false)
+}
+
+impl Display for HttpErrorPayload {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_fmt(format_args!(
+            "Http Error: status {}, content type {:?}, content: {}",
+            self.status,
+            self.content_type,
+            String::from_utf8_lossy(&self.content),
+        ))
+    }
+}
+
+/// Candid serializable form of the `AgentError`, for the canisters returning it to their callers.
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum CallError {
+    HttpError(HttpErrorPayload),
+    ReplicaError(RejectResponse),
+    Other(String),
+}
+
+impl From<AgentError> for CallError {
+    fn from(error: AgentError) -> Self {
+        match error {
+            AgentError::HttpError(payload) => CallError::HttpError(payload),
+            AgentError::ReplicaError(reject) => CallError::ReplicaError(reject),
+            error => CallError::Other(error.to_string()),
+        }
+    }
+}
+
+/// Status of the call request, as certified in the `request_status` subtree.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RequestStatusResponse {
+    /// The status of the request is unknown.
+    Unknown,
+    /// The request has been received, and will probably get processed.
+    Received,
+    /// The request is currently being processed.
+    Processing,
+    /// The request has been successfully replied to.
+    Replied(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// The request has been rejected.
+    Rejected(RejectResponse),
+    /// The call has been completed, and it has been long enough that the reply/reject data
+    /// has been purged, but the call has not expired yet.
+    Done,
+}
+
+impl RequestStatusResponse {
+    pub fn status(&self) -> &'static str {
+        match self {
+            RequestStatusResponse::Unknown => "unknown",
+            RequestStatusResponse::Received => "received",
+            RequestStatusResponse::Processing => "processing",
+            RequestStatusResponse::Replied(_) => "replied",
+            RequestStatusResponse::Rejected(_) => "rejected",
+            RequestStatusResponse::Done => "done",
+        }
+    }
+
+    /// Reply of the call, or the error telling why there is no reply.
+    pub fn into_reply(self, request_id: &[u8]) -> Result<Vec<u8>, AgentError> {
+        match self {
+            RequestStatusResponse::Replied(reply) => Ok(reply),
+            RequestStatusResponse::Rejected(reject) => Err(AgentError::ReplicaError(reject)),
+            RequestStatusResponse::Done => {
+                Err(AgentError::RequestStatusDoneNoReply(hex(request_id)))
+            }
+            pending => Err(AgentError::RequestStatusNotCompleted(
+                hex(request_id),
+                pending.status().to_owned(),
+            )),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "status")]
 pub enum QueryResponse {
     #[serde(rename = "replied")]
-    Replied { reply: CallReply },
+    Replied {
+        reply: CallReply,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        signatures: Vec<NodeSignature>,
+    },
     #[serde(rename = "rejected")]
-    Rejected(RejectResponse),
+    Rejected {
+        reject_code: RejectCode,
+        reject_message: String,
+        #[serde(default)]
+        error_code: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        signatures: Vec<NodeSignature>,
+    },
+}
+
+impl QueryResponse {
+    /// Signatures of the nodes that executed the query.
+    pub fn signatures(&self) -> &[NodeSignature] {
+        match self {
+            QueryResponse::Replied { signatures, .. } => signatures,
+            QueryResponse::Rejected { signatures, .. } => signatures,
+        }
+    }
+
+    /// The same response without the node signatures, which differ between the replicas.
+    pub fn without_signatures(mut self) -> Self {
+        match &mut self {
+            QueryResponse::Replied { signatures, .. } => signatures.clear(),
+            QueryResponse::Rejected { signatures, .. } => signatures.clear(),
+        }
+        self
+    }
+}
+
+/// Signature of the query response by the node, see
+/// https://internetcomputer.org/docs/current/references/ic-interface-spec#http-query
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct NodeSignature {
+    pub timestamp: u64,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+    pub identity: Principal,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -237,3 +420,53 @@ pub struct ReadStateResponse {
     #[serde(with = "serde_bytes")]
     pub certificate: Vec<u8>,
 }
+
+/// Response of the v3 call endpoint completed in time, see
+/// https://internetcomputer.org/docs/current/references/ic-interface-spec#http-call
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "status")]
+pub enum CallV3Response {
+    /// Certificate with the status of the request.
+    #[serde(rename = "replied")]
+    Replied {
+        #[serde(with = "serde_bytes")]
+        certificate: Vec<u8>,
+    },
+    #[serde(rename = "non_replicated_rejection")]
+    NonReplicatedRejection(RejectResponse),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{AgentError, CallError, HttpErrorPayload, MAX_HTTP_ERROR_CONTENT_BYTES};
+
+    #[test]
+    fn test_http_error_payload() {
+        let payload = HttpErrorPayload::new(
+            503,
+            Some("text/plain".to_owned()),
+            vec![b'x'; MAX_HTTP_ERROR_CONTENT_BYTES + 1],
+        );
+        assert_eq!(payload.content, vec![b'x'; MAX_HTTP_ERROR_CONTENT_BYTES]);
+        assert!(!payload.is_cbor());
+
+        let payload = HttpErrorPayload::new(400, None, b"invalid".to_vec());
+        assert_eq!(payload.content, b"invalid");
+        assert!(!payload.is_cbor());
+        assert_eq!(
+            payload.to_string(),
+            "Http Error: status 400, content type None, content: invalid"
+        );
+
+        let payload = HttpErrorPayload::new(
+            400,
+            Some("application/cbor; charset=utf-8".to_owned()),
+            vec![],
+        );
+        assert!(payload.is_cbor());
+        assert_eq!(
+            CallError::from(AgentError::HttpError(payload.clone())),
+            CallError::HttpError(payload)
+        );
+    }
+}
@@ -0,0 +1,197 @@
+mod key;
+mod request_file;
+
+use crate::key::{OsRandGenerator, PemSigner, SignerCtx};
+use crate::request_file::{read_request, write_request, RequestFormat};
+use candid::{IDLArgs, Principal};
+use clap::{Parser, Subcommand};
+use ic_agent::agent::http_transport::ReqwestHttpReplicaV2Transport;
+use ic_agent::Agent;
+use icgeek_ic_call_api::AgentRequest;
+use icgeek_ic_call_backend::IngressExpiryPolicy;
+use icgeek_ic_call_client::CallV3Transport;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Sign internet computer requests offline and submit them later.
+#[derive(Parser)]
+#[command(name = "ic-call-signer", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Sign the request with the PEM key and write it to the file.
+    Sign {
+        /// PEM file with the Ed25519 or secp256k1 private key.
+        #[arg(long)]
+        pem: PathBuf,
+        #[arg(long)]
+        canister_id: Principal,
+        #[arg(long)]
+        method: String,
+        /// Candid text argument, e.g. `(record { to = "..."; amount = 10 : nat })`.
+        #[arg(long, default_value = "()")]
+        arg: String,
+        /// Sign a query instead of an update call.
+        #[arg(long)]
+        query: bool,
+        /// Lifetime of the signed request, replicas accept at most 5 minutes.
+        #[arg(long, default_value_t = 300)]
+        lifetime_secs: u64,
+        #[arg(long)]
+        out: PathBuf,
+        /// Defaults to JSON for the `.json` files and to CBOR otherwise.
+        #[arg(long, value_enum)]
+        format: Option<RequestFormat>,
+    },
+    /// Submit the signed request file and print the Candid reply.
+    Submit {
+        #[arg(long)]
+        file: PathBuf,
+        #[arg(long, default_value = "https://icp0.io")]
+        url: String,
+        /// Fetch the root key of the replica, only for the local development replicas.
+        #[arg(long)]
+        fetch_root_key: bool,
+        /// Submit the call to the v3 endpoint, which replies synchronously.
+        #[arg(long)]
+        v3: bool,
+        #[arg(long, value_enum)]
+        format: Option<RequestFormat>,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let result = match Cli::parse().command {
+        Command::Sign {
+            pem,
+            canister_id,
+            method,
+            arg,
+            query,
+            lifetime_secs,
+            out,
+            format,
+        } => {
+            sign(
+                pem,
+                canister_id,
+                method,
+                arg,
+                query,
+                lifetime_secs,
+                out,
+                format,
+            )
+            .await
+        }
+        Command::Submit {
+            file,
+            url,
+            fetch_root_key,
+            v3,
+            format,
+        } => submit(file, url, fetch_root_key, v3, format).await,
+    };
+
+    if let Err(error) = result {
+        eprintln!("{error}");
+        std::process::exit(1);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn sign(
+    pem: PathBuf,
+    canister_id: Principal,
+    method: String,
+    arg: String,
+    query: bool,
+    lifetime_secs: u64,
+    out: PathBuf,
+    format: Option<RequestFormat>,
+) -> Result<(), String> {
+    let pem = std::fs::read_to_string(&pem)
+        .map_err(|e| format!("Can not read {}: {e}", pem.display()))?;
+    let signer = PemSigner::from_pem(&pem)?;
+
+    let arg = arg
+        .parse::<IDLArgs>()
+        .and_then(|args| args.to_bytes())
+        .map_err(|e| format!("Invalid Candid argument: {e}"))?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    let policy = IngressExpiryPolicy {
+        lifetime: Duration::from_secs(lifetime_secs),
+        ..Default::default()
+    };
+    let ctx = SignerCtx {
+        ingress_expiry: policy.get_ingress_expiry(now.as_nanos()),
+    };
+
+    let request = if query {
+        AgentRequest::Query(
+            icgeek_ic_call_backend::create_query_request(&ctx, &signer, &canister_id, &method, arg)
+                .await?,
+        )
+    } else {
+        AgentRequest::Call(
+            icgeek_ic_call_backend::create_call_request(
+                &ctx,
+                &signer,
+                &OsRandGenerator,
+                &canister_id,
+                &method,
+                arg,
+            )
+            .await?,
+        )
+    };
+
+    write_request(&out, &request, format)
+}
+
+async fn submit(
+    file: PathBuf,
+    url: String,
+    fetch_root_key: bool,
+    v3: bool,
+    format: Option<RequestFormat>,
+) -> Result<(), String> {
+    let request = read_request(&file, format)?;
+
+    let agent = Agent::builder()
+        .with_transport(ReqwestHttpReplicaV2Transport::create(&url).map_err(|e| e.to_string())?)
+        .build()
+        .map_err(|e| e.to_string())?;
+    if fetch_root_key {
+        agent.fetch_root_key().await.map_err(|e| e.to_string())?;
+    }
+    let call_transport = ReqwestHttpReplicaV2Transport::create(&url).map_err(|e| e.to_string())?;
+
+    let reply = if v3 {
+        let call_v3_transport = CallV3Transport::create(&url).map_err(|e| e.to_string())?;
+        icgeek_ic_call_client::perform_request_v3(
+            &agent,
+            &call_transport,
+            &call_v3_transport,
+            request,
+        )
+        .await
+    } else {
+        icgeek_ic_call_client::perform_request(&agent, &call_transport, request).await
+    }
+    .map_err(|e| e.to_string())?;
+
+    match IDLArgs::from_bytes(&reply) {
+        Ok(args) => println!("{args}"),
+        Err(_) => println!("{}", hex::encode(&reply)),
+    }
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use clap::ValueEnum;
+use icgeek_ic_call_api::AgentRequest;
+use std::path::Path;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+pub enum RequestFormat {
+    Cbor,
+    Json,
+}
+
+impl RequestFormat {
+    /// JSON for the `.json` files, CBOR otherwise.
+    pub fn detect(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("json") => RequestFormat::Json,
+            _ => RequestFormat::Cbor,
+        }
+    }
+}
+
+pub fn encode_request(request: &AgentRequest, format: RequestFormat) -> Result<Vec<u8>, String> {
+    match format {
+        RequestFormat::Cbor => serde_cbor::to_vec(request).map_err(|e| e.to_string()),
+        RequestFormat::Json => serde_json::to_vec_pretty(request).map_err(|e| e.to_string()),
+    }
+}
+
+pub fn decode_request(bytes: &[u8], format: RequestFormat) -> Result<AgentRequest, String> {
+    match format {
+        RequestFormat::Cbor => serde_cbor::from_slice(bytes).map_err(|e| e.to_string()),
+        RequestFormat::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+    }
+}
+
+pub fn write_request(
+    path: &Path,
+    request: &AgentRequest,
+    format: Option<RequestFormat>,
+) -> Result<(), String> {
+    let format = format.unwrap_or_else(|| RequestFormat::detect(path));
+    let bytes = encode_request(request, format)?;
+    std::fs::write(path, bytes).map_err(|e| format!("Can not write {}: {e}", path.display()))
+}
+
+pub fn read_request(path: &Path, format: Option<RequestFormat>) -> Result<AgentRequest, String> {
+    let format = format.unwrap_or_else(|| RequestFormat::detect(path));
+    let bytes = std::fs::read(path).map_err(|e| format!("Can not read {}: {e}", path.display()))?;
+    decode_request(&bytes, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::request_file::{decode_request, encode_request, RequestFormat};
+    use candid::Principal;
+    use icgeek_ic_call_api::{AgentCallRequest, AgentRequest};
+    use std::path::Path;
+
+    #[test]
+    fn test() {
+        let request = AgentRequest::Call(AgentCallRequest {
+            canister_id: Principal::from_text("r5m4o-xaaaa-aaaah-qbpfq-cai").unwrap(),
+            request_id: vec![1; 32],
+            request_sign: vec![2, 3],
+            read_state_request_sign: vec![4, 5],
+        });
+
+        for format in [RequestFormat::Cbor, RequestFormat::Json] {
+            let bytes = encode_request(&request, format).unwrap();
+            assert_eq!(decode_request(&bytes, format).unwrap(), request);
+        }
+
+        assert_eq!(
+            RequestFormat::detect(Path::new("transfer.JSON")),
+            RequestFormat::Json
+        );
+        assert_eq!(
+            RequestFormat::detect(Path::new("transfer.cbor")),
+            RequestFormat::Cbor
+        );
+    }
+}
@@ -1,10 +1,15 @@
 use candid::Principal;
 use ic_certification::{Certificate, Delegation, HashTree, Label, LookupResult};
 use ic_verify_bls_signature::verify_bls_signature;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
 const DER_PREFIX: &[u8; 37] = b"\x30\x81\x82\x30\x1d\x06\x0d\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x01\x02\x01\x06\x0c\x2b\x06\x01\x04\x01\x82\xdc\x7c\x05\x03\x02\x01\x03\x61\x00";
 const KEY_LENGTH: usize = 96;
 const IC_STATE_ROOT_DOMAIN_SEPARATOR: &[u8; 14] = b"\x0Dic-state-root";
+/// DER encoded `AlgorithmIdentifier` with the canister signature OID `1.3.6.1.4.1.56387.1.2`.
+const CANISTER_SIG_ALGORITHM_ID: &[u8; 14] =
+    b"\x30\x0c\x06\x0a\x2b\x06\x01\x04\x01\x83\xb8\x43\x01\x02";
 
 pub fn verify_certified_data(
     certificate: &[u8],
@@ -12,7 +17,7 @@ pub fn verify_certified_data(
     root_pk: &[u8],
     certified_data: &[u8],
 ) -> Result<(), String> {
-    let verified_certificate = verify_certificate(certificate, root_pk)?;
+    let verified_certificate = verify_certificate_for_canister(certificate, canister_id, root_pk)?;
 
     let certified_data_path = [
         "canister".into(),
@@ -28,16 +33,115 @@ pub fn verify_certified_data(
     Ok(())
 }
 
+#[derive(Deserialize)]
+struct CanisterSignature {
+    #[serde(with = "serde_bytes")]
+    certificate: Vec<u8>,
+    tree: HashTree,
+}
+
+/// Verify the canister signature of the message made by the DER encoded canister
+/// signature public key: the certificate must be valid w.r.t. `root_pk` and certify
+/// the signature tree of the canister, which must contain
+/// the `sig/<sha256(seed)>/<sha256(message)>` path.
+pub fn verify_canister_signature(
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+    root_pk: &[u8],
+) -> Result<(), String> {
+    let (canister_id, seed) = parse_canister_sig_public_key(public_key)?;
+    let signature: CanisterSignature = serde_cbor::from_slice(signature)
+        .map_err(|err| format!("failed to decode canister signature: {}", err))?;
+
+    verify_certified_data(
+        &signature.certificate,
+        &canister_id,
+        root_pk,
+        &signature.tree.digest(),
+    )?;
+    verify_canister_sig_tree(&signature.tree, &seed, message)
+}
+
+/// Canister id and seed of the DER encoded canister signature public key.
+pub fn parse_canister_sig_public_key(public_key: &[u8]) -> Result<(Principal, Vec<u8>), String> {
+    let (key_info, rest) = read_der(public_key, 0x30)?;
+    if !rest.is_empty() {
+        return Err("DerTrailingBytes".to_owned());
+    }
+    let algorithm_id = key_info
+        .get(..CANISTER_SIG_ALGORITHM_ID.len())
+        .filter(|algorithm_id| algorithm_id == CANISTER_SIG_ALGORITHM_ID)
+        .ok_or_else(|| "DerAlgorithmMismatch".to_owned())?;
+
+    let (bits, rest) = read_der(&key_info[algorithm_id.len()..], 0x03)?;
+    let raw_key = match bits.split_first() {
+        Some((0, raw_key)) if rest.is_empty() => raw_key,
+        _ => return Err("DerBitStringMismatch".to_owned()),
+    };
+
+    let (canister_id_len, raw_key) = raw_key
+        .split_first()
+        .ok_or_else(|| "CanisterSigKeyEmpty".to_owned())?;
+    let canister_id_len = *canister_id_len as usize;
+    if raw_key.len() < canister_id_len {
+        return Err("CanisterSigKeyTooShort".to_owned());
+    }
+    let canister_id = Principal::try_from_slice(&raw_key[..canister_id_len])
+        .map_err(|err| format!("invalid canister id: {}", err))?;
+
+    Ok((canister_id, raw_key[canister_id_len..].to_vec()))
+}
+
+/// Check that the certified signature tree contains the signature of the message.
+pub fn verify_canister_sig_tree(
+    tree: &HashTree,
+    seed: &[u8],
+    message: &[u8],
+) -> Result<(), String> {
+    let sig_path = [
+        "sig".into(),
+        Label::from(Sha256::digest(seed).to_vec()),
+        Label::from(Sha256::digest(message).to_vec()),
+    ];
+
+    match tree.lookup_path(&sig_path) {
+        LookupResult::Found([]) => Ok(()),
+        _ => Err("message is not signed by the canister".to_owned()),
+    }
+}
+
+/// Content of the DER value with the tag and the bytes following it.
+fn read_der(buf: &[u8], tag: u8) -> Result<(&[u8], &[u8]), String> {
+    match buf {
+        [actual_tag, ..] if *actual_tag != tag => Err("DerTagMismatch".to_owned()),
+        [_, length, rest @ ..] if *length < 0x80 => split_der(rest, *length as usize),
+        [_, 0x81, length, rest @ ..] => split_der(rest, *length as usize),
+        [_, 0x82, high, low, rest @ ..] => {
+            split_der(rest, u16::from_be_bytes([*high, *low]) as usize)
+        }
+        _ => Err("DerLengthMismatch".to_owned()),
+    }
+}
+
+fn split_der(buf: &[u8], length: usize) -> Result<(&[u8], &[u8]), String> {
+    if buf.len() < length {
+        return Err("DerLengthMismatch".to_owned());
+    }
+    Ok(buf.split_at(length))
+}
+
 /// Verification of the delegation certificate ensures that
 /// * the certificate is well-formed and contains a tree, a signature, and
 ///   _no_ further delegation, i.e., it comes directly from the root subnet,
 /// * the signature is valid w.r.t. `root_pk`,
 /// * the tree is well-formed and contains time as well as subnet information
-///   (i.e., a public_key and canister ranges) for the given subnet,
-/// * the canister ranges are well-formed and contain the `canister_id`, and
+///   (i.e., a public_key and canister ranges) for the given subnet, and
 /// * the public key is well-formed.
 ///
 /// Returns the verified certificate, if verification is successful.
+/// The canister ranges of the delegated subnet are not checked,
+/// see `verify_certificate_for_canister`.
 pub fn verify_certificate<'a>(
     certificate: &'a [u8],
     root_pk: &'a [u8],
@@ -47,6 +151,18 @@ pub fn verify_certificate<'a>(
     Ok(certificate)
 }
 
+/// Same as `verify_certificate`, the canister ranges of the delegated subnet
+/// must also contain the `canister_id`.
+pub fn verify_certificate_for_canister<'a>(
+    certificate: &'a [u8],
+    canister_id: &Principal,
+    root_pk: &'a [u8],
+) -> Result<Certificate, String> {
+    let certificate: Certificate = parse_certificate(certificate)?;
+    verify_for_canister(root_pk.to_vec(), &certificate, canister_id)?;
+    Ok(certificate)
+}
+
 pub fn parse_certificate(certificate: &[u8]) -> Result<Certificate, String> {
     serde_cbor::from_slice(certificate)
         .map_err(|err| format!("failed to decode certificate: {}", err))
@@ -54,6 +170,23 @@ pub fn parse_certificate(certificate: &[u8]) -> Result<Certificate, String> {
 
 /// Verify a certificate, checking delegation if present.
 pub fn verify(root_key: Vec<u8>, cert: &Certificate) -> Result<(), String> {
+    verify_delegated(root_key, cert, None)
+}
+
+/// Same as `verify`, the delegated subnet must be authorized for the `canister_id`.
+pub fn verify_for_canister(
+    root_key: Vec<u8>,
+    cert: &Certificate,
+    canister_id: &Principal,
+) -> Result<(), String> {
+    verify_delegated(root_key, cert, Some(canister_id))
+}
+
+fn verify_delegated(
+    root_key: Vec<u8>,
+    cert: &Certificate,
+    canister_id: Option<&Principal>,
+) -> Result<(), String> {
     let sig = &cert.signature;
 
     let root_hash = cert.tree.digest();
@@ -61,7 +194,7 @@ pub fn verify(root_key: Vec<u8>, cert: &Certificate) -> Result<(), String> {
     msg.extend_from_slice(IC_STATE_ROOT_DOMAIN_SEPARATOR);
     msg.extend_from_slice(&root_hash);
 
-    let der_key = check_delegation(root_key, &cert.delegation)?;
+    let der_key = check_subnet_delegation(root_key, &cert.delegation, canister_id)?;
     let key = extract_der(der_key)?;
 
     verify_bls_signature(sig, &msg, &key).map_err(|_| "fail verify signature".to_owned())
@@ -85,12 +218,49 @@ pub fn extract_der(buf: Vec<u8>) -> Result<Vec<u8>, String> {
 pub fn check_delegation(
     root_key: Vec<u8>,
     delegation: &Option<Delegation>,
+) -> Result<Vec<u8>, String> {
+    check_subnet_delegation(root_key, delegation, None)
+}
+
+/// Same as `check_delegation`, the canister ranges of the delegated subnet
+/// must contain the `canister_id`.
+pub fn check_delegation_for_canister(
+    root_key: Vec<u8>,
+    delegation: &Option<Delegation>,
+    canister_id: &Principal,
+) -> Result<Vec<u8>, String> {
+    check_subnet_delegation(root_key, delegation, Some(canister_id))
+}
+
+fn check_subnet_delegation(
+    root_key: Vec<u8>,
+    delegation: &Option<Delegation>,
+    canister_id: Option<&Principal>,
 ) -> Result<Vec<u8>, String> {
     match delegation {
         None => Ok(root_key),
         Some(delegation) => {
             let cert: Certificate = serde_cbor::from_slice(&delegation.certificate)
                 .map_err(|_| "can not obtain certificate from delegation".to_owned())?;
+            if cert.delegation.is_some() {
+                return Err("CertificateHasTooManyDelegations".to_owned());
+            }
+
+            if let Some(canister_id) = canister_id {
+                let canister_ranges_path = [
+                    "subnet".into(),
+                    delegation.subnet_id.clone().into(),
+                    "canister_ranges".into(),
+                ];
+                let canister_ranges = lookup_value(&cert.tree, canister_ranges_path)?;
+                let canister_ranges: Vec<(Principal, Principal)> =
+                    serde_cbor::from_slice(canister_ranges)
+                        .map_err(|err| format!("failed to decode canister ranges: {}", err))?;
+                if !principal_is_within_ranges(canister_id, &canister_ranges) {
+                    // the subnet is not authorized to certify the state of this canister
+                    return Err("CertificateNotAuthorized".to_owned());
+                }
+            }
 
             verify(root_key, &cert)?;
             let public_key_path = [
@@ -103,6 +273,12 @@ pub fn check_delegation(
     }
 }
 
+fn principal_is_within_ranges(principal: &Principal, ranges: &[(Principal, Principal)]) -> bool {
+    ranges
+        .iter()
+        .any(|(low, high)| principal >= low && principal <= high)
+}
+
 pub fn lookup_value<'a, P>(tree: &'a HashTree, path: P) -> Result<&'a [u8], String>
 where
     for<'p> &'p P: IntoIterator<Item = &'p Label>,
@@ -113,3 +289,80 @@ where
         _ => Err(format!("Can not lookup {:?}", path.into())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{check_delegation, check_delegation_for_canister, verify_certified_data};
+    use candid::Principal;
+    use ic_certification::{fork, label, leaf, Certificate, Delegation};
+
+    fn subnet_id() -> Principal {
+        Principal::from_text("tdb26-jop6k-aogll-7ltgs-eruif-6kk7m-qpktf-gdiqx-mxtrf-vb5e6-eqe")
+            .unwrap()
+    }
+
+    fn canister_id() -> Principal {
+        Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap()
+    }
+
+    /// Delegation from the root subnet to the subnet of the ranges, with an invalid signature.
+    fn delegation(ranges: &[(Principal, Principal)]) -> Delegation {
+        let subnet_tree = fork(
+            label(
+                "canister_ranges",
+                leaf(serde_cbor::to_vec(&ranges).unwrap()),
+            ),
+            label("public_key", leaf(vec![0_u8; 133])),
+        );
+        let certificate = Certificate {
+            tree: label(
+                "subnet",
+                label(subnet_id().as_slice().to_vec(), subnet_tree),
+            ),
+            signature: vec![0_u8; 48],
+            delegation: None,
+        };
+
+        Delegation {
+            subnet_id: subnet_id().as_slice().to_vec(),
+            certificate: serde_cbor::to_vec(&certificate).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_delegation_canister_ranges() {
+        let other = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
+        let foreign_delegation = Some(delegation(&[(other, other)]));
+        assert_eq!(
+            check_delegation_for_canister(vec![], &foreign_delegation, &canister_id()),
+            Err("CertificateNotAuthorized".to_owned())
+        );
+        // the ranges are not checked without the canister
+        assert_eq!(
+            check_delegation(vec![], &foreign_delegation),
+            Err("DerKeyLengthMismatch".to_owned())
+        );
+
+        // the canister is within the ranges, the signature is verified next
+        let own_delegation = Some(delegation(&[(canister_id(), canister_id())]));
+        assert_eq!(
+            check_delegation_for_canister(vec![], &own_delegation, &canister_id()),
+            Err("DerKeyLengthMismatch".to_owned())
+        );
+
+        let certificate = Certificate {
+            tree: leaf(vec![]),
+            signature: vec![0_u8; 48],
+            delegation: foreign_delegation,
+        };
+        assert_eq!(
+            verify_certified_data(
+                &serde_cbor::to_vec(&certificate).unwrap(),
+                &canister_id(),
+                &[],
+                &[]
+            ),
+            Err("CertificateNotAuthorized".to_owned())
+        );
+    }
+}